@@ -1,10 +1,16 @@
-use std::fs;
-use std::path::PathBuf;
-use tauri_specta::ts;
-use specta::collect_types;
-use specta::ts::{BigIntExportBehavior, ExportConfiguration};
-
 fn main() {
+    // The command-line interface builds without the app, and so without Tauri
+    #[cfg(feature = "gui")]
+    build_app();
+}
+
+#[cfg(feature = "gui")]
+fn build_app() {
+    use std::fs;
+    use std::path::PathBuf;
+    use tauri_specta::ts;
+    use specta::collect_types;
+
     // Run the tauri plugin before building
     tauri_build::build();
 
@@ -1,18 +1,25 @@
 use specta::Type;
-use tauri::{Window, AppHandle};
+use tauri::{AppHandle, Manager, State};
 use std::path::PathBuf;
-use std::time::{SystemTime, UNIX_EPOCH};
-use std::time::{Duration, Instant};
-use std::sync::{Arc, Mutex};
-use serde_json::{json, Value};
+use std::time::Instant;
+use serde_json::{json, Map, Value};
 use serde::{Serialize, Deserialize};
 use serde_with::{serde_as, DisplayFromStr};
-use dirs;
-use tokio::time;
 use crate::db;
-use crate::client;
-use crate::state;
 use crate::db_manager;
+use crate::registry;
+use crate::throttle;
+use crate::queue;
+use crate::probe;
+use crate::retry;
+use crate::prealloc;
+use crate::conflict;
+use crate::workspace;
+use crate::recovery;
+use crate::control;
+use crate::status::DownloadStatus;
+use crate::error::SpeedyError;
+use crate::manager::{self, DownloadManager, DownloadRequest, EventSink};
 
 // Helper function to convert string parameter to u64 if needed
 fn parse_u64_param(param: &str) -> u64 {
@@ -40,6 +47,10 @@ pub struct DownloadProgress {
     
     pub speed: f64,
     pub estimated_time_left: f64,
+    pub indeterminate: bool,
+    pub phase: String,          // "downloading", "merging", "verifying" or "completed"
+    pub merge_progress: f64,
+    pub verify_progress: f64,
     pub segments: Vec<SegmentProgress>,
 }
 
@@ -69,251 +80,91 @@ pub struct DownloadProgressEvent {
     pub progress: f64,
 }
 
-/// Starts a download process and tracks its progress
-#[tauri::command]
-#[specta::specta]
-pub async fn start_download(url: String, name: String, parts: String, download_id: Option<u64>, window: Window) -> Result<(), String> {
-    // Convert parts from string to u64
-    let parts = parse_u64_param(&parts);
-    
-    let (tx, rx) = std::sync::mpsc::channel::<client::DownloadEvent>();
+/// Reports download progress to the app's windows
+pub struct AppEventSink {
+    app_handle: AppHandle,
+}
 
-    // Create shared download state
-    let download_state = Arc::new(Mutex::new(state::DownloadState::new()));
-    
-    // Get the filename from the URL
-    let filename = client::Client::get_file_name(&url);
-    
-    // Use provided download_id or generate a new one
-    let download_id = download_id.unwrap_or_else(|| {
-        // Generate a unique ID based on current timestamp
-        let timestamp = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .expect("Time went backwards")
-            .as_secs();
-        timestamp
-    });
-    
-    // Create a database entry for this download
-    let download = db::Download::new(download_id, url.clone(), filename.clone(), 0, parts);
-    if let Err(e) = db_manager::insert_download(&download).await {
-        eprintln!("Failed to insert download into database: {}", e);
+impl AppEventSink {
+    pub fn new(app_handle: AppHandle) -> Self {
+        Self { app_handle }
     }
-    
-    // Start the download process
-    let url_clone = url.clone();
-    let download_id_clone = download_id;
-    tokio::spawn(async move {
-        let mut client = client::Client::new(url_clone, parts);
-        if let Err(e) = client.download(tx.clone()).await {
-            let error_message = e.to_string();
-            eprintln!("Download error: {}", error_message);
-            // Update database with error - avoid using the error directly across await
-            if let Err(db_err) = db_manager::mark_error(download_id_clone, &error_message).await {
-                eprintln!("Failed to update database with error: {}", db_err);
-            }
-        }
-    });
+}
 
-    // Create a thread to process events and update the download state
-    let state = download_state.clone();
-    let download_id_clone = download_id;
-    let filename_clone = filename.clone();
-    
-    tokio::spawn(async move {
-        while let Ok(event) = rx.recv() {
-            match event {
-                client::DownloadEvent::Initialize { file_size, segments } => {
-                    // Use a block to limit the scope of the mutex guard
-                    {
-                        let mut state_guard = state.lock().unwrap();
-                        state_guard.initialize(file_size, segments.clone());
-                    }
-                    
-                    // Update the database with the file size
-                    if let Ok(Some(mut download)) = db_manager::get_download(download_id_clone).await {
-                        download.total_size = file_size;
-                        if let Err(e) = db_manager::update_download(&download).await {
-                            eprintln!("Failed to update download size in database: {}", e);
-                        }
-                    }
-                },
-                client::DownloadEvent::BytesReceived { segment_id, bytes, speed } => {
-                    // Use a block to limit the scope of the mutex guard
-                    {
-                        let mut state_guard = state.lock().unwrap();
-                        state_guard.add_bytes(segment_id, bytes, speed);
-                    }
-                    
-                    // Update the database with progress
-                    if let Err(e) = db_manager::update_progress(download_id_clone, bytes).await {
-                        eprintln!("Failed to update download progress in database: {}", e);
-                    }
-                },
-                client::DownloadEvent::Error { segment_id, message } => {
-                    eprintln!("Error in segment {}: {}", segment_id, message);
-                    
-                    // Update database with error
-                    let error_message = message.clone();
-                    if let Err(e) = db_manager::mark_error(download_id_clone, &error_message).await {
-                        eprintln!("Failed to update database with error: {}", e);
-                    }
-                },
-                client::DownloadEvent::Complete => {
-                    // Mark the state as complete
-                    {
-                        let mut state_guard = state.lock().unwrap();
-                        state_guard.mark_complete();
-                    }
-                    
-                    // Try to get the output path for the completed download
-                    let output_dir = dirs::download_dir().unwrap_or_else(|| std::env::current_dir().unwrap_or_else(|_| std::path::PathBuf::from(".")));
-                    let output_path = output_dir.join(&filename_clone);
-                    let path_str = output_path.to_string_lossy().to_string();
-                    
-                    // Update database with completion
-                    if let Err(e) = db_manager::mark_complete(download_id_clone, &path_str).await {
-                        eprintln!("Failed to mark download as complete in database: {}", e);
-                    }
-                    
-                    break;
-                }
-            }
+impl EventSink for AppEventSink {
+    fn progress(&self, _download_id: u64, payload: &Map<String, Value>) {
+        if let Err(e) = self.app_handle.emit_all("download-progress", payload.clone()) {
+            eprintln!("Error sending update to frontend: {:?}", e);
         }
-    });
-
-    // Create a UI update thread to send progress to the frontend
-    let watcher_state = download_state.clone();
-    let watcher_window = window.clone();
-    let download_id_clone = download_id;
-    tokio::spawn(async move {
-        let mut interval = time::interval(Duration::from_millis(50));
-        let mut high_water_mark = state::HighWaterMarkTracker::new();
-        
-        loop {
-            interval.tick().await;
-            
-            let download_complete;
-            let mut progress_json;
-            
-            {
-                let state_guard = watcher_state.lock().unwrap();
-                download_complete = state_guard.is_complete;
-                progress_json = state_guard.create_progress_json();
-            }
-            
-            // Ensure progress values never decrease
-            high_water_mark.ensure_monotonic_progress(&mut progress_json);
-            
-            // Add the download ID to the payload
-            progress_json.insert("downloadId".to_string(), Value::from(download_id_clone));
-            
-            // Send update to the frontend
-            let total_progress = progress_json.get("progress").and_then(|v| v.as_f64()).unwrap_or(0.0);
-            
-            if let Err(e) = watcher_window.emit("download-progress", progress_json) {
-                eprintln!("Error sending update to frontend: {:?}", e);
-            }
-            
-            // If download is complete and we've sent the 100% update, break the loop
-            if download_complete && total_progress >= 100.0 {
-                break;
-            }
-        }
-    });
+    }
+}
 
+/// Adds a download to the queue, it starts as soon as a download slot is free.
+/// `name` and `directory` choose where the file is saved, an empty name uses the
+/// one from the URL and no directory the downloads folder.
+/// `checksum` is an expected digest like "sha256:<hex>", `checksum_url` points to a
+/// `.sha256` file or a `SHA256SUMS` listing to read it from.
+// The frontend passes each field as its own argument
+#[allow(clippy::too_many_arguments)]
+#[tauri::command]
+#[specta::specta]
+pub async fn start_download(
+    url: String,
+    name: String,
+    parts: String,
+    download_id: Option<u64>,
+    directory: Option<String>,
+    checksum: Option<String>,
+    checksum_url: Option<String>,
+    manager: State<'_, DownloadManager>,
+) -> Result<(), SpeedyError> {
+    let mut request = DownloadRequest::new(url, parse_u64_param(&parts));
+    request.name = Some(name);
+    request.download_id = download_id;
+    request.directory = directory;
+    request.checksum = checksum;
+    request.checksum_url = checksum_url;
+    manager.add(request).await?;
     Ok(())
 }
 
 /// List all downloads from the database
 #[tauri::command]
 #[specta::specta]
-pub async fn list_downloads() -> Result<Vec<db::Download>, String> {
+pub async fn list_downloads() -> Result<Vec<db::Download>, SpeedyError> {
     match db_manager::list_downloads().await {
         Ok(downloads) => Ok(downloads),
-        Err(e) => Err(format!("Failed to list downloads: {}", e)),
+        Err(e) => Err(SpeedyError::from(e).context("Failed to list downloads")),
     }
 }
 
 /// Get a download by ID from the database
 #[tauri::command]
 #[specta::specta]
-pub async fn get_download(download_id: String) -> Result<Option<db::Download>, String> {
+pub async fn get_download(download_id: String) -> Result<Option<db::Download>, SpeedyError> {
     let download_id = parse_u64_param(&download_id);
     match db_manager::get_download(download_id).await {
         Ok(download) => Ok(download),
-        Err(e) => Err(format!("Failed to get download: {}", e)),
+        Err(e) => Err(SpeedyError::from(e).context("Failed to get download")),
     }
 }
 
 /// Delete a download from the database
 #[tauri::command]
 #[specta::specta]
-pub async fn delete_download(download_id: String, should_also_delete_file: Option<bool>) -> Result<(), String> {
-    println!("Deleting download: {}", download_id);
+pub async fn delete_download(download_id: String, should_also_delete_file: Option<bool>, manager: State<'_, DownloadManager>) -> Result<(), SpeedyError> {
+    eprintln!("Deleting download: {}", download_id);
     let download_id = parse_u64_param(&download_id);
-    
-    let should_delete_file = should_also_delete_file.unwrap_or(false);
-    
-    // If we don't need to delete the file, we can proceed directly to database deletion
-    if !should_delete_file {
-        return delete_from_database(download_id).await;
-    }
-    
-    // If we need to delete the file, first get the download to find the file path
-    match db_manager::get_download(download_id).await {
-        Ok(Some(download)) => {
-            // Check if we have a valid save path
-            if let Some(save_path) = download.save_path.filter(|path| !path.is_empty()) {
-                // Try to delete the file
-                match std::fs::remove_file(&save_path) {
-                    Ok(_) => {
-                        println!("Successfully deleted file: {}", save_path);
-                        delete_from_database(download_id).await
-                    },
-                    Err(e) => {
-                        println!("Warning: Could not delete file at {}: {}", save_path, e);
-                        Err(format!("File deletion failed, not removing from database"))
-                    }
-                }
-            } else {
-                // No valid save path, proceed with database deletion
-                println!("No valid save path found, proceeding with database deletion");
-                delete_from_database(download_id).await
-            }
-        },
-        Ok(None) => {
-            println!("Download not found, cannot delete file");
-            Err(format!("Download not found in database"))
-        },
-        Err(e) => {
-            println!("Error retrieving download for file deletion: {}", e);
-            Err(format!("Error retrieving download: {}", e))
-        },
-    }
-}
-
-// Helper function to delete a download from the database
-pub async fn delete_from_database(download_id: u64) -> Result<(), String> {
-    match db_manager::delete_download(download_id).await {
-        Ok(_) => {
-            println!("Successfully deleted download: {}", download_id);
-            Ok(())
-        },
-        Err(e) => {
-            println!("Failed to delete download: {}", e);
-            Err(format!("Failed to delete download: {}", e))
-        },
-    }
+    manager.remove(download_id, should_also_delete_file.unwrap_or(false)).await
 }
 
 /// Get downloads with a specific status from the database
 #[tauri::command]
 #[specta::specta]
-pub async fn get_downloads_by_status(status: String) -> Result<Vec<db::Download>, String> {
-    match db_manager::get_downloads_by_status(&status).await {
+pub async fn get_downloads_by_status(status: DownloadStatus) -> Result<Vec<db::Download>, SpeedyError> {
+    match db_manager::get_downloads_by_status(status).await {
         Ok(downloads) => Ok(downloads),
-        Err(e) => Err(format!("Failed to get downloads by status: {}", e)),
+        Err(e) => Err(SpeedyError::from(e).context("Failed to get downloads by status")),
     }
 }
 
@@ -325,7 +176,7 @@ pub async fn open_details_window(
     url: String,
     title: String,
     app_handle: AppHandle
-) -> Result<(), String> {
+) -> Result<(), SpeedyError> {
     let download_id = parse_u64_param(&download_id);
     let label = format!("download-{}-{}", download_id, chrono::Utc::now().timestamp());
     
@@ -339,176 +190,299 @@ pub async fn open_details_window(
     .center()
     .build() {
         Ok(_) => Ok(()),
-        Err(e) => Err(format!("Failed to open window: {}", e))
+        Err(e) => Err(SpeedyError::other(format!("Failed to open window: {}", e)))
     }
 }
 
 /// For backward compatibility with the previous "greet" command
 #[tauri::command]
 #[specta::specta]
-pub async fn greet(_name: &str, window: Window) -> Result<(), String> {
+pub async fn greet(_name: &str, manager: State<'_, DownloadManager>) -> Result<(), SpeedyError> {
     let url = "https://test-videos.co.uk/vids/bigbuckbunny/mp4/h264/1080/Big_Buck_Bunny_1080_10s_1MB.mp4".to_string();
     let parts = 5;
     let download_id = 0; // Default ID for the greet command
     let name = "test".to_string();
-    start_download(url, name, parts.to_string(), Some(download_id), window).await
+    start_download(url, name, parts.to_string(), Some(download_id), None, None, None, manager).await
+}
+
+/// Looks up the size, range support, filename and content type of a URL without downloading it
+#[tauri::command]
+#[specta::specta]
+pub async fn probe_url(url: String) -> Result<probe::ProbeResult, SpeedyError> {
+    let http_client = reqwest::Client::new();
+    probe::probe(&http_client, &url).await.map_err(SpeedyError::from)
 }
 
 /// Checks if a file is already being downloaded or exists in parts
 /// Returns information about any existing download with the same filename
 #[tauri::command]
 #[specta::specta]
-pub async fn check_existing_download(url: String) -> Result<serde_json::Value, String> {
-    // Get the filename from the URL
-    let filename = client::Client::get_file_name(&url);
-    
-    // Get the downloads directory and temp directory
-    let downloads_dir = dirs::download_dir().unwrap_or_else(|| std::env::current_dir().unwrap_or_else(|_| std::path::PathBuf::from(".")));
-    let temp_dir = std::env::temp_dir();
-    
-    // Check if the complete file already exists in the downloads folder
-    let complete_file_path = downloads_dir.join(&filename);
-    let complete_file_exists = complete_file_path.exists();
-    
-    // Check for part files in the temp directory
-    let mut part_files = Vec::new();
-    if let Ok(entries) = std::fs::read_dir(&temp_dir) {
-        for entry in entries.filter_map(Result::ok) {
-            let path = entry.path();
-            if let Some(file_name) = path.file_name().and_then(|n| n.to_str()) {
-                // Look for part files with pattern: filename.part1, filename.part2, etc.
-                if file_name.starts_with(&filename) && file_name.contains(".part") {
-                    part_files.push(path);
-                }
-            }
-        }
-    }
-    
-    // Generate a unique filename regardless of whether we found existing files
-    let file_stem = std::path::Path::new(&filename)
-        .file_stem()
-        .and_then(|s| s.to_str())
-        .unwrap_or("file");
-        
-    let extension = std::path::Path::new(&filename)
-        .extension()
-        .and_then(|s| s.to_str())
-        .unwrap_or("");
-    
-    // Try to find a non-colliding filename
-    let mut counter = 1;
-    let mut new_filename = if extension.is_empty() {
-        format!("{} ({})", file_stem, counter)
-    } else {
-        format!("{} ({}).{}", file_stem, counter, extension)
-    };
-    
-    let mut new_path = downloads_dir.join(&new_filename);
+pub async fn check_existing_download(url: String) -> Result<serde_json::Value, SpeedyError> {
+    manager::check_existing_download(&url).await
+}
+
+/// Pauses a download by its ID
+#[tauri::command]
+#[specta::specta]
+pub async fn pause_download(download_id: String, manager: State<'_, DownloadManager>) -> Result<(), SpeedyError> {
+    eprintln!("Pausing download: {}", download_id);
+    let download_id = parse_u64_param(&download_id);
+    manager.pause(download_id).await?;
+    eprintln!("Successfully paused download: {}", download_id);
+    Ok(())
+}
+
+/// Cancels a download by its ID, stopping it and removing its part files
+#[tauri::command]
+#[specta::specta]
+pub async fn cancel_download(download_id: String, manager: State<'_, DownloadManager>) -> Result<(), SpeedyError> {
+    eprintln!("Cancelling download: {}", download_id);
+    let download_id = parse_u64_param(&download_id);
+    manager.cancel(download_id).await?;
+    eprintln!("Successfully cancelled download: {}", download_id);
+    Ok(())
+}
+
+/// Sets the speed limit of a single download in KB/s, 0 removes the limit
+#[tauri::command]
+#[specta::specta]
+pub async fn set_speed_limit(download_id: String, limit_k_bps: f64) -> Result<(), SpeedyError> {
+    let download_id = parse_u64_param(&download_id);
+    let limit = throttle::kbps_to_limit(limit_k_bps);
+    eprintln!("Setting speed limit of download {} to {:?} bytes/s", download_id, limit);
     
-    // Keep incrementing until we find a name that doesn't exist
-    while new_path.exists() {
-        counter += 1;
-        new_filename = if extension.is_empty() {
-            format!("{} ({})", file_stem, counter)
-        } else {
-            format!("{} ({}).{}", file_stem, counter, extension)
-        };
-        new_path = downloads_dir.join(&new_filename);
-    }
+    registry::set_speed_limit(download_id, limit).await;
+    Ok(())
+}
+
+/// Sets the speed limit shared by all downloads in KB/s, 0 removes the limit
+#[tauri::command]
+#[specta::specta]
+pub async fn set_global_speed_limit(limit_k_bps: f64) -> Result<(), SpeedyError> {
+    let limit = throttle::kbps_to_limit(limit_k_bps);
+    eprintln!("Setting global speed limit to {:?} bytes/s", limit);
     
-    if part_files.is_empty() && !complete_file_exists {
-        // No existing files found
-        return Ok(serde_json::json!({
-            "exists": false,
-            "original_filename": filename,
-            "suggested_filename": filename
-        }));
+    throttle::GLOBAL_LIMITER.set_limit(limit);
+    Ok(())
+}
+
+/// Resumes a download by its ID
+#[tauri::command]
+#[specta::specta]
+pub async fn resume_download(download_id: String, manager: State<'_, DownloadManager>) -> Result<(), SpeedyError> {
+    eprintln!("Resuming download: {}", download_id);
+    let download_id = parse_u64_param(&download_id);
+    manager.resume(download_id).await
+}
+
+/// Gets the queued downloads in the order they will start
+#[tauri::command]
+#[specta::specta]
+pub async fn get_queue() -> Result<Vec<db::Download>, SpeedyError> {
+    match db_manager::get_queued_downloads().await {
+        Ok(downloads) => Ok(downloads),
+        Err(e) => Err(SpeedyError::from(e).context("Failed to get queue")),
     }
-    
-    if !part_files.is_empty() {
-        // We found part files, suggesting a download is in progress or was interrupted
-        return Ok(serde_json::json!({
-            "exists": true,
-            "type": "in_progress",
-            "original_filename": filename,
-            "part_files": part_files.len(),
-            "location": temp_dir.to_string_lossy(),
-            "suggested_filename": new_filename
-        }));
+}
+
+/// Gets the maximum number of downloads that run at the same time
+#[tauri::command]
+#[specta::specta]
+pub async fn get_max_concurrent_downloads() -> Result<u32, SpeedyError> {
+    Ok(queue::max_concurrent_downloads().await)
+}
+
+/// Sets the maximum number of downloads that run at the same time
+#[tauri::command]
+#[specta::specta]
+pub async fn set_max_concurrent_downloads(limit: u32, manager: State<'_, DownloadManager>) -> Result<(), SpeedyError> {
+    eprintln!("Setting max concurrent downloads to {}", limit);
+    manager.set_max_concurrent_downloads(limit).await
+}
+
+/// Gets how many times a failed segment is retried before its download fails
+#[tauri::command]
+#[specta::specta]
+pub async fn get_max_segment_retries() -> Result<u32, SpeedyError> {
+    Ok(retry::max_segment_retries().await)
+}
+
+/// Sets how many times a failed segment is retried, applies to downloads started afterwards
+#[tauri::command]
+#[specta::specta]
+pub async fn set_max_segment_retries(retries: u32) -> Result<(), SpeedyError> {
+    eprintln!("Setting max segment retries to {}", retries);
+    retry::set_max_segment_retries(retries).await
+        .map_err(|e| SpeedyError::from(e).context("Failed to save max segment retries"))
+}
+
+/// Gets whether new downloads write straight into a preallocated output file
+#[tauri::command]
+#[specta::specta]
+pub async fn get_preallocate_files() -> Result<bool, SpeedyError> {
+    Ok(prealloc::preallocate_files().await)
+}
+
+/// Sets whether new downloads write straight into a preallocated output file
+/// instead of part files that are merged at the end
+#[tauri::command]
+#[specta::specta]
+pub async fn set_preallocate_files(enabled: bool) -> Result<(), SpeedyError> {
+    eprintln!("Setting preallocated output files to {}", enabled);
+    prealloc::set_preallocate_files(enabled).await
+        .map_err(|e| SpeedyError::from(e).context("Failed to save preallocation setting"))
+}
+
+/// Gets whether downloads interrupted by quitting resume when the app starts again
+#[tauri::command]
+#[specta::specta]
+pub async fn get_resume_on_startup() -> Result<bool, SpeedyError> {
+    Ok(recovery::resume_on_startup().await)
+}
+
+/// Sets whether downloads interrupted by quitting resume when the app starts again,
+/// instead of waiting paused until they are resumed by hand
+#[tauri::command]
+#[specta::specta]
+pub async fn set_resume_on_startup(enabled: bool) -> Result<(), SpeedyError> {
+    eprintln!("Setting resume on startup to {}", enabled);
+    recovery::set_resume_on_startup(enabled).await
+        .map_err(|e| SpeedyError::from(e).context("Failed to save resume on startup setting"))
+}
+
+/// Gets the directory new downloads keep their part files in
+#[tauri::command]
+#[specta::specta]
+pub async fn get_workspace_dir() -> Result<String, SpeedyError> {
+    Ok(workspace::workspace_root().await.to_string_lossy().into_owned())
+}
+
+/// Sets the directory new downloads keep their part files in, downloads that
+/// have started already keep theirs
+#[tauri::command]
+#[specta::specta]
+pub async fn set_workspace_dir(path: String) -> Result<(), SpeedyError> {
+    let path = PathBuf::from(path.trim());
+    if !path.is_absolute() {
+        return Err(SpeedyError::invalid_input(format!("Workspace directory must be an absolute path: {}", path.display())));
     }
-    
-    if complete_file_exists {
-        // The file already exists
-        return Ok(serde_json::json!({
-            "exists": true,
-            "type": "completed",
-            "original_filename": filename,
-            "suggested_filename": new_filename,
-            "file_path": complete_file_path.to_string_lossy()
-        }));
+    std::fs::create_dir_all(&path)
+        .map_err(|e| SpeedyError::from(e).context(&format!("Failed to create workspace directory {}", path.display())))?;
+    eprintln!("Setting workspace directory to {}", path.display());
+    workspace::set_workspace_root(&path).await
+        .map_err(|e| SpeedyError::from(e).context("Failed to save workspace directory"))
+}
+
+/// Gets whether scripts and browser extensions can control downloads through the
+/// local HTTP and WebSocket API
+#[tauri::command]
+#[specta::specta]
+pub async fn get_control_api_enabled() -> Result<bool, SpeedyError> {
+    Ok(control::control_api_enabled().await)
+}
+
+/// Sets whether the local control API is served, starting or stopping it right away
+#[tauri::command]
+#[specta::specta]
+pub async fn set_control_api_enabled(enabled: bool, manager: State<'_, DownloadManager>) -> Result<(), SpeedyError> {
+    eprintln!("Setting control API enabled to {}", enabled);
+    control::set_control_api_enabled(enabled).await
+        .map_err(|e| SpeedyError::from(e).context("Failed to save control API setting"))?;
+    control::restart(manager.inner().clone()).await;
+    Ok(())
+}
+
+/// Gets the localhost port the control API listens on
+#[tauri::command]
+#[specta::specta]
+pub async fn get_control_api_port() -> Result<u16, SpeedyError> {
+    Ok(control::control_api_port().await)
+}
+
+/// Sets the localhost port the control API listens on, restarting it if it's served
+#[tauri::command]
+#[specta::specta]
+pub async fn set_control_api_port(port: u16, manager: State<'_, DownloadManager>) -> Result<(), SpeedyError> {
+    if port == 0 {
+        return Err(SpeedyError::invalid_input("Invalid port: 0"));
     }
-    
-    // This should never happen as we've handled all cases above
-    Ok(serde_json::json!({
-        "exists": false,
-        "original_filename": filename,
-        "suggested_filename": filename
-    }))
+    eprintln!("Setting control API port to {}", port);
+    control::set_control_api_port(port).await
+        .map_err(|e| SpeedyError::from(e).context("Failed to save control API port"))?;
+    control::restart(manager.inner().clone()).await;
+    Ok(())
 }
 
-/// Pauses a download by its ID
+/// Gets the token clients of the control API authenticate with
+#[tauri::command]
+#[specta::specta]
+pub async fn get_control_api_token() -> Result<String, SpeedyError> {
+    control::control_api_token().await
+        .map_err(|e| SpeedyError::from(e).context("Failed to read control API token"))
+}
+
+/// Replaces the control API token with a new one, clients using the old one are refused
+#[tauri::command]
+#[specta::specta]
+pub async fn reset_control_api_token(manager: State<'_, DownloadManager>) -> Result<String, SpeedyError> {
+    let token = control::reset_control_api_token().await
+        .map_err(|e| SpeedyError::from(e).context("Failed to save control API token"))?;
+    control::restart(manager.inner().clone()).await;
+    Ok(token)
+}
+
+/// Gets what happens when a download's file already exists: overwrite, rename, skip or ask
 #[tauri::command]
 #[specta::specta]
-pub async fn pause_download(download_id: String) -> Result<(), String> {
-    println!("Pausing download: {}", download_id);
+pub async fn get_conflict_policy() -> Result<String, SpeedyError> {
+    Ok(conflict::conflict_policy().await.to_string())
+}
+
+/// Sets what happens when a download's file already exists: overwrite, rename, skip or ask
+#[tauri::command]
+#[specta::specta]
+pub async fn set_conflict_policy(policy: String) -> Result<(), SpeedyError> {
+    let policy = conflict::ConflictPolicy::parse(&policy)
+        .ok_or_else(|| SpeedyError::invalid_input(format!("Invalid conflict policy: {}. Use overwrite, rename, skip or ask", policy)))?;
+    eprintln!("Setting file conflict policy to {}", policy);
+    conflict::set_conflict_policy(policy).await
+        .map_err(|e| SpeedyError::from(e).context("Failed to save conflict policy"))
+}
+
+/// Resolves a download stopped because its file exists by overwriting the file or
+/// saving under another name, then puts the download back in the queue
+#[tauri::command]
+#[specta::specta]
+pub async fn resolve_conflict(download_id: String, policy: String, manager: State<'_, DownloadManager>) -> Result<(), SpeedyError> {
     let download_id = parse_u64_param(&download_id);
-    
-    // Update the download status to paused in the database
-    match db_manager::update_status(download_id, "paused").await {
-        Ok(_) => {
-            println!("Successfully paused download: {}", download_id);
-            Ok(())
-        },
-        Err(e) => {
-            println!("Failed to pause download: {}", e);
-            Err(format!("Failed to pause download: {}", e))
-        },
-    }
+    let policy = conflict::ConflictPolicy::parse(&policy)
+        .ok_or_else(|| SpeedyError::invalid_input(format!("Invalid policy: {}. Use overwrite or rename", policy)))?;
+    manager.resolve_conflict(download_id, policy).await
 }
 
-/// Resumes a download by its ID
+/// Sets the priority of a download, higher priority queued downloads start first
 #[tauri::command]
 #[specta::specta]
-pub async fn resume_download(download_id: String, window: Window) -> Result<(), String> {
-    println!("Resuming download: {}", download_id);
+pub async fn set_download_priority(download_id: String, priority: i32) -> Result<(), SpeedyError> {
     let download_id = parse_u64_param(&download_id);
-    
-    // Get the download information from the database
-    let download = match db_manager::get_download(download_id).await {
-        Ok(Some(download)) => download,
-        Ok(None) => return Err("Download not found".to_string()),
-        Err(e) => return Err(format!("Error retrieving download: {}", e)),
-    };
-    
-    // Update the download status to in_progress
-    if let Err(e) = db_manager::update_status(download_id, "downloading").await {
-        return Err(format!("Failed to update download status: {}", e));
+    match db_manager::update_priority(download_id, priority).await {
+        Ok(_) => Ok(()),
+        Err(e) => Err(SpeedyError::from(e).context("Failed to set download priority")),
     }
-    
-    // Restart the download with the same parameters
-    start_download(
-        download.url,
-        download.filename,
-        download.parts.to_string(),
-        Some(download_id),
-        window
-    ).await
+}
+
+/// Moves a queued download to a 0-based position in the queue
+#[tauri::command]
+#[specta::specta]
+pub async fn move_in_queue(download_id: String, position: u32) -> Result<(), SpeedyError> {
+    let download_id = parse_u64_param(&download_id);
+    queue::move_to(download_id, position as usize).await
 }
 
 #[tauri::command]
 #[specta::specta]
-pub async fn debug_commands() -> Result<String, String> {
+pub async fn debug_commands() -> Result<String, SpeedyError> {
     // Return information about registered commands
-    let info = "Available commands: start_download, open_details_window, greet, list_downloads, get_download, delete_download, get_downloads_by_status, check_existing_download, pause_download, resume_download";
+    let info = "Available commands: start_download, open_details_window, greet, list_downloads, get_download, delete_download, get_downloads_by_status, check_existing_download, pause_download, resume_download, cancel_download, set_speed_limit, set_global_speed_limit, get_queue, get_max_concurrent_downloads, set_max_concurrent_downloads, set_download_priority, move_in_queue, get_max_segment_retries, set_max_segment_retries, get_preallocate_files, set_preallocate_files, get_conflict_policy, set_conflict_policy, resolve_conflict, probe_url, get_workspace_dir, set_workspace_dir, get_resume_on_startup, set_resume_on_startup, get_control_api_enabled, set_control_api_enabled, get_control_api_port, set_control_api_port, get_control_api_token, reset_control_api_token";
     Ok(info.to_string())
 } 
\ No newline at end of file
@@ -0,0 +1,539 @@
+use crate::control;
+use crate::db::Download;
+use crate::db_manager;
+use crate::error::SpeedyError;
+use crate::manager::{self, DownloadManager, DownloadRequest};
+use crate::queue;
+use crate::status::DownloadStatus;
+use serde_json::{json, Map, Value};
+use std::path::PathBuf;
+
+// Error codes defined by JSON-RPC 2.0
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+
+/// aria2 reports every failed method call with this code
+const CALL_FAILED: i64 = 1;
+
+/// A failed call, sent back as the `error` member of the response
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        Self { code, message: message.into() }
+    }
+
+    fn invalid_params(message: impl Into<String>) -> Self {
+        Self::new(INVALID_PARAMS, message)
+    }
+}
+
+impl From<SpeedyError> for RpcError {
+    fn from(error: SpeedyError) -> Self {
+        Self::new(CALL_FAILED, error.message())
+    }
+}
+
+/// Answer a JSON-RPC request, or a batch of them, in the dialect of aria2's RPC
+/// interface so its clients and browser extensions can drive Speedy.
+///
+/// `authorized` says whether the HTTP request carried the API token already.
+/// Otherwise each call has to pass it the way aria2 expects, as a
+/// `"token:<secret>"` first param.
+pub async fn handle(manager: &DownloadManager, token: &str, authorized: bool, body: Value) -> Value {
+    match body {
+        Value::Array(requests) if !requests.is_empty() => {
+            let mut responses = Vec::new();
+            for request in requests {
+                responses.push(respond(manager, token, authorized, request).await);
+            }
+            Value::Array(responses)
+        },
+        request => respond(manager, token, authorized, request).await,
+    }
+}
+
+/// Response to a body that isn't valid JSON
+pub fn parse_error(error: &SpeedyError) -> Value {
+    response(Value::Null, Err(RpcError::new(PARSE_ERROR, error.message())))
+}
+
+async fn respond(manager: &DownloadManager, token: &str, authorized: bool, request: Value) -> Value {
+    let id = request.get("id").cloned().unwrap_or(Value::Null);
+    let method = match request.get("method").and_then(Value::as_str) {
+        Some(method) => method,
+        None => return response(id, Err(RpcError::new(INVALID_REQUEST, "Missing method"))),
+    };
+    let params = match request.get("params") {
+        None | Some(Value::Null) => Vec::new(),
+        Some(Value::Array(params)) => params.clone(),
+        Some(_) => return response(id, Err(RpcError::invalid_params("params must be an array"))),
+    };
+
+    let result = if method == "system.multicall" {
+        multicall(manager, token, authorized, &params).await
+    } else {
+        call(manager, token, authorized, method, params).await
+    };
+    response(id, result)
+}
+
+fn response(id: Value, result: Result<Value, RpcError>) -> Value {
+    match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Err(e) => json!({ "jsonrpc": "2.0", "id": id, "error": { "code": e.code, "message": e.message } }),
+    }
+}
+
+/// Run `[{"methodName": ..., "params": [...]}, ...]` in order. Like aria2, each
+/// result is wrapped in a one-element array and a failed call gives its error object.
+async fn multicall(manager: &DownloadManager, token: &str, authorized: bool, params: &[Value]) -> Result<Value, RpcError> {
+    let calls = match params.first() {
+        Some(Value::Array(calls)) => calls,
+        _ => return Err(RpcError::invalid_params("Expected an array of calls")),
+    };
+
+    let mut results = Vec::new();
+    for entry in calls {
+        let method = entry.get("methodName").and_then(Value::as_str).unwrap_or("");
+        let params = match entry.get("params") {
+            Some(Value::Array(params)) => params.clone(),
+            _ => Vec::new(),
+        };
+        let result = if method == "system.multicall" {
+            Err(RpcError::new(CALL_FAILED, "system.multicall can't be nested"))
+        } else {
+            call(manager, token, authorized, method, params).await
+        };
+        results.push(match result {
+            Ok(value) => json!([value]),
+            Err(e) => json!({ "code": e.code, "message": e.message }),
+        });
+    }
+    Ok(Value::Array(results))
+}
+
+async fn call(manager: &DownloadManager, token: &str, authorized: bool, method: &str, mut params: Vec<Value>) -> Result<Value, RpcError> {
+    let secret = params.first().and_then(Value::as_str).and_then(|param| param.strip_prefix("token:")).map(str::to_string);
+    let secret_matches = match secret {
+        Some(secret) => {
+            params.remove(0);
+            control::tokens_match(&secret, token)
+        },
+        None => false,
+    };
+    if !authorized && !secret_matches {
+        return Err(RpcError::new(CALL_FAILED, "Unauthorized"));
+    }
+
+    match method {
+        "aria2.addUri" => {
+            let download_id = add_uri(manager, &params).await?;
+            Ok(Value::from(gid(download_id)))
+        },
+        "aria2.tellStatus" => {
+            let download = find(gid_param(&params, 0)?).await?;
+            Ok(select(status(manager, &download), &keys_param(&params, 1)?))
+        },
+        "aria2.tellActive" => {
+            let keys = keys_param(&params, 0)?;
+            let active = all_downloads().await?.into_iter().filter(|download| download.status.is_active());
+            Ok(active.map(|download| select(status(manager, &download), &keys)).collect())
+        },
+        "aria2.tellWaiting" => {
+            let waiting = page(waiting_downloads().await?, int_param(&params, 0)?, int_param(&params, 1)?);
+            let keys = keys_param(&params, 2)?;
+            Ok(waiting.iter().map(|download| select(status(manager, download), &keys)).collect())
+        },
+        "aria2.tellStopped" => {
+            let stopped = all_downloads().await?.into_iter().filter(is_stopped).collect();
+            let stopped = page(stopped, int_param(&params, 0)?, int_param(&params, 1)?);
+            let keys = keys_param(&params, 2)?;
+            Ok(stopped.iter().map(|download| select(status(manager, download), &keys)).collect())
+        },
+        "aria2.pause" => {
+            let download_id = gid_param(&params, 0)?;
+            manager.pause(download_id).await?;
+            Ok(Value::from(gid(download_id)))
+        },
+        "aria2.unpause" => {
+            let download_id = gid_param(&params, 0)?;
+            manager.resume(download_id).await?;
+            Ok(Value::from(gid(download_id)))
+        },
+        "aria2.remove" => {
+            let download = find(gid_param(&params, 0)?).await?;
+            // aria2 only removes downloads that haven't stopped, the rest stay listed as they are
+            if is_stopped(&download) {
+                return Err(RpcError::new(CALL_FAILED, format!("Active Download not found for GID#{}", gid(download.download_id))));
+            }
+            manager.cancel(download.download_id).await?;
+            Ok(Value::from(gid(download.download_id)))
+        },
+        "aria2.getGlobalStat" => {
+            let downloads = all_downloads().await?;
+            let speed: u64 = downloads.iter()
+                .filter_map(|download| manager.state(download.download_id))
+                .map(|state| state.lock().unwrap().get_average_speed() as u64)
+                .sum();
+            let count = |matches: fn(&Download) -> bool| downloads.iter().filter(|download| matches(download)).count().to_string();
+            let num_stopped = count(is_stopped);
+            Ok(json!({
+                "downloadSpeed": speed.to_string(),
+                "uploadSpeed": "0",
+                "numActive": count(|download| download.status.is_active()),
+                "numWaiting": count(is_waiting),
+                "numStopped": num_stopped,
+                "numStoppedTotal": num_stopped,
+            }))
+        },
+        "aria2.getVersion" => Ok(json!({ "version": env!("CARGO_PKG_VERSION"), "enabledFeatures": ["HTTPS"] })),
+        _ => Err(RpcError::new(METHOD_NOT_FOUND, format!("Method not found: {}", method))),
+    }
+}
+
+/// aria2.addUri: `[uris, options?, position?]`. Mirrors aren't used, only the first URI
+/// is downloaded. Understood options are `dir`, `out`, `split` and `checksum`.
+async fn add_uri(manager: &DownloadManager, params: &[Value]) -> Result<u64, RpcError> {
+    let url = match params.first() {
+        Some(Value::Array(uris)) => uris.first().and_then(Value::as_str),
+        _ => None,
+    };
+    let url = url.ok_or_else(|| RpcError::invalid_params("Expected an array of URIs"))?;
+    if !url.starts_with("http://") && !url.starts_with("https://") {
+        return Err(SpeedyError::invalid_input(format!("Unsupported URI: {}. Only http:// and https:// are supported", url)).into());
+    }
+
+    let options = match params.get(1) {
+        None | Some(Value::Null) => Map::new(),
+        Some(Value::Object(options)) => options.clone(),
+        Some(_) => return Err(RpcError::invalid_params("options must be an object")),
+    };
+    let option = |name: &str| options.get(name).and_then(Value::as_str).map(str::to_string);
+
+    let parts = match option("split") {
+        Some(split) => split.parse().ok().filter(|&parts| parts > 0)
+            .ok_or_else(|| RpcError::invalid_params(format!("Invalid split: {}", split)))?,
+        None => manager::DEFAULT_PARTS,
+    };
+    let mut request = DownloadRequest::new(url.to_string(), parts);
+    request.directory = option("dir");
+    request.name = option("out");
+    request.checksum = option("checksum");
+
+    let download_id = manager.add(request).await?;
+    if let Some(position) = params.get(2).and_then(Value::as_u64) {
+        queue::move_to(download_id, position as usize).await?;
+    }
+    Ok(download_id)
+}
+
+/// aria2 identifies downloads by a GID of 16 hex digits
+fn gid(download_id: u64) -> String {
+    format!("{:016x}", download_id)
+}
+
+fn gid_param(params: &[Value], index: usize) -> Result<u64, RpcError> {
+    let value = params.get(index).and_then(Value::as_str)
+        .ok_or_else(|| RpcError::invalid_params("Expected a GID"))?;
+    u64::from_str_radix(value, 16).map_err(|_| RpcError::invalid_params(format!("Invalid GID: {}", value)))
+}
+
+fn int_param(params: &[Value], index: usize) -> Result<i64, RpcError> {
+    params.get(index).and_then(Value::as_i64)
+        .ok_or_else(|| RpcError::invalid_params(format!("Expected an integer as param {}", index + 1)))
+}
+
+/// Keys to return in status objects, empty for all of them
+fn keys_param(params: &[Value], index: usize) -> Result<Vec<String>, RpcError> {
+    match params.get(index) {
+        None | Some(Value::Null) => Ok(Vec::new()),
+        Some(keys) => serde_json::from_value(keys.clone())
+            .map_err(|_| RpcError::invalid_params("keys must be an array of strings")),
+    }
+}
+
+async fn find(download_id: u64) -> Result<Download, SpeedyError> {
+    match db_manager::get_download(download_id).await {
+        Ok(Some(download)) => Ok(download),
+        Ok(None) => Err(SpeedyError::not_found(format!("No such download for GID#{}", gid(download_id)))),
+        Err(e) => Err(SpeedyError::from(e).context("Error retrieving download")),
+    }
+}
+
+async fn all_downloads() -> Result<Vec<Download>, SpeedyError> {
+    db_manager::list_downloads().await
+        .map_err(|e| SpeedyError::from(e).context("Failed to list downloads"))
+}
+
+/// Queued downloads in the order they start, then paused ones, which aria2 lists as waiting too
+async fn waiting_downloads() -> Result<Vec<Download>, SpeedyError> {
+    let mut waiting = db_manager::get_queued_downloads().await
+        .map_err(|e| SpeedyError::from(e).context("Failed to read queue"))?;
+    let paused = db_manager::get_downloads_by_status(DownloadStatus::Paused).await
+        .map_err(|e| SpeedyError::from(e).context("Failed to list downloads"))?;
+    waiting.extend(paused);
+    Ok(waiting)
+}
+
+fn is_waiting(download: &Download) -> bool {
+    matches!(download.status, DownloadStatus::Queued | DownloadStatus::Paused)
+}
+
+fn is_stopped(download: &Download) -> bool {
+    !download.status.is_active() && !is_waiting(download)
+}
+
+/// `num` entries from `offset`. A negative offset counts back from the last
+/// entry and returns entries in reverse, as aria2 does.
+fn page(downloads: Vec<Download>, offset: i64, num: i64) -> Vec<Download> {
+    let num = num.max(0) as usize;
+    if offset >= 0 {
+        downloads.into_iter().skip(offset as usize).take(num).collect()
+    } else {
+        let end = downloads.len() as i64 + offset + 1;
+        downloads.into_iter().take(end.max(0) as usize).rev().take(num).collect()
+    }
+}
+
+/// aria2's name for a status
+fn status_name(status: DownloadStatus) -> &'static str {
+    match status {
+        DownloadStatus::Probing | DownloadStatus::Downloading | DownloadStatus::Merging | DownloadStatus::Verifying => "active",
+        DownloadStatus::Queued => "waiting",
+        DownloadStatus::Paused => "paused",
+        DownloadStatus::Completed => "complete",
+        DownloadStatus::Cancelled => "removed",
+        DownloadStatus::Error | DownloadStatus::Incomplete | DownloadStatus::VerificationFailed
+            | DownloadStatus::Conflict | DownloadStatus::Skipped => "error",
+    }
+}
+
+/// aria2's exit status for the kind of error a download stopped with
+fn error_code(download: &Download) -> &'static str {
+    match download.status {
+        DownloadStatus::Completed => "0",
+        DownloadStatus::Cancelled => "5",
+        DownloadStatus::VerificationFailed => "32",
+        DownloadStatus::Conflict | DownloadStatus::Skipped => "13",
+        _ => match download.error_code.as_deref() {
+            Some("network") => "6",
+            Some("http_status") => "22",
+            Some("disk_full") => "9",
+            Some("io") => "17",
+            Some("checksum") => "32",
+            Some("conflict") => "13",
+            Some("not_found") => "3",
+            _ => "1",
+        },
+    }
+}
+
+/// Status object of a download as aria2.tellStatus describes it, with progress
+/// from the running task when there is one
+fn status(manager: &DownloadManager, download: &Download) -> Map<String, Value> {
+    let mut total_length = download.total_size;
+    let mut completed_length = if download.status == DownloadStatus::Completed {
+        download.total_size.max(download.downloaded_bytes)
+    } else {
+        download.downloaded_bytes
+    };
+    let mut download_speed = 0;
+    let mut connections = 0;
+    if let Some(state) = manager.state(download.download_id) {
+        let state = state.lock().unwrap();
+        if state.file_size > 0 {
+            total_length = state.file_size;
+        }
+        completed_length = completed_length.max(state.total_downloaded);
+        download_speed = state.get_average_speed() as u64;
+        connections = state.segment_sizes.iter()
+            .filter(|(segment_id, &size)| state.segment_progress.get(segment_id).copied().unwrap_or(0) < size)
+            .count();
+    }
+
+    let path = match &download.save_path {
+        Some(save_path) => PathBuf::from(save_path),
+        None => manager::output_path(download),
+    };
+    let dir = path.parent().map(|dir| dir.to_string_lossy().into_owned()).unwrap_or_default();
+
+    let mut map = Map::new();
+    map.insert("gid".to_string(), Value::from(gid(download.download_id)));
+    map.insert("status".to_string(), Value::from(status_name(download.status)));
+    map.insert("totalLength".to_string(), Value::from(total_length.to_string()));
+    map.insert("completedLength".to_string(), Value::from(completed_length.to_string()));
+    map.insert("uploadLength".to_string(), Value::from("0"));
+    map.insert("downloadSpeed".to_string(), Value::from(download_speed.to_string()));
+    map.insert("uploadSpeed".to_string(), Value::from("0"));
+    map.insert("connections".to_string(), Value::from(connections.to_string()));
+    map.insert("dir".to_string(), Value::from(dir));
+    map.insert("files".to_string(), json!([{
+        "index": "1",
+        "path": path.to_string_lossy(),
+        "length": total_length.to_string(),
+        "completedLength": completed_length.to_string(),
+        "selected": "true",
+        "uris": [{ "uri": download.url, "status": "used" }],
+    }]));
+    if is_stopped(download) {
+        map.insert("errorCode".to_string(), Value::from(error_code(download)));
+    }
+    if let Some(error_message) = &download.error_message {
+        map.insert("errorMessage".to_string(), Value::from(error_message.as_str()));
+    }
+    map
+}
+
+/// Only the requested keys of a status object, all of them when none are given
+fn select(mut map: Map<String, Value>, keys: &[String]) -> Value {
+    if !keys.is_empty() {
+        map.retain(|key, _| keys.contains(key));
+    }
+    Value::Object(map)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOKEN: &str = "s3cret";
+
+    fn download(download_id: u64, status: DownloadStatus) -> Download {
+        let mut download = Download::new(download_id, format!("http://127.0.0.1:9/{}.bin", download_id),
+                                          format!("{}.bin", download_id), 100, 1);
+        download.status = status;
+        download
+    }
+
+    fn ids(downloads: Vec<Download>) -> Vec<u64> {
+        downloads.iter().map(|download| download.download_id).collect()
+    }
+
+    fn downloads() -> Vec<Download> {
+        (1..=5).map(|download_id| download(download_id, DownloadStatus::Queued)).collect()
+    }
+
+    fn error(result: Result<Value, RpcError>) -> (i64, String) {
+        match result {
+            Ok(value) => panic!("Expected an error, got {}", value),
+            Err(e) => (e.code, e.message),
+        }
+    }
+
+    #[test]
+    fn pages_forwards_from_a_positive_offset() {
+        assert_eq!(ids(page(downloads(), 0, 2)), vec![1, 2]);
+        assert_eq!(ids(page(downloads(), 3, 10)), vec![4, 5]);
+        assert_eq!(ids(page(downloads(), 5, 2)), Vec::<u64>::new());
+        assert_eq!(ids(page(downloads(), 0, -1)), Vec::<u64>::new());
+    }
+
+    #[test]
+    fn pages_backwards_from_a_negative_offset() {
+        assert_eq!(ids(page(downloads(), -1, 2)), vec![5, 4]);
+        assert_eq!(ids(page(downloads(), -2, 10)), vec![4, 3, 2, 1]);
+        assert_eq!(ids(page(downloads(), -5, 3)), vec![1]);
+        assert_eq!(ids(page(downloads(), -6, 3)), Vec::<u64>::new());
+    }
+
+    #[test]
+    fn formats_and_parses_gids() {
+        assert_eq!(gid(1), "0000000000000001");
+        assert_eq!(gid(0xdeadbeef), "00000000deadbeef");
+        assert_eq!(gid(u64::MAX), "ffffffffffffffff");
+
+        let params = [Value::from(gid(0xdeadbeef)), Value::from("2089b05ecca3d829"), Value::from("xyz"), Value::from(1)];
+        assert_eq!(gid_param(&params, 0).ok(), Some(0xdeadbeef));
+        assert_eq!(gid_param(&params, 1).ok(), Some(0x2089b05ecca3d829));
+        for index in [2, 3, 4] {
+            assert_eq!(gid_param(&params, index).err().map(|e| e.code), Some(INVALID_PARAMS), "{}", index);
+        }
+    }
+
+    #[test]
+    fn maps_statuses_to_aria2_names() {
+        let cases = [
+            (DownloadStatus::Probing, "active"),
+            (DownloadStatus::Downloading, "active"),
+            (DownloadStatus::Merging, "active"),
+            (DownloadStatus::Verifying, "active"),
+            (DownloadStatus::Queued, "waiting"),
+            (DownloadStatus::Paused, "paused"),
+            (DownloadStatus::Completed, "complete"),
+            (DownloadStatus::Cancelled, "removed"),
+            (DownloadStatus::Error, "error"),
+            (DownloadStatus::Incomplete, "error"),
+            (DownloadStatus::VerificationFailed, "error"),
+            (DownloadStatus::Conflict, "error"),
+            (DownloadStatus::Skipped, "error"),
+        ];
+        for (status, name) in cases {
+            assert_eq!(status_name(status), name, "{:?}", status);
+        }
+    }
+
+    #[test]
+    fn maps_errors_to_aria2_exit_codes() {
+        assert_eq!(error_code(&download(1, DownloadStatus::Completed)), "0");
+        assert_eq!(error_code(&download(1, DownloadStatus::Cancelled)), "5");
+        assert_eq!(error_code(&download(1, DownloadStatus::VerificationFailed)), "32");
+        assert_eq!(error_code(&download(1, DownloadStatus::Conflict)), "13");
+
+        let cases = [
+            (Some("network"), "6"),
+            (Some("http_status"), "22"),
+            (Some("disk_full"), "9"),
+            (Some("io"), "17"),
+            (Some("checksum"), "32"),
+            (Some("not_found"), "3"),
+            (Some("something_new"), "1"),
+            (None, "1"),
+        ];
+        for (code, exit_code) in cases {
+            let mut failed = download(1, DownloadStatus::Error);
+            failed.error_code = code.map(str::to_string);
+            assert_eq!(error_code(&failed), exit_code, "{:?}", code);
+        }
+    }
+
+    #[tokio::test]
+    async fn refuses_unauthorized_calls_without_the_token() {
+        let manager = DownloadManager::default();
+        let unauthorized = (CALL_FAILED, "Unauthorized".to_string());
+
+        assert_eq!(error(call(&manager, TOKEN, false, "aria2.getVersion", vec![]).await), unauthorized);
+        let wrong = vec![Value::from("token:wrong")];
+        assert_eq!(error(call(&manager, TOKEN, false, "aria2.getVersion", wrong).await), unauthorized);
+        // The token has to come first
+        let late = vec![Value::from("0000000000000001"), Value::from("token:s3cret")];
+        assert_eq!(error(call(&manager, TOKEN, false, "aria2.pause", late).await), unauthorized);
+
+        let response = handle(&manager, TOKEN, false, json!({ "jsonrpc": "2.0", "id": "1", "method": "aria2.getVersion" })).await;
+        assert_eq!(response["id"], "1");
+        assert_eq!(response["error"]["code"], CALL_FAILED);
+        assert!(response.get("result").is_none());
+    }
+
+    #[tokio::test]
+    async fn accepts_and_strips_the_token_param() {
+        let manager = DownloadManager::default();
+        let with_token = vec![Value::from("token:s3cret")];
+        let version = call(&manager, TOKEN, false, "aria2.getVersion", with_token).await.ok().unwrap();
+        assert_eq!(version["version"], env!("CARGO_PKG_VERSION"));
+
+        // The GID is read after the token, which isn't mistaken for it
+        let params = vec![Value::from("token:s3cret"), Value::from("xyz")];
+        assert_eq!(error(call(&manager, TOKEN, false, "aria2.pause", params).await),
+                   (INVALID_PARAMS, "Invalid GID: xyz".to_string()));
+        // Clients that authorized over HTTP may still send it
+        let params = vec![Value::from("token:anything"), Value::from("xyz")];
+        assert_eq!(error(call(&manager, TOKEN, true, "aria2.pause", params).await).0, INVALID_PARAMS);
+        assert!(call(&manager, TOKEN, true, "aria2.getVersion", vec![]).await.is_ok());
+    }
+}
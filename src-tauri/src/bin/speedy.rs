@@ -0,0 +1,403 @@
+// Command-line front end of the download engine, for downloading without the app
+
+use std::path::PathBuf;
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use serde_json::{Map, Value};
+use tokio::sync::mpsc;
+use tokio::time;
+use tauri_app::client::DownloadEvent;
+use tauri_app::db::Download;
+use tauri_app::db_manager;
+use tauri_app::error::SpeedyError;
+use tauri_app::manager::{self, DownloadManager, DownloadRequest, EventSink, DEFAULT_PARTS};
+use tauri_app::status::DownloadStatus;
+
+const USAGE: &str = "Usage: speedy [--db PATH] <command>
+
+Commands:
+  get <url> [--parts N] [--out DIR] [--name NAME] [--checksum ALGO:HEX] [--checksum-url URL] [--quiet]
+                     Download a file, Ctrl-C pauses it
+  list               List downloads
+  resume <id> [--quiet]
+                     Continue a paused or failed download
+  pause <id> [--force]
+                     Pause a queued download so it isn't started
+  rm <id> [--delete-file] [--force]
+                     Remove a download, and the downloaded file with --delete-file
+
+Downloads another speedy is running are left alone, --force takes over one whose
+speedy was killed before it could pause it.
+
+Options:
+  --db PATH          Database to keep downloads in, instead of the one in the data directory
+
+Downloads made here are kept apart from the app's, so the two never run the same download.";
+
+/// How often the progress line is redrawn
+const RENDER_INTERVAL: Duration = Duration::from_millis(200);
+
+// Exit codes, so scripts can tell failures apart without parsing messages
+const EXIT_OK: i32 = 0;
+const EXIT_FAILURE: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+const EXIT_INTERRUPTED: i32 = 130;
+
+/// Exit code for an error code stored with a download or carried by a SpeedyError
+fn exit_code(error_code: &str) -> i32 {
+    match error_code {
+        "invalid_input" => EXIT_USAGE,
+        "network" => 3,
+        "http_status" => 4,
+        "io" => 5,
+        "disk_full" => 6,
+        "checksum" => 7,
+        "conflict" => 8,
+        "incomplete" => 9,
+        "db" => 10,
+        "not_found" => 11,
+        _ => EXIT_FAILURE,
+    }
+}
+
+enum Command {
+    Get { request: DownloadRequest, quiet: bool },
+    List,
+    Resume { download_id: u64, quiet: bool },
+    Pause { download_id: u64, force: bool },
+    Rm { download_id: u64, delete_file: bool, force: bool },
+}
+
+struct Cli {
+    db: Option<PathBuf>,
+    command: Command,
+}
+
+fn parse_id(value: Option<String>) -> Result<u64, String> {
+    let value = value.ok_or("Missing download ID")?;
+    value.parse().map_err(|_| format!("Invalid download ID: {}", value))
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Cli, String> {
+    let mut db = None;
+    let mut positional = Vec::new();
+    let mut parts = DEFAULT_PARTS;
+    let mut out = None;
+    let mut name = None;
+    let mut checksum = None;
+    let mut checksum_url = None;
+    let mut quiet = false;
+    let mut delete_file = false;
+    let mut force = false;
+
+    while let Some(arg) = args.next() {
+        let mut value = |flag: &str| args.next().ok_or_else(|| format!("{} needs a value", flag));
+        match arg.as_str() {
+            "--db" => db = Some(PathBuf::from(value("--db")?)),
+            "--parts" => {
+                let parts_value = value("--parts")?;
+                parts = parts_value.parse().ok().filter(|&parts| parts > 0)
+                    .ok_or_else(|| format!("Invalid number of parts: {}", parts_value))?;
+            },
+            "--out" => out = Some(value("--out")?),
+            "--name" => name = Some(value("--name")?),
+            "--checksum" => checksum = Some(value("--checksum")?),
+            "--checksum-url" => checksum_url = Some(value("--checksum-url")?),
+            "--quiet" | "-q" => quiet = true,
+            "--delete-file" => delete_file = true,
+            "--force" => force = true,
+            _ if arg.starts_with('-') => return Err(format!("Unknown option: {}", arg)),
+            _ => positional.push(arg),
+        }
+    }
+
+    let mut positional = positional.into_iter();
+    let command = match positional.next().as_deref() {
+        Some("get") => {
+            let url = positional.next().ok_or("Missing URL")?;
+            let mut request = DownloadRequest::new(url, parts);
+            request.name = name;
+            // The engine wants an absolute directory
+            request.directory = match out {
+                Some(out) => Some(std::env::current_dir()
+                    .map_err(|e| format!("Failed to read the current directory: {}", e))?
+                    .join(out)
+                    .to_string_lossy()
+                    .into_owned()),
+                None => None,
+            };
+            request.checksum = checksum;
+            request.checksum_url = checksum_url;
+            Command::Get { request, quiet }
+        },
+        Some("list") => Command::List,
+        Some("resume") => Command::Resume { download_id: parse_id(positional.next())?, quiet },
+        Some("pause") => Command::Pause { download_id: parse_id(positional.next())?, force },
+        Some("rm") => Command::Rm { download_id: parse_id(positional.next())?, delete_file, force },
+        Some(command) => return Err(format!("Unknown command: {}", command)),
+        None => return Err("Missing command".to_string()),
+    };
+    if let Some(extra) = positional.next() {
+        return Err(format!("Unexpected argument: {}", extra));
+    }
+    Ok(Cli { db, command })
+}
+
+/// Database the CLI uses unless --db says otherwise, next to the app's
+fn default_db_path() -> PathBuf {
+    let mut path = dirs::data_dir()
+        .unwrap_or_else(|| std::env::current_dir().unwrap_or_else(|_| PathBuf::from(".")));
+    path.push("speedy");
+    std::fs::create_dir_all(&path).unwrap_or(());
+    path.push("cli.db");
+    path
+}
+
+/// What the progress line shows, built from the client's events
+struct TerminalProgress {
+    phase: &'static str,
+    file_size: u64,
+    received: u64,
+    phase_done: u64,  // bytes merged or hashed
+    phase_total: u64,
+    speed: f64,       // smoothed bytes per second
+    last_received: u64,
+    last_render: Instant,
+}
+
+impl TerminalProgress {
+    fn new() -> Self {
+        Self {
+            phase: "starting",
+            file_size: 0,
+            received: 0,
+            phase_done: 0,
+            phase_total: 0,
+            speed: 0.0,
+            last_received: 0,
+            last_render: Instant::now(),
+        }
+    }
+
+    fn apply(&mut self, event: &DownloadEvent) {
+        match event {
+            DownloadEvent::Initialize { file_size, .. } => {
+                // Bytes already on disk are reported again by the segments
+                self.phase = "downloading";
+                self.file_size = *file_size;
+                self.received = 0;
+                self.last_received = 0;
+            },
+            DownloadEvent::BytesReceived { bytes, .. } => self.received += bytes,
+            DownloadEvent::Merging { merged_bytes, total_bytes } => {
+                self.phase = "merging";
+                self.phase_done = *merged_bytes;
+                self.phase_total = *total_bytes;
+            },
+            DownloadEvent::Verifying { hashed_bytes, total_bytes } => {
+                self.phase = "verifying";
+                self.phase_done = *hashed_bytes;
+                self.phase_total = *total_bytes;
+            },
+            _ => {},
+        }
+    }
+
+    fn line(&mut self) -> String {
+        let elapsed = self.last_render.elapsed().as_secs_f64();
+        if elapsed > 0.0 {
+            let current = self.received.saturating_sub(self.last_received) as f64 / elapsed;
+            self.speed = if self.speed == 0.0 { current } else { self.speed * 0.7 + current * 0.3 };
+        }
+        self.last_received = self.received;
+        self.last_render = Instant::now();
+
+        match self.phase {
+            "merging" | "verifying" => format!("{} {:5.1}%", self.phase, percent(self.phase_done, self.phase_total)),
+            // A server that doesn't send the size leaves only the byte count
+            _ if self.file_size == 0 => format!("{} {} {}/s", self.phase, format_bytes(self.received), format_bytes(self.speed as u64)),
+            _ => format!("{} {:5.1}% {} / {} {}/s", self.phase, percent(self.received, self.file_size),
+                         format_bytes(self.received), format_bytes(self.file_size), format_bytes(self.speed as u64)),
+        }
+    }
+}
+
+fn percent(done: u64, total: u64) -> f64 {
+    if total == 0 { 0.0 } else { (done as f64 / total as f64 * 100.0).min(100.0) }
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 { format!("{} B", bytes) } else { format!("{:.1} {}", value, UNITS[unit]) }
+}
+
+/// Feeds the progress line of one download and reports when its run ends
+struct TerminalSink {
+    download_id: u64,
+    progress: Arc<Mutex<TerminalProgress>>,
+    finished: mpsc::UnboundedSender<Download>,
+}
+
+impl EventSink for TerminalSink {
+    // The progress line is built from the events instead
+    fn progress(&self, _download_id: u64, _payload: &Map<String, Value>) {}
+
+    fn event(&self, download_id: u64, event: &DownloadEvent) {
+        if download_id == self.download_id {
+            self.progress.lock().unwrap().apply(event);
+        }
+    }
+
+    fn finished(&self, download: &Download) {
+        if download.download_id == self.download_id {
+            let _ = self.finished.send(download.clone());
+        }
+    }
+}
+
+/// Watch a download the manager is about to run until it finishes or Ctrl-C pauses it
+fn watch(manager: &DownloadManager, download_id: u64) -> (Arc<Mutex<TerminalProgress>>, mpsc::UnboundedReceiver<Download>) {
+    let progress = Arc::new(Mutex::new(TerminalProgress::new()));
+    let (finished_tx, finished_rx) = mpsc::unbounded_channel();
+    manager.add_sink(TerminalSink { download_id, progress: progress.clone(), finished: finished_tx });
+    (progress, finished_rx)
+}
+
+async fn wait_for(manager: &DownloadManager, download_id: u64, progress: Arc<Mutex<TerminalProgress>>,
+                  mut finished: mpsc::UnboundedReceiver<Download>, quiet: bool) -> i32 {
+    let mut interval = time::interval(RENDER_INTERVAL);
+    let download = loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                if !quiet {
+                    eprint!("\r\x1b[K{}", progress.lock().unwrap().line());
+                }
+            },
+            download = finished.recv() => match download {
+                Some(download) => break download,
+                None => return EXIT_FAILURE,
+            },
+            _ = tokio::signal::ctrl_c() => {
+                if !quiet {
+                    eprintln!();
+                }
+                return match manager.pause(download_id).await {
+                    Ok(_) => {
+                        eprintln!("Paused, continue with: speedy resume {}", download_id);
+                        EXIT_INTERRUPTED
+                    },
+                    Err(e) => fail(&e),
+                };
+            },
+        }
+    };
+    if !quiet {
+        eprintln!("\r\x1b[K{}", progress.lock().unwrap().line());
+    }
+
+    match download.status {
+        DownloadStatus::Completed => {
+            println!("{}", download.save_path.unwrap_or(download.filename));
+            EXIT_OK
+        },
+        DownloadStatus::Paused => {
+            eprintln!("Paused, continue with: speedy resume {}", download_id);
+            EXIT_INTERRUPTED
+        },
+        status => {
+            eprintln!("Download {} ended {}: {}", download_id, status,
+                      download.error_message.as_deref().unwrap_or("no error recorded"));
+            exit_code(download.error_code.as_deref().unwrap_or("other"))
+        }
+    }
+}
+
+fn fail(error: &SpeedyError) -> i32 {
+    eprintln!("{}", error);
+    exit_code(error.code())
+}
+
+/// Manager for changing downloads another speedy may be running, unless `force`
+/// says the one running it was killed
+async fn open_manager(db_path: PathBuf, force: bool) -> DownloadManager {
+    if force {
+        db_manager::init_db_at(Some(db_path)).await;
+        DownloadManager::default()
+    } else {
+        DownloadManager::open_shared(Some(db_path)).await
+    }
+}
+
+async fn run(cli: Cli) -> i32 {
+    let db_path = cli.db.unwrap_or_else(default_db_path);
+    match cli.command {
+        Command::Get { mut request, quiet } => {
+            let manager = DownloadManager::open_shared(Some(db_path)).await;
+            // Picked here so the progress line follows the download from its first event
+            let download_id = match manager::unused_download_id().await {
+                Ok(download_id) => download_id,
+                Err(e) => return fail(&e),
+            };
+            request.download_id = Some(download_id);
+            let (progress, finished) = watch(&manager, download_id);
+            if let Err(e) = manager.add(request).await {
+                return fail(&e);
+            }
+            eprintln!("Download {} started", download_id);
+            wait_for(&manager, download_id, progress, finished, quiet).await
+        },
+        Command::Resume { download_id, quiet } => {
+            let manager = DownloadManager::open_shared(Some(db_path)).await;
+            let (progress, finished) = watch(&manager, download_id);
+            if let Err(e) = manager.resume(download_id).await {
+                return fail(&e);
+            }
+            wait_for(&manager, download_id, progress, finished, quiet).await
+        },
+        Command::List => {
+            db_manager::init_db_at(Some(db_path)).await;
+            let downloads = match db_manager::list_downloads().await {
+                Ok(downloads) => downloads,
+                Err(e) => return fail(&SpeedyError::from(e).context("Failed to list downloads")),
+            };
+            println!("{:<15} {:<20} {:>6} {:>11}  NAME", "ID", "STATUS", "DONE", "SIZE");
+            for download in downloads {
+                println!("{:<15} {:<20} {:>5.1}% {:>11}  {}", download.download_id, download.status.as_str(),
+                         percent(download.downloaded_bytes, download.total_size),
+                         format_bytes(download.total_size), download.filename);
+            }
+            EXIT_OK
+        },
+        Command::Pause { download_id, force } => {
+            match open_manager(db_path, force).await.pause(download_id).await {
+                Ok(_) => EXIT_OK,
+                Err(e) => fail(&e),
+            }
+        },
+        Command::Rm { download_id, delete_file, force } => {
+            match open_manager(db_path, force).await.remove(download_id, delete_file).await {
+                Ok(_) => EXIT_OK,
+                Err(e) => fail(&e),
+            }
+        },
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let code = match parse_args(std::env::args().skip(1)) {
+        Ok(cli) => run(cli).await,
+        Err(message) => {
+            eprintln!("{}\n\n{}", message, USAGE);
+            EXIT_USAGE
+        }
+    };
+    process::exit(code);
+}
@@ -0,0 +1,345 @@
+use md5::Md5;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use crate::error::SpeedyError;
+use std::error::Error;
+use std::fmt;
+use std::io::Read;
+use std::path::Path;
+
+/// Hash algorithms a download can be verified with
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChecksumAlgorithm {
+    Sha256,
+    Sha1,
+    Md5,
+}
+
+impl ChecksumAlgorithm {
+    /// Parse an algorithm name such as `sha256`, `SHA-256` or `md5`
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.trim().to_ascii_lowercase().replace('-', "").as_str() {
+            "sha256" => Some(Self::Sha256),
+            "sha1" => Some(Self::Sha1),
+            "md5" => Some(Self::Md5),
+            _ => None,
+        }
+    }
+
+    /// Guess the algorithm from the length of a hex digest
+    pub fn from_digest_len(len: usize) -> Option<Self> {
+        match len {
+            64 => Some(Self::Sha256),
+            40 => Some(Self::Sha1),
+            32 => Some(Self::Md5),
+            _ => None,
+        }
+    }
+
+    /// Guess the algorithm from the name of a checksum file, e.g. `file.sha256` or `SHA1SUMS`
+    pub fn from_url(url: &str) -> Option<Self> {
+        let name = url.split('?').next().unwrap_or(url);
+        let name = name.rsplit('/').next().unwrap_or(name).to_ascii_lowercase();
+        if name.contains("sha256") || name.contains("sha-256") {
+            Some(Self::Sha256)
+        } else if name.contains("sha1") || name.contains("sha-1") {
+            Some(Self::Sha1)
+        } else if name.contains("md5") {
+            Some(Self::Md5)
+        } else {
+            None
+        }
+    }
+
+    /// Length of a digest in hex characters
+    fn digest_len(&self) -> usize {
+        match self {
+            Self::Sha256 => 64,
+            Self::Sha1 => 40,
+            Self::Md5 => 32,
+        }
+    }
+}
+
+impl fmt::Display for ChecksumAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Sha256 => write!(f, "sha256"),
+            Self::Sha1 => write!(f, "sha1"),
+            Self::Md5 => write!(f, "md5"),
+        }
+    }
+}
+
+/// A digest a download is expected to have
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExpectedChecksum {
+    pub algorithm: ChecksumAlgorithm,
+    pub digest: String, // lowercase hex
+}
+
+impl ExpectedChecksum {
+    pub fn new(algorithm: ChecksumAlgorithm, digest: &str) -> Result<Self, String> {
+        let digest = digest.trim().to_ascii_lowercase();
+        if digest.len() != algorithm.digest_len() || !digest.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(format!("'{}' is not a valid {} digest", digest, algorithm));
+        }
+        Ok(Self { algorithm, digest })
+    }
+
+    /// Parse `algorithm:hex` (also `algorithm=hex`), or a bare hex digest whose
+    /// algorithm is guessed from its length
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let value = value.trim();
+        match value.split_once([':', '=']) {
+            Some((algorithm, digest)) => {
+                let algorithm = ChecksumAlgorithm::parse(algorithm)
+                    .ok_or_else(|| format!("Unsupported checksum algorithm '{}', use sha256, sha1 or md5", algorithm))?;
+                Self::new(algorithm, digest)
+            },
+            None => {
+                let algorithm = ChecksumAlgorithm::from_digest_len(value.len())
+                    .ok_or_else(|| format!("Can't tell the algorithm of checksum '{}', prefix it with sha256:, sha1: or md5:", value))?;
+                Self::new(algorithm, value)
+            }
+        }
+    }
+}
+
+impl fmt::Display for ExpectedChecksum {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.algorithm, self.digest)
+    }
+}
+
+/// Where the expected checksum of a download comes from
+#[derive(Clone, Debug)]
+pub enum ChecksumSource {
+    Digest(ExpectedChecksum),
+    Url(String), // a `.sha256` style file or a `SHA256SUMS` style listing
+}
+
+/// Incremental hasher for any of the supported algorithms
+pub enum Hasher {
+    Sha256(Sha256),
+    Sha1(Sha1),
+    Md5(Md5),
+}
+
+impl Hasher {
+    pub fn new(algorithm: ChecksumAlgorithm) -> Self {
+        match algorithm {
+            ChecksumAlgorithm::Sha256 => Self::Sha256(Sha256::new()),
+            ChecksumAlgorithm::Sha1 => Self::Sha1(Sha1::new()),
+            ChecksumAlgorithm::Md5 => Self::Md5(Md5::new()),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Sha256(hasher) => hasher.update(data),
+            Self::Sha1(hasher) => hasher.update(data),
+            Self::Md5(hasher) => hasher.update(data),
+        }
+    }
+
+    /// Finish hashing and return the digest as lowercase hex
+    pub fn finalize_hex(self) -> String {
+        match self {
+            Self::Sha256(hasher) => format!("{:x}", hasher.finalize()),
+            Self::Sha1(hasher) => format!("{:x}", hasher.finalize()),
+            Self::Md5(hasher) => format!("{:x}", hasher.finalize()),
+        }
+    }
+}
+
+/// The downloaded file doesn't have the digest it was expected to have
+#[derive(Debug)]
+pub struct ChecksumMismatch {
+    pub expected: ExpectedChecksum,
+    pub actual: String,
+}
+
+impl fmt::Display for ChecksumMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Checksum mismatch: expected {} but the downloaded file has {}:{}",
+               self.expected, self.expected.algorithm, self.actual)
+    }
+}
+
+impl Error for ChecksumMismatch {}
+
+/// Find the digest of `file_name` in the contents of a checksum file.
+///
+/// Understands GNU style listings (`<hex>  <name>`, `*` marking binary mode), BSD
+/// style lines (`SHA256 (<name>) = <hex>`) and files holding a single digest.
+pub fn parse_checksum_file(content: &str, file_name: &str, hint: Option<ChecksumAlgorithm>) -> Option<ExpectedChecksum> {
+    let mut entries: Vec<(Option<ChecksumAlgorithm>, &str, Option<&str>)> = Vec::new();
+    for line in content.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        if let Some((left, digest)) = line.rsplit_once(" = ") {
+            if let Some((algorithm, name)) = left.split_once(" (") {
+                entries.push((ChecksumAlgorithm::parse(algorithm), digest.trim(), Some(name.trim_end_matches(')'))));
+                continue;
+            }
+        }
+
+        let mut fields = line.splitn(2, char::is_whitespace);
+        let digest = fields.next().unwrap_or_default();
+        let name = fields.next().map(|name| name.trim().trim_start_matches('*'));
+        entries.push((None, digest, name));
+    }
+
+    // Listings may name files with a directory prefix
+    let names_file = |name: Option<&str>| {
+        name.map(|name| name.rsplit('/').next().unwrap_or(name) == file_name).unwrap_or(false)
+    };
+    let (algorithm, digest, _) = entries
+        .iter()
+        .find(|(_, _, name)| names_file(*name))
+        .or_else(|| if entries.len() == 1 { entries.first() } else { None })?;
+
+    let algorithm = algorithm.or(hint).or_else(|| ChecksumAlgorithm::from_digest_len(digest.len()))?;
+    ExpectedChecksum::new(algorithm, digest).ok()
+}
+
+/// Download a checksum file and find the digest of `file_name` in it
+pub async fn fetch_checksum(client: &reqwest::Client, url: &str, file_name: &str) -> Result<ExpectedChecksum, SpeedyError> {
+    let response = client.get(url).send().await
+        .map_err(|e| SpeedyError::Network { message: format!("Failed to fetch checksum file {}: {}", url, e) })?;
+    if !response.status().is_success() {
+        return Err(SpeedyError::HttpStatus {
+            status: response.status().as_u16(),
+            message: format!("Server returned error status {} for checksum file {}", response.status(), url),
+        });
+    }
+    let content = response.text().await
+        .map_err(|e| SpeedyError::Network { message: format!("Failed to read checksum file {}: {}", url, e) })?;
+
+    parse_checksum_file(&content, file_name, ChecksumAlgorithm::from_url(url))
+        .ok_or_else(|| SpeedyError::Checksum { message: format!("Checksum file {} has no digest for {}", url, file_name) })
+}
+
+/// Hash a file with a bounded buffer, calling `progress` with the bytes hashed so far.
+/// Uses blocking I/O, so run it off the async runtime.
+pub fn hash_file(path: &Path, algorithm: ChecksumAlgorithm, mut progress: impl FnMut(u64)) -> std::io::Result<String> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Hasher::new(algorithm);
+    let mut buffer = vec![0; 64 * 1024];
+    let mut hashed = 0u64;
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+        hashed += read as u64;
+        progress(hashed);
+    }
+    Ok(hasher.finalize_hex())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHA256: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+    const SHA1: &str = "a9993e364706816aba3e25717850c26c9cd0d89d";
+    const MD5: &str = "900150983cd24fb0d6963f7d28e17f72";
+
+    #[test]
+    fn parses_algorithm_names() {
+        assert_eq!(ChecksumAlgorithm::parse("sha256"), Some(ChecksumAlgorithm::Sha256));
+        assert_eq!(ChecksumAlgorithm::parse(" SHA-256 "), Some(ChecksumAlgorithm::Sha256));
+        assert_eq!(ChecksumAlgorithm::parse("SHA1"), Some(ChecksumAlgorithm::Sha1));
+        assert_eq!(ChecksumAlgorithm::parse("md5"), Some(ChecksumAlgorithm::Md5));
+        assert_eq!(ChecksumAlgorithm::parse("sha512"), None);
+    }
+
+    #[test]
+    fn detects_algorithm_from_digest_length() {
+        assert_eq!(ChecksumAlgorithm::from_digest_len(SHA256.len()), Some(ChecksumAlgorithm::Sha256));
+        assert_eq!(ChecksumAlgorithm::from_digest_len(SHA1.len()), Some(ChecksumAlgorithm::Sha1));
+        assert_eq!(ChecksumAlgorithm::from_digest_len(MD5.len()), Some(ChecksumAlgorithm::Md5));
+        assert_eq!(ChecksumAlgorithm::from_digest_len(128), None);
+    }
+
+    #[test]
+    fn detects_algorithm_from_checksum_file_url() {
+        assert_eq!(ChecksumAlgorithm::from_url("https://example.com/SHA256SUMS"), Some(ChecksumAlgorithm::Sha256));
+        assert_eq!(ChecksumAlgorithm::from_url("https://example.com/a.iso.sha-1?raw=1"), Some(ChecksumAlgorithm::Sha1));
+        assert_eq!(ChecksumAlgorithm::from_url("https://example.com/a.iso.md5"), Some(ChecksumAlgorithm::Md5));
+        assert_eq!(ChecksumAlgorithm::from_url("https://sha256.example.com/CHECKSUMS"), None);
+    }
+
+    #[test]
+    fn parses_expected_checksum() {
+        let expected = ExpectedChecksum::parse(&format!("sha256:{}", SHA256.to_uppercase())).unwrap();
+        assert_eq!(expected, ExpectedChecksum { algorithm: ChecksumAlgorithm::Sha256, digest: SHA256.to_string() });
+        assert_eq!(expected.to_string(), format!("sha256:{}", SHA256));
+
+        assert_eq!(ExpectedChecksum::parse(&format!("SHA-1={}", SHA1)).unwrap().algorithm, ChecksumAlgorithm::Sha1);
+        assert_eq!(ExpectedChecksum::parse(MD5).unwrap().algorithm, ChecksumAlgorithm::Md5);
+
+        assert!(ExpectedChecksum::parse(&format!("sha512:{}", SHA256)).is_err());
+        assert!(ExpectedChecksum::parse(&format!("sha256:{}", SHA1)).is_err());
+        assert!(ExpectedChecksum::parse(&SHA1.replace('a', "g")).is_err());
+        assert!(ExpectedChecksum::parse("abc123").is_err());
+    }
+
+    #[test]
+    fn finds_file_in_gnu_listing() {
+        let listing = format!(
+            "# release checksums\n\n{}  other.tar.gz\n{} *dist/app.tar.gz\n{}  app.tar.gz.sig\n",
+            SHA1.replace('a', "b"), SHA1, SHA1.replace('a', "c"),
+        );
+        let expected = parse_checksum_file(&listing, "app.tar.gz", None).unwrap();
+        assert_eq!(expected.algorithm, ChecksumAlgorithm::Sha1);
+        assert_eq!(expected.digest, SHA1);
+
+        assert_eq!(parse_checksum_file(&listing, "missing.tar.gz", None), None);
+    }
+
+    #[test]
+    fn finds_file_in_bsd_listing() {
+        let listing = format!("MD5 (other.iso) = {}\nSHA256 (app.iso) = {}\n", MD5, SHA256);
+        let expected = parse_checksum_file(&listing, "app.iso", None).unwrap();
+        assert_eq!(expected, ExpectedChecksum { algorithm: ChecksumAlgorithm::Sha256, digest: SHA256.to_string() });
+        assert_eq!(parse_checksum_file(&listing, "other.iso", None).unwrap().algorithm, ChecksumAlgorithm::Md5);
+    }
+
+    #[test]
+    fn uses_single_digest_whatever_its_name() {
+        // A `.sha256` file next to the download, even when the download was renamed
+        assert_eq!(parse_checksum_file(&format!("{}\n", SHA256), "app.iso", None).unwrap().digest, SHA256);
+        assert_eq!(parse_checksum_file(&format!("{}  upstream-name.iso", MD5), "app.iso", None).unwrap().digest, MD5);
+    }
+
+    #[test]
+    fn algorithm_hint_must_match_digest() {
+        let sha1_file = format!("{}  app.iso", SHA1);
+        assert_eq!(parse_checksum_file(&sha1_file, "app.iso", Some(ChecksumAlgorithm::Sha1)).unwrap().digest, SHA1);
+        assert_eq!(parse_checksum_file(&sha1_file, "app.iso", Some(ChecksumAlgorithm::Sha256)), None);
+    }
+
+    #[test]
+    fn rejects_malformed_listings() {
+        assert_eq!(parse_checksum_file("", "app.iso", None), None);
+        assert_eq!(parse_checksum_file("not a checksum  app.iso", "app.iso", None), None);
+        assert_eq!(parse_checksum_file(&format!("{}  a.iso\n{}  b.iso", SHA1, SHA1), "app.iso", None), None);
+    }
+
+    #[test]
+    fn hashes_with_every_algorithm() {
+        for (algorithm, digest) in [(ChecksumAlgorithm::Sha256, SHA256), (ChecksumAlgorithm::Sha1, SHA1), (ChecksumAlgorithm::Md5, MD5)] {
+            let mut hasher = Hasher::new(algorithm);
+            hasher.update(b"a");
+            hasher.update(b"bc");
+            assert_eq!(hasher.finalize_hex(), digest);
+        }
+    }
+}
@@ -1,19 +1,34 @@
 use std::{
-    collections::HashMap,
-    path::PathBuf,
-    sync::{mpsc::Sender, Arc},
-    time::Instant,
+    collections::{HashMap, HashSet},
+    fmt,
+    path::{Path, PathBuf},
+    sync::{mpsc::Sender, Arc, Mutex as StdMutex},
+    time::{Duration, Instant},
 };
 use serde::{Deserialize, Serialize};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    sync::Mutex,
+    io::{AsyncSeekExt, AsyncWriteExt, SeekFrom},
+    sync::{watch, Mutex},
     task::JoinSet,
 };
 use dirs;
+use crate::throttle::{RateLimiter, Throttle};
+use crate::manifest::{DownloadManifest, SegmentRange};
+use crate::prealloc::{ProgressBitmap, SharedBitmap, BLOCK_SIZE};
+use crate::probe;
+use crate::filename;
+use crate::conflict::{self, ConflictPolicy, OutputExists};
+use crate::workspace;
+use crate::error::SpeedyError;
+use crate::checksum::{self, ChecksumMismatch, ChecksumSource, ExpectedChecksum, Hasher};
+use crate::retry::{self, HttpStatusError, RetryPolicy, SegmentFailed, SegmentInterrupted};
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum DownloadEvent {
+    /// The segment layout used for this run, to be persisted for resuming
+    ManifestPlanned {
+        manifest: DownloadManifest,
+    },
     /// Initial information about total file size and segments
     Initialize {
         file_size: u64,
@@ -25,19 +40,219 @@ pub enum DownloadEvent {
         bytes: u64,
         speed: f64,
     },
+    /// Part of a segment's range was handed to a new segment on a free connection
+    SegmentSplit {
+        segment_id: u64,
+        segment_size: u64,     // new size of the segment that was split
+        new_segment_id: u64,
+        new_segment_size: u64,
+    },
+    /// Part files are being merged into the output file after downloading finished
+    Merging {
+        merged_bytes: u64,
+        total_bytes: u64,
+    },
+    /// A preallocated output file is being hashed to verify its checksum
+    Verifying {
+        hashed_bytes: u64,
+        total_bytes: u64,
+    },
+    /// The checksum of the finished file, as "algorithm:hex"
+    ChecksumComputed {
+        checksum: String,
+    },
     /// An error occurred
     Error {
         segment_id: u64,
         message: String,
     },
-    /// Download completed
-    Complete,
+    /// The output file is saved under another name because one with its name exists
+    Renamed {
+        file_name: String,
+    },
+    /// Download completed and the file was saved to `output_path`
+    Complete {
+        output_path: PathBuf,
+    },
+    /// Download stopped before completion because it was paused or cancelled
+    Stopped {
+        reason: StopReason,
+    },
+}
+
+/// Why a running download was asked to stop
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StopReason {
+    /// Keep the part files so the download can be resumed later
+    Paused,
+    /// Abort the download and remove its part files
+    Cancelled,
+}
+
+/// How many times a download starts over because the file on the server changed
+const MAX_RESOURCE_RESTARTS: u32 = 1;
+
+/// Smallest range a free connection takes over from another segment
+const MIN_SPLIT_SIZE: u64 = 1024 * 1024;
+
+/// Upper bound on how many segments splitting can grow a download to
+const MAX_SEGMENTS: u64 = 64;
+
+/// Bytes copied per merge step, progress is reported after each one
+const MERGE_CHUNK_SIZE: u64 = 8 * 1024 * 1024;
+
+/// How long a segment waits for more data before treating the connection as dead
+const STALL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The file on the server changed since the segment layout was planned,
+/// detected by the server ignoring our `If-Range` and sending the whole file
+#[derive(Debug)]
+pub struct ResourceChanged {
+    pub segment_id: u64,
+}
+
+impl fmt::Display for ResourceChanged {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "The file on the server changed while downloading (segment {} received the whole file instead of its range)", self.segment_id)
+    }
+}
+
+impl std::error::Error for ResourceChanged {}
+
+/// The server answered a range request with the whole file, so it can only
+/// be downloaded over a single connection
+#[derive(Debug)]
+pub struct RangeIgnored {
+    pub segment_id: u64,
+}
+
+impl fmt::Display for RangeIgnored {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "The server ignored the range request of segment {} and sent the whole file", self.segment_id)
+    }
+}
+
+impl std::error::Error for RangeIgnored {}
+
+/// The finished segments don't add up to the whole file, e.g. because a part file
+/// went missing or was cut short. Part files are kept so resuming only has to
+/// download what's missing.
+#[derive(Debug)]
+pub struct IncompleteDownload {
+    pub expected_bytes: u64,
+    pub missing_bytes: u64,
+    pub details: Vec<String>,
+}
+
+impl fmt::Display for IncompleteDownload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Download is incomplete: {} of {} bytes are missing", self.missing_bytes, self.expected_bytes)?;
+        if !self.details.is_empty() {
+            write!(f, " ({})", self.details.join("; "))?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for IncompleteDownload {}
+
+/// Live view of a segment's range shared between its task and the client.
+/// The client may move `end` down to hand the tail to another connection, so the
+/// task claims bytes through the cursor before writing them.
+#[derive(Clone, Debug)]
+struct SegmentCursor {
+    start: u64,    // First byte of the range
+    end: u64,      // Last byte of the range (inclusive)
+    written: u64,  // Bytes of this range claimed by the segment task
+    reported: u64, // Bytes of this range reported through BytesReceived
+}
+
+type SharedCursor = Arc<StdMutex<SegmentCursor>>;
+
+type SegmentResult = Result<(), Box<dyn std::error::Error + Send + Sync>>;
+
+/// Running segment tasks, each yielding its index and how it ended
+type SegmentTasks = JoinSet<(u64, SegmentResult)>;
+
+/// Where a segment task writes the bytes of its range
+#[derive(Clone)]
+enum SegmentOutput {
+    /// A part file of its own, merged into the output file at the end
+    PartFile(PathBuf),
+    /// The preallocated output file at the segment's offset, tracked by the bitmap
+    Preallocated { path: PathBuf, bitmap: SharedBitmap },
+}
+
+impl SegmentOutput {
+    fn path(&self) -> &PathBuf {
+        match self {
+            SegmentOutput::PartFile(path) => path,
+            SegmentOutput::Preallocated { path, .. } => path,
+        }
+    }
+}
+
+impl SegmentCursor {
+    /// Offset of the next byte to download
+    fn position(&self) -> u64 {
+        self.start + self.written
+    }
+
+    /// Bytes left to download in this range
+    fn remaining(&self) -> u64 {
+        (self.end + 1).saturating_sub(self.position())
+    }
+
+    /// Number of bytes covered by this range
+    fn size(&self) -> u64 {
+        self.end - self.start + 1
+    }
+}
+
+/// Handle used to stop a running `Client` from outside of its task
+#[derive(Clone)]
+pub struct ControlHandle {
+    sender: Arc<watch::Sender<Option<StopReason>>>,
+    limiter: Arc<RateLimiter>,
+}
+
+impl ControlHandle {
+    /// Ask every segment task to stop after flushing what it has written
+    pub fn stop(&self, reason: StopReason) {
+        self.sender.send_replace(Some(reason));
+    }
+
+    pub fn pause(&self) {
+        self.stop(StopReason::Paused);
+    }
+
+    pub fn cancel(&self) {
+        self.stop(StopReason::Cancelled);
+    }
+
+    /// Change the speed limit in bytes per second, `None` for unlimited.
+    /// Takes effect immediately for every segment of the download.
+    pub fn set_speed_limit(&self, limit: Option<u64>) {
+        self.limiter.set_limit(limit);
+    }
 }
 
 pub struct Client {
     url: String,
     parts: u64,
     progress: Arc<Mutex<ClientProgress>>,
+    control: ControlHandle,
+    stop_rx: watch::Receiver<Option<StopReason>>,
+    throttle: Throttle,
+    manifest: Option<DownloadManifest>,
+    single_stream: bool,
+    retry_policy: RetryPolicy,
+    preallocate: bool,
+    checksum: Option<ChecksumSource>,
+    output_dir: PathBuf,
+    file_name: String,
+    workspace: Option<PathBuf>,
+    conflict_policy: ConflictPolicy,
 }
 
 #[derive(Clone)]
@@ -109,10 +324,10 @@ impl Client {
     pub fn new(url: String, parts: u64) -> Self {
         // Validate parts (at least 1, at most 32)
         let parts = if parts < 1 {
-            println!("Warning: parts must be at least 1, using 1 instead of {}", parts);
+            eprintln!("Warning: parts must be at least 1, using 1 instead of {}", parts);
             1
         } else if parts > 32 {
-            println!("Warning: too many parts requested ({}), limiting to 32", parts);
+            eprintln!("Warning: too many parts requested ({}), limiting to 32", parts);
             32
         } else {
             parts
@@ -134,417 +349,1350 @@ impl Client {
             progress.segment_ids.insert(i, i + 1); // Assign 1-based segment IDs
         }
         
+        let (stop_tx, stop_rx) = watch::channel(None);
+        let throttle = Throttle::new();
+        let file_name = Self::get_file_name(&url);
+        
         Self { 
             url, 
             parts, 
             progress: Arc::new(Mutex::new(progress)),
+            control: ControlHandle {
+                sender: Arc::new(stop_tx),
+                limiter: throttle.download_limiter(),
+            },
+            stop_rx,
+            throttle,
+            manifest: None,
+            single_stream: false,
+            retry_policy: RetryPolicy::default(),
+            preallocate: false,
+            checksum: None,
+            output_dir: Self::default_output_dir(),
+            file_name,
+            workspace: None,
+            conflict_policy: conflict::DEFAULT_CONFLICT_POLICY,
         }
     }
 
+    /// Resume using the segment layout persisted by a previous run
+    pub fn set_manifest(&mut self, manifest: DownloadManifest) {
+        self.manifest = Some(manifest);
+    }
+
+    /// Set how failed segments are retried
+    pub fn set_retry_policy(&mut self, retry_policy: RetryPolicy) {
+        self.retry_policy = retry_policy;
+    }
+
+    /// Write new downloads straight into a preallocated output file instead of part files.
+    /// A resumed download keeps the mode it was started with.
+    pub fn set_preallocate(&mut self, preallocate: bool) {
+        self.preallocate = preallocate;
+    }
+
+    /// Save the file into `output_dir` under `file_name` instead of the user's downloads
+    /// folder and the name taken from the URL
+    pub fn set_destination(&mut self, output_dir: Option<PathBuf>, file_name: Option<String>) {
+        if let Some(output_dir) = output_dir {
+            self.output_dir = output_dir;
+        }
+        if let Some(file_name) = file_name {
+            self.file_name = file_name;
+        }
+    }
+
+    /// Keep the part files in `workspace`, a directory no other download uses
+    pub fn set_workspace(&mut self, workspace: PathBuf) {
+        self.workspace = Some(workspace);
+    }
+
+    /// Set what happens when the output file already exists
+    pub fn set_conflict_policy(&mut self, conflict_policy: ConflictPolicy) {
+        self.conflict_policy = conflict_policy;
+    }
+
+    /// Verify the finished file against a digest, or one listed in a checksum file
+    pub fn set_checksum(&mut self, checksum: ChecksumSource) {
+        self.checksum = Some(checksum);
+    }
+
     pub fn get_progress(&self) -> Arc<Mutex<ClientProgress>> {
         self.progress.clone()
     }
 
-    /// Extract file name from URL, removing query parameters
-    pub fn get_file_name(url: &str) -> String {
-        let maybe_filename = url.split('/').last();
-        
-        // If we couldn't find a slash, just use the whole URL
-        let with_query = maybe_filename.unwrap_or(url);
-        
-        // Remove query parameters if any
-        let filename = with_query.split('?').next().unwrap_or(with_query);
-        
-        // If we got an empty string, provide a default filename
-        if filename.is_empty() {
-            return "download".to_string();
+    /// Get a handle that can pause or cancel this client while it is downloading
+    pub fn control_handle(&self) -> ControlHandle {
+        self.control.clone()
+    }
+
+    /// Returns the reason this client was asked to stop, if any
+    pub fn stop_reason(&self) -> Option<StopReason> {
+        *self.stop_rx.borrow()
+    }
+
+    /// Directory holding this download's part files, one named after the file
+    /// under the default workspace root when none was set
+    fn workspace(&self) -> PathBuf {
+        match &self.workspace {
+            Some(workspace) => workspace.clone(),
+            None => workspace::default_root().join(&self.file_name),
+        }
+    }
+
+    /// Path of the part file for the given segment index
+    pub fn part_path(workspace: &Path, index: u64) -> PathBuf {
+        workspace.join(format!("part.{}", index))
+    }
+
+    /// Directory finished downloads are saved to unless another one is chosen
+    pub fn default_output_dir() -> PathBuf {
+        // Try to use the Downloads directory, fall back to current directory if not available
+        dirs::download_dir().unwrap_or_else(|| std::env::current_dir().unwrap_or_else(|_| PathBuf::from(".")))
+    }
+
+    /// Path the finished download is saved to
+    pub fn output_path(&self) -> PathBuf {
+        self.output_dir.join(&self.file_name)
+    }
+
+    /// Remove an unfinished preallocated output file along with its progress bitmap.
+    /// The output file is left alone unless the bitmap marks it as ours.
+    pub async fn remove_preallocated(output_path: &Path) {
+        if tokio::fs::metadata(ProgressBitmap::sidecar_path(output_path)).await.is_err() {
+            return;
+        }
+        match tokio::fs::remove_file(output_path).await {
+            Ok(_) => eprintln!("Removed unfinished output file: {}", output_path.display()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {},
+            Err(e) => eprintln!("Error removing unfinished output file {}: {}", output_path.display(), e),
+        }
+        ProgressBitmap::remove(output_path).await;
+    }
+
+    /// Remove every part file belonging to a download
+    pub async fn remove_part_files(workspace: &Path, parts: u64) {
+        for i in 0..parts {
+            let part_path = Self::part_path(workspace, i);
+            match tokio::fs::remove_file(&part_path).await {
+                Ok(_) => eprintln!("Removed part file: {}", part_path.display()),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {},
+                Err(e) => eprintln!("Error removing part {}: {}", i, e),
+            }
         }
-        
-        filename.to_string()
+    }
+
+    /// Extract a safe file name from the URL path, without asking the server.
+    /// Use `filename::detect` on a probe result to honor Content-Disposition and redirects.
+    pub fn get_file_name(url: &str) -> String {
+        filename::from_url(url)
     }
     
-    /// Download the file with the specified number of parallel segments
+    /// Download the file with the specified number of parallel segments.
+    /// Starts over from scratch if the file on the server changes underneath us,
+    /// or over a single connection if the server turns out to ignore ranges.
     pub async fn download(&mut self, event_sender: Sender<DownloadEvent>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let mut restarts = 0;
+        loop {
+            match self.download_once(&event_sender).await {
+                Err(e) if e.is::<ResourceChanged>() && restarts < MAX_RESOURCE_RESTARTS => {
+                    restarts += 1;
+                    eprintln!("{}, restarting the download", e);
+                },
+                Err(e) if e.is::<RangeIgnored>() && !self.single_stream => {
+                    self.single_stream = true;
+                    eprintln!("{}, switching to a single connection", e);
+                },
+                result => return result,
+            }
+        }
+    }
+    
+    /// Single attempt at downloading the file
+    async fn download_once(&mut self, event_sender: &Sender<DownloadEvent>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         let url = self.url.clone();
-        let parts = self.parts;
 
         // Validate URL
         if !url.starts_with("http://") && !url.starts_with("https://") {
             return Err(format!("Invalid URL: {}. URL must start with http:// or https://", url).into());
         }
 
-        // Get file information
-        let response = match reqwest::get(url.clone()).await {
-            Ok(resp) => resp,
-            Err(e) => return Err(format!("Failed to connect to URL: {}. Error: {}", url, e).into())
+        // Get file information without downloading the body
+        let mut stop_rx = self.stop_rx.clone();
+        let http_client = reqwest::Client::new();
+        let probe = tokio::select! {
+            probe = probe::probe(&http_client, &url) => probe?,
+            reason = wait_for_stop(&mut stop_rx) => {
+                eprintln!("Download stopped before it started ({:?})", reason);
+                event_sender.send(DownloadEvent::Stopped { reason })?;
+                return Ok(());
+            }
         };
         
-        // Check if the response was successful
-        let status = response.status();
-        if !status.is_success() {
-            return Err(format!("Server returned error status {} for URL: {}", status, url).into());
-        }
+        eprintln!("Downloading to file: {}", self.output_path().display());
         
-        let headers = response.headers().clone();
-        println!("Server response headers: {:?}", headers);
+        // Fetch the expected digest before downloading, a missing one fails the download early
+        if let Some(ChecksumSource::Url(checksum_url)) = &self.checksum {
+            let expected = checksum::fetch_checksum(&http_client, checksum_url, &self.file_name).await?;
+            eprintln!("Expecting checksum {}", expected);
+            self.checksum = Some(ChecksumSource::Digest(expected));
+        }
+
+        // Create the workspace holding the part files
+        let workspace = self.workspace();
+        eprintln!("Using workspace: {}", workspace.display());
+        tokio::fs::create_dir_all(&workspace).await?;
         
-        let supports_range = headers.contains_key("accept-ranges");
-        println!("Server supports range requests: {}", supports_range);
-        if !supports_range {
-            // Some servers don't explicitly advertise range support but still honor range requests
-            println!("WARNING: Server doesn't explicitly support range requests, but we'll try anyway");
-            // return Err("Server doesn't support range requests".into());
+        // Don't download a file that isn't going to be saved
+        if let Some(conflict) = self.output_conflict().await {
+            if conflict.policy == ConflictPolicy::Skip {
+                workspace::remove(&workspace).await;
+            }
+            return Err(conflict.into());
+        }
+
+        eprintln!("Server supports range requests: {}", probe.supports_ranges);
+        if self.single_stream || !probe.supports_ranges || probe.total_size.is_none() {
+            // Segments need both range support and a known size to be planned
+            eprintln!("Downloading over a single connection (size: {:?})", probe.total_size);
+            return self.download_single_stream(&http_client, event_sender, &workspace, probe.total_size).await;
         }
 
-        let content_length = headers
-            .get("content-length")
-            .ok_or("Content-Length header not found")?
-            .to_str()?
-            .parse::<u64>()?;
-        println!("Content-Length: {} bytes", content_length);
+        let content_length = probe.total_size.ok_or("Server didn't report the file size")?;
+        eprintln!("Content-Length: {} bytes", content_length);
         
         if content_length == 0 {
             return Err("Server returned zero content length, cannot download empty file".into());
         }
             
-        let file_name = Self::get_file_name(&self.url);
-        println!("Downloading to file: {}", file_name);
+        let etag = probe.etag.clone();
+        let last_modified = probe.last_modified.clone();
 
-        // Create temp directory
-        let temp_dir = std::env::temp_dir().join("speedy");
-        println!("Using temp directory: {}", temp_dir.display());
-        tokio::fs::create_dir_all(&temp_dir).await?;
+        // Reuse the persisted segment layout if it still matches, otherwise plan a new one
+        let (manifest, bitmap) = self.prepare_manifest(event_sender, &workspace, content_length, etag, last_modified).await?;
+        self.parts = manifest.parts();
+        let parts = self.parts;
+        event_sender.send(DownloadEvent::ManifestPlanned { manifest: manifest.clone() })?;
 
         // Initialize progress tracking
         self.progress.lock().await.set_file_size(content_length);
         
-        // Calculate segment sizes
-        let chunk_size = content_length / parts;
         let mut segment_sizes = HashMap::new();
         
-        println!("Splitting download into {} parts of approximately {} bytes each", parts, chunk_size);
+        eprintln!("Downloading in {} parts", parts);
         
-        for i in 0..parts {
-            let (start, end) = self.calculate_range(i, parts, chunk_size, content_length);
-            let segment_size = end - start + 1;
-            
-            println!("Segment {} range: {}-{} (size: {})", i+1, start, end, segment_size);
+        for segment in &manifest.segments {
+            eprintln!("Segment {} range: {}-{} (size: {}, on disk: {})",
+                     segment.segment_id(), segment.start, segment.end, segment.size(), segment.bytes_written);
             // Store segment size by segment ID (1-based)
-            segment_sizes.insert(i + 1, segment_size);
+            segment_sizes.insert(segment.segment_id(), segment.size());
             
             // Update local progress info
             let mut progress = self.progress.lock().await;
-            progress.set_total_bytes(segment_size, &i);
+            progress.set_segment_id(segment.segment_id(), &segment.index);
+            progress.set_total_bytes(segment.size(), &segment.index);
+            progress.set_chunks(segment.bytes_written, &segment.index);
         }
 
-        // Check for existing part files and update progress
-        self.resume_existing_parts(&temp_dir, &file_name, parts).await;
-
         // Send initialization event
         event_sender.send(DownloadEvent::Initialize { 
             file_size: content_length,
             segments: segment_sizes.clone(),
         })?;
 
-        println!("Starting download tasks for {} segments", parts);
+        eprintln!("Starting download tasks for {} segments", parts);
         // Start download tasks
-        let mut threads = JoinSet::new();
+        let mut threads: SegmentTasks = JoinSet::new();
         
-        for i in 0..parts {
-            let (start, end) = self.calculate_range(i, parts, chunk_size, content_length);
-            
-            let url = self.url.clone();
-            let file_name = file_name.clone();
-            let temp_dir = temp_dir.clone();
-            let progress_arc = self.progress.clone();
-            let event_tx = event_sender.clone();
-            let segment_id = i + 1; // 1-based segment ID
-            let segment_size = segment_sizes[&segment_id];
-
-            // Get existing downloaded bytes for this part
-            let part_path = temp_dir.join(format!("{}.{}", file_name, i));
-            let existing_bytes = if let Ok(metadata) = tokio::fs::metadata(&part_path).await {
-                metadata.len()
-            } else {
-                0
-            };
-
-            // Create a static version of the download_segment method that doesn't use &self
-            threads.spawn(async move {
-                // Only download if we haven't completed this segment
-                let range_start = start + existing_bytes;
-                
-                if range_start <= end {
-                    // Use a static helper function instead of a method that captures self
-                    download_segment_static(
-                        &url,
-                        range_start,
-                        end,
-                        &part_path,
-                        existing_bytes,
-                        segment_size,
-                        i,
-                        segment_id,
-                        progress_arc,
-                        event_tx,
-                    ).await?;
-                } else {
-                    // This segment is already fully downloaded
-                    event_tx.send(DownloadEvent::BytesReceived {
-                        segment_id,
-                        bytes: 0, // No new bytes
-                        speed: 0.0,
-                    })?;
-                }
-                
-                Ok::<(), Box<dyn std::error::Error + Send + Sync>>(())
-            });
+        // Only accept ranges of the version the layout was planned for
+        let if_range = if_range_validator(&manifest);
+        
+        let mut cursors: Vec<SharedCursor> = manifest.segments
+            .iter()
+            .map(|segment| Arc::new(StdMutex::new(SegmentCursor {
+                start: segment.start,
+                end: segment.end,
+                written: segment.bytes_written,
+                reported: 0,
+            })))
+            .collect();
+        let mut running = HashSet::new();
+        
+        let output_path = self.output_path();
+        let segment_output = |index: u64| match &bitmap {
+            Some(bitmap) => SegmentOutput::Preallocated { path: output_path.clone(), bitmap: bitmap.clone() },
+            None => SegmentOutput::PartFile(Self::part_path(&workspace, index)),
+        };
+        
+        for (index, cursor) in cursors.iter().enumerate() {
+            let index = index as u64;
+            self.spawn_segment(&mut threads, event_sender.clone(), index, cursor.clone(), if_range.clone(), segment_output(index));
+            running.insert(index);
         }
 
-        // Wait for all download tasks to complete
+        // Wait for all download tasks to complete, putting connections that
+        // finish early to work on the largest range still left
+        let mut restart_error = None;
+        let mut fatal_error = None;
         while let Some(res) = threads.join_next().await {
             match res {
-                Ok(download_result) => {
+                Ok((index, download_result)) => {
+                    running.remove(&index);
                     if let Err(download_err) = download_result {
                         eprintln!("Download error: {}", download_err);
-                        // Optionally, you can propagate this error if needed
-                        // return Err(format!("Download error: {}", download_err).into());
+                        // A segment only gives up once retrying can't help, and the file
+                        // is useless without it, so don't keep the other connections busy
+                        threads.abort_all();
+                        if download_err.is::<ResourceChanged>() || download_err.is::<RangeIgnored>() {
+                            restart_error = Some(download_err);
+                        } else if fatal_error.is_none() {
+                            fatal_error = Some(download_err);
+                        }
+                        continue;
+                    }
+                    
+                    if restart_error.is_some() || fatal_error.is_some() || self.stop_reason().is_some() {
+                        continue;
+                    }
+                    
+                    if let Some((victim, cursor)) = split_largest(&cursors, &running, manifest.alignment()) {
+                        let new_index = cursors.len() as u64;
+                        let victim_size = cursors[victim as usize].lock().unwrap().size();
+                        eprintln!("Segment {} freed a connection, splitting segment {}: new segment {} takes {}-{}",
+                                 index + 1, victim + 1, new_index + 1, cursor.start, cursor.end);
+                        
+                        {
+                            let mut progress = self.progress.lock().await;
+                            progress.set_total_bytes(victim_size, &victim);
+                            progress.set_segment_id(new_index + 1, &new_index);
+                            progress.set_total_bytes(cursor.size(), &new_index);
+                            progress.set_chunks(0, &new_index);
+                        }
+                        
+                        let new_segment_size = cursor.size();
+                        cursors.push(Arc::new(StdMutex::new(cursor)));
+                        event_sender.send(DownloadEvent::SegmentSplit {
+                            segment_id: victim + 1,
+                            segment_size: victim_size,
+                            new_segment_id: new_index + 1,
+                            new_segment_size,
+                        })?;
+                        // Persist the new layout before the new segment writes anything
+                        event_sender.send(DownloadEvent::ManifestPlanned {
+                            manifest: current_layout(&manifest, &cursors),
+                        })?;
+                        
+                        self.spawn_segment(&mut threads, event_sender.clone(), new_index, cursors[new_index as usize].clone(), if_range.clone(), segment_output(new_index));
+                        running.insert(new_index);
                     }
                 },
+                Err(join_err) if join_err.is_cancelled() => {},
                 Err(join_err) => {
                     eprintln!("Thread join error: {}", join_err);
-                    // Optionally, you can propagate this error if needed
-                    // return Err(format!("Thread error: {}", join_err).into());
+                    threads.abort_all();
+                    if fatal_error.is_none() {
+                        fatal_error = Some(format!("Segment task failed: {}", join_err).into());
+                    }
+                }
+            }
+        }
+        self.parts = cursors.len() as u64;
+
+        // These parts can't be used by the next attempt, drop them so it plans afresh
+        if let Some(err) = restart_error {
+            Self::remove_part_files(&workspace, self.parts).await;
+            Self::remove_preallocated(&self.output_path()).await;
+            self.manifest = None;
+            return Err(err);
+        }
+
+        // Keep the part files and layout so the download can be resumed later
+        if let Some(err) = fatal_error {
+            if let Some(bitmap) = &bitmap {
+                if let Err(e) = bitmap.lock().await.save().await {
+                    eprintln!("Failed to save progress bitmap: {}", e);
                 }
             }
+            return Err(err);
         }
 
-        // Merge files and clean up
-        self.merge_part_files(&file_name, &temp_dir, parts).await?;
+        // Segments created by splitting are appended, so merge by byte offset
+        let merge_order = current_layout(&manifest, &cursors).merge_order();
+        self.finish(event_sender, &workspace, &merge_order, bitmap.as_ref()).await
+    }
+    
+    /// Start the task downloading the range tracked by `cursor` into `output`
+    fn spawn_segment(
+        &self,
+        threads: &mut SegmentTasks,
+        event_tx: Sender<DownloadEvent>,
+        index: u64,
+        cursor: SharedCursor,
+        if_range: Option<String>,
+        output: SegmentOutput,
+    ) {
+        let url = self.url.clone();
+        let progress_arc = self.progress.clone();
+        let mut stop_rx = self.stop_rx.clone();
+        let throttle = self.throttle.clone();
+        let retry_policy = self.retry_policy;
+        let segment_id = index + 1;
+        
+        // The task reports its index so the client knows which connection freed up
+        threads.spawn(async move {
+            let mut retries = 0;
+            loop {
+                // Every attempt picks up from the bytes already written
+                let result = download_segment_static(
+                    &url,
+                    if_range.as_deref(),
+                    cursor.clone(),
+                    &output,
+                    index,
+                    segment_id,
+                    progress_arc.clone(),
+                    event_tx.clone(),
+                    stop_rx.clone(),
+                    throttle.clone(),
+                ).await;
+                
+                let err = match result {
+                    Ok(()) => return (index, Ok(())),
+                    Err(err) if err.is::<ResourceChanged>() || err.is::<RangeIgnored>() => return (index, Err(err)),
+                    Err(err) => err,
+                };
+                
+                let class = retry::classify(err.as_ref());
+                if !class.is_retryable() || retries >= retry_policy.max_retries {
+                    return (index, Err(SegmentFailed { error: err, class, retries }.into()));
+                }
+                
+                let delay = retry_policy.backoff(retries);
+                retries += 1;
+                eprintln!("Segment {} failed ({}: {}), retry {}/{} in {:?}",
+                         segment_id, class, err, retries, retry_policy.max_retries, delay);
+                
+                tokio::select! {
+                    _ = tokio::time::sleep(delay) => {},
+                    reason = wait_for_stop(&mut stop_rx) => {
+                        eprintln!("Segment {} stopped while waiting to retry ({:?})", segment_id, reason);
+                        return (index, Ok(()));
+                    }
+                }
+            }
+        });
+    }
+    
+    /// Merge the part files and report completion, unless the download was stopped.
+    /// `parts` lists the part indices in the order their ranges appear in the file,
+    /// with the size each part must have when it is known.
+    /// Preallocated downloads pass their bitmap and have nothing to merge.
+    async fn finish(
+        &mut self,
+        event_sender: &Sender<DownloadEvent>,
+        workspace: &Path,
+        parts: &[(u64, Option<u64>)],
+        bitmap: Option<&SharedBitmap>,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        // Segments return early when asked to stop, so don't merge an incomplete file
+        if let Some(reason) = self.stop_reason() {
+            match (reason, bitmap) {
+                (StopReason::Cancelled, _) => {
+                    workspace::remove(workspace).await;
+                    Self::remove_preallocated(&self.output_path()).await;
+                },
+                (StopReason::Paused, Some(bitmap)) => {
+                    if let Err(e) = bitmap.lock().await.save().await {
+                        eprintln!("Failed to save progress bitmap: {}", e);
+                    }
+                },
+                (StopReason::Paused, None) => {},
+            }
+            eprintln!("Download stopped ({:?})", reason);
+            event_sender.send(DownloadEvent::Stopped { reason })?;
+            return Ok(());
+        }
+
+        let mut actual_checksum = None;
+        match bitmap {
+            Some(bitmap) => {
+                // Every byte is already in place, the bitmap and the file just have to agree
+                let mut bitmap = bitmap.lock().await;
+                let file_size = self.progress.lock().await.get_file_size();
+                let on_disk = tokio::fs::metadata(self.output_path()).await.map(|m| m.len()).unwrap_or(0);
+                if !bitmap.is_complete() || on_disk != file_size {
+                    bitmap.save().await?;
+                    let mut details = Vec::new();
+                    if on_disk != file_size {
+                        details.push(format!("output file has {} bytes instead of {}", on_disk, file_size));
+                    }
+                    return Err(IncompleteDownload {
+                        expected_bytes: file_size,
+                        missing_bytes: bitmap.missing_bytes(),
+                        details,
+                    }.into());
+                }
+                ProgressBitmap::remove(&self.output_path()).await;
+                eprintln!("Download complete! File saved to: {}", self.output_path().display());
+            },
+            // Merge files and clean up, hashing them on the way
+            None => match self.merge_part_files(event_sender, workspace, parts).await {
+                Ok(digest) => actual_checksum = digest,
+                Err(e) => {
+                    let skipped = e.downcast_ref::<OutputExists>()
+                        .map(|conflict| conflict.policy == ConflictPolicy::Skip)
+                        .unwrap_or(false);
+                    if skipped {
+                        workspace::remove(workspace).await;
+                    }
+                    return Err(e);
+                }
+            },
+        }
+        workspace::remove(workspace).await;
+        
+        if let Some(expected) = self.expected_checksum() {
+            let actual = match actual_checksum {
+                Some(actual) => actual,
+                None => Self::hash_output_file(event_sender, self.output_path(), expected.algorithm).await?,
+            };
+            event_sender.send(DownloadEvent::ChecksumComputed {
+                checksum: format!("{}:{}", expected.algorithm, actual),
+            })?;
+            if actual != expected.digest {
+                return Err(ChecksumMismatch { expected, actual }.into());
+            }
+            eprintln!("Checksum verified: {}", expected);
+        }
         
         // Send complete event
-        event_sender.send(DownloadEvent::Complete)?;
+        event_sender.send(DownloadEvent::Complete { output_path: self.output_path() })?;
         
         Ok(())
     }
     
-    /// Calculate start and end byte range for a segment
-    fn calculate_range(&self, index: u64, parts: u64, chunk_size: u64, content_length: u64) -> (u64, u64) {
-        let start = index * chunk_size;
-        let end = if index == parts - 1 {
-            content_length - 1
-        } else {
-            start + chunk_size - 1
-        };
-        (start, end)
+    /// The existing file in the way of the output when the policy is to skip or ask.
+    /// A preallocated output file with a progress bitmap is our own unfinished download.
+    async fn output_conflict(&self) -> Option<OutputExists> {
+        if !matches!(self.conflict_policy, ConflictPolicy::Skip | ConflictPolicy::Ask) {
+            return None;
+        }
+        let output_path = self.output_path();
+        if tokio::fs::metadata(&output_path).await.is_err()
+            || tokio::fs::metadata(ProgressBitmap::sidecar_path(&output_path)).await.is_ok() {
+            return None;
+        }
+        Some(OutputExists { path: output_path, policy: self.conflict_policy })
     }
     
-    /// Check for existing part files and update progress
-    async fn resume_existing_parts(&self, temp_dir: &PathBuf, file_name: &str, parts: u64) {
-        for i in 0..parts {
-            let part_path = temp_dir.join(format!("{}.{}", file_name, i));
-            if let Ok(metadata) = tokio::fs::metadata(&part_path).await {
-                let file_size = metadata.len();
-                if file_size > 0 {
-                    // Update progress with existing file size
-                    let mut progress = self.progress.lock().await;
-                    progress.set_chunks(file_size, &i);
+    /// Claim the output path according to the conflict policy, switching to
+    /// another name and reporting it when the file exists and may be renamed
+    async fn claim_output(&mut self, event_sender: &Sender<DownloadEvent>) -> Result<PathBuf, Box<dyn std::error::Error + Send + Sync>> {
+        tokio::fs::create_dir_all(&self.output_dir).await?;
+        let output_path = self.output_path();
+        let policy = self.conflict_policy;
+        let claimed = tokio::task::spawn_blocking(move || conflict::claim(&output_path, policy)).await??;
+        
+        if claimed != self.output_path() {
+            if let Some(file_name) = claimed.file_name().and_then(|name| name.to_str()) {
+                eprintln!("{} already exists, saving as {}", self.output_path().display(), file_name);
+                self.file_name = file_name.to_string();
+                event_sender.send(DownloadEvent::Renamed { file_name: self.file_name.clone() })?;
+            }
+        }
+        Ok(claimed)
+    }
+    
+    /// Remove an output file this run claimed but couldn't finish writing, so the
+    /// next attempt doesn't take its own leftover for a file that's in the way
+    async fn release_output(output_path: &Path) {
+        match tokio::fs::remove_file(output_path).await {
+            Ok(_) => eprintln!("Removed unfinished output file: {}", output_path.display()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {},
+            Err(e) => eprintln!("Error removing unfinished output file {}: {}", output_path.display(), e),
+        }
+        ProgressBitmap::remove(output_path).await;
+    }
+    
+    /// The digest to verify against, once any checksum file has been fetched
+    fn expected_checksum(&self) -> Option<ExpectedChecksum> {
+        match &self.checksum {
+            Some(ChecksumSource::Digest(expected)) => Some(expected.clone()),
+            _ => None,
+        }
+    }
+    
+    /// Hash the output file on a blocking thread, for downloads that weren't merged
+    async fn hash_output_file(
+        event_sender: &Sender<DownloadEvent>,
+        output_path: PathBuf,
+        algorithm: checksum::ChecksumAlgorithm,
+    ) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+        let total_bytes = tokio::fs::metadata(&output_path).await?.len();
+        let event_tx = event_sender.clone();
+        eprintln!("Hashing {} to verify its checksum", output_path.display());
+        
+        let digest = tokio::task::spawn_blocking(move || {
+            let _ = event_tx.send(DownloadEvent::Verifying { hashed_bytes: 0, total_bytes });
+            let mut last_reported = 0;
+            checksum::hash_file(&output_path, algorithm, |hashed_bytes| {
+                // Report about as often as merging does
+                if hashed_bytes - last_reported >= MERGE_CHUNK_SIZE || hashed_bytes == total_bytes {
+                    last_reported = hashed_bytes;
+                    let _ = event_tx.send(DownloadEvent::Verifying { hashed_bytes, total_bytes });
+                }
+            })
+        }).await??;
+        
+        Ok(digest)
+    }
+    
+    /// Download the whole file over one connection, for servers without range
+    /// support or that don't tell us the size. This can't be resumed.
+    async fn download_single_stream(
+        &mut self,
+        http_client: &reqwest::Client,
+        event_sender: &Sender<DownloadEvent>,
+        workspace: &Path,
+        total_size: Option<u64>,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        // Parts left by earlier attempts can't be continued without ranges
+        let stale_parts = self.manifest.take().map(|manifest| manifest.parts()).unwrap_or(0).max(self.parts);
+        Self::remove_part_files(workspace, stale_parts).await;
+        Self::remove_preallocated(&self.output_path()).await;
+        self.parts = 1;
+        
+        // A size of 0 tells the frontend that progress is indeterminate
+        let file_size = total_size.unwrap_or(0);
+        let part_path = Self::part_path(workspace, 0);
+        let mut stop_rx = self.stop_rx.clone();
+        let mut retries = 0;
+        loop {
+            // Every attempt starts over, so progress does too
+            {
+                let mut progress = self.progress.lock().await;
+                progress.set_file_size(file_size);
+                progress.set_total_bytes(file_size, &0);
+            }
+            event_sender.send(DownloadEvent::Initialize {
+                file_size,
+                segments: HashMap::from([(1, file_size)]),
+            })?;
+            
+            let err = match self.stream_whole_file(http_client, event_sender, &part_path, total_size).await {
+                Ok(()) => break,
+                Err(err) => err,
+            };
+            
+            let class = retry::classify(err.as_ref());
+            if !class.is_retryable() || retries >= self.retry_policy.max_retries {
+                return Err(SegmentFailed { error: err, class, retries }.into());
+            }
+            
+            let delay = self.retry_policy.backoff(retries);
+            retries += 1;
+            eprintln!("Single stream download failed ({}: {}), retry {}/{} in {:?}",
+                     class, err, retries, self.retry_policy.max_retries, delay);
+            
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {},
+                reason = wait_for_stop(&mut stop_rx) => {
+                    eprintln!("Download stopped while waiting to retry ({:?})", reason);
+                    break;
                 }
             }
         }
+        
+        self.finish(event_sender, workspace, &[(0, total_size)], None).await
     }
     
-    /// Merge all part files into the final output file
-    async fn merge_part_files(&self, file_name: &str, temp_dir: &PathBuf, parts: u64) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        // Try to use the Downloads directory, fall back to current directory if not available
-        let output_dir = dirs::download_dir().unwrap_or_else(|| std::env::current_dir().unwrap_or_else(|_| PathBuf::from(".")));
-        let output_path = output_dir.join(file_name);
+    /// Stream the whole response body into a single part file
+    async fn stream_whole_file(
+        &self,
+        http_client: &reqwest::Client,
+        event_tx: &Sender<DownloadEvent>,
+        part_path: &Path,
+        total_size: Option<u64>,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let url = &self.url;
+        let mut stop_rx = self.stop_rx.clone();
+        let start_time = Instant::now();
+        let segment_id = 1;
         
-        println!("Merging {} part files into: {}", parts, output_path.display());
-        let mut total_bytes_merged = 0u64;
-        let mut successful_parts = 0u64;
-
-        // Create the output file
-        let mut output_file = match tokio::fs::File::create(&output_path).await {
-            Ok(file) => file,
-            Err(e) => return Err(format!("Failed to create output file '{}': {}", 
-                                        output_path.display(), e).into())
+        let response = tokio::select! {
+            response = http_client.get(url).send() => response?,
+            reason = wait_for_stop(&mut stop_rx) => {
+                eprintln!("Download stopped before receiving data ({:?})", reason);
+                return Ok(());
+            }
         };
         
-        for i in 0..parts {
-            let part_path = temp_dir.join(format!("{}.{}", file_name, i));
+        let status = response.status();
+        if !status.is_success() {
+            return Err(SpeedyError::HttpStatus {
+                status: status.as_u16(),
+                message: format!("Server returned error status {} for URL: {}", status, url),
+            }.into());
+        }
+        
+        let mut file = tokio::fs::File::create(part_path).await?;
+        let mut bytes_downloaded = 0u64;
+        let mut last_reported_bytes = 0u64;
+        
+        let mut response = response;
+        loop {
+            let chunk = tokio::select! {
+                chunk = tokio::time::timeout(STALL_TIMEOUT, response.chunk()) => chunk,
+                reason = wait_for_stop(&mut stop_rx) => {
+                    file.flush().await?;
+                    eprintln!("Download stopped at {} bytes ({:?})", bytes_downloaded, reason);
+                    return Ok(());
+                }
+            };
+            let chunk = match chunk {
+                Ok(Ok(Some(chunk))) => chunk,
+                Ok(Ok(None)) => break,
+                Ok(Err(e)) => return Err(Box::new(e)),
+                Err(_) => {
+                    let missing_bytes = total_size.map(|size| size.saturating_sub(bytes_downloaded)).unwrap_or(0);
+                    return Err(Box::new(SegmentInterrupted { segment_id, missing_bytes, stalled: true }));
+                }
+            };
+            let chunk_size = chunk.len() as u64;
+            bytes_downloaded += chunk_size;
+            
+            file.write_all(&chunk).await?;
             
-            // Check if the part file exists and log its size
-            if let Ok(metadata) = tokio::fs::metadata(&part_path).await {
-                println!("Part {} exists, size: {} bytes", i, metadata.len());
-            } else {
-                println!("Warning: Part {} does not exist", i);
-                continue;
+            let bytes_change = bytes_downloaded - last_reported_bytes;
+            if bytes_change >= 16 * 1024 {
+                let bytes_per_second = bytes_downloaded as f64 / start_time.elapsed().as_secs_f64();
+                {
+                    let mut progress = self.progress.lock().await;
+                    progress.set_chunks(bytes_downloaded, &0);
+                    progress.set_bytes_per_second(bytes_per_second, &0);
+                }
+                
+                event_tx.send(DownloadEvent::BytesReceived {
+                    segment_id,
+                    bytes: bytes_change,
+                    speed: bytes_per_second,
+                })?;
+                last_reported_bytes = bytes_downloaded;
             }
-
-            // Open and read part file
-            match tokio::fs::File::open(&part_path).await {
-                Ok(mut part_file) => {
-                    let mut buffer = Vec::new();
-                    if let Err(e) = part_file.read_to_end(&mut buffer).await {
-                        eprintln!("Error reading part file {}: {}", i, e);
-                        continue; // Skip this part but try to continue with others
+            
+            // Respect the download and global speed limits before reading more
+            tokio::select! {
+                _ = self.throttle.consume(chunk_size) => {},
+                reason = wait_for_stop(&mut stop_rx) => {
+                    file.flush().await?;
+                    eprintln!("Download stopped at {} bytes ({:?})", bytes_downloaded, reason);
+                    return Ok(());
+                }
+            }
+        }
+        
+        file.flush().await?;
+        
+        // Report whatever arrived since the last update
+        if bytes_downloaded > last_reported_bytes {
+            let bytes_per_second = bytes_downloaded as f64 / start_time.elapsed().as_secs_f64();
+            self.progress.lock().await.set_chunks(bytes_downloaded, &0);
+            event_tx.send(DownloadEvent::BytesReceived {
+                segment_id,
+                bytes: bytes_downloaded - last_reported_bytes,
+                speed: bytes_per_second,
+            })?;
+        }
+        
+        // Without ranges we can't pick up where a dropped connection left off
+        if let Some(expected) = total_size {
+            if bytes_downloaded != expected {
+                return Err(Box::new(SegmentInterrupted { segment_id, missing_bytes: expected.saturating_sub(bytes_downloaded), stalled: false }));
+            }
+        }
+        
+        eprintln!("Single stream download finished: {} bytes in {:.1}s",
+                 bytes_downloaded, start_time.elapsed().as_secs_f64());
+        Ok(())
+    }
+    
+    /// Validate the persisted manifest against the server's resource and the files
+    /// on disk, falling back to a fresh layout when it can't be trusted.
+    /// Returns the progress bitmap too when segments write into a preallocated file.
+    async fn prepare_manifest(
+        &mut self,
+        event_sender: &Sender<DownloadEvent>,
+        workspace: &Path,
+        file_size: u64,
+        etag: Option<String>,
+        last_modified: Option<String>,
+    ) -> Result<(DownloadManifest, Option<SharedBitmap>), Box<dyn std::error::Error + Send + Sync>> {
+        match self.manifest.take() {
+            Some(mut manifest) if manifest.preallocated && manifest.is_consistent()
+                && manifest.matches_resource(&self.url, file_size, etag.as_deref(), last_modified.as_deref()) => {
+                // The bitmap is the source of truth, the saved progress may lag behind it
+                let output_path = self.output_path();
+                let bitmap = match tokio::fs::metadata(&output_path).await {
+                    Ok(metadata) if metadata.len() == file_size => ProgressBitmap::load(&output_path, file_size).await,
+                    _ => None,
+                };
+                let bitmap = match bitmap {
+                    Some(bitmap) => bitmap,
+                    None => {
+                        eprintln!("Preallocated output file or its progress bitmap is missing, starting over");
+                        Self::remove_preallocated(&self.output_path()).await;
+                        return self.plan_fresh(event_sender, file_size, etag, last_modified).await;
                     }
+                };
+                for segment in manifest.segments.iter_mut() {
+                    segment.bytes_written = bitmap.contiguous_from(segment.start, segment.end);
+                }
+                
+                manifest.etag = manifest.etag.or(etag);
+                manifest.last_modified = manifest.last_modified.or(last_modified);
+                
+                eprintln!("Resuming preallocated download ({} of {} bytes written)",
+                         manifest.bytes_written(), file_size);
+                Ok((manifest, Some(Arc::new(Mutex::new(bitmap)))))
+            },
+            Some(mut manifest) if !manifest.preallocated && manifest.is_consistent()
+                && manifest.matches_resource(&self.url, file_size, etag.as_deref(), last_modified.as_deref()) => {
+                // Part files are the source of truth, the saved progress may lag behind them
+                for segment in manifest.segments.iter_mut() {
+                    let part_path = Self::part_path(workspace, segment.index);
+                    let on_disk = match tokio::fs::metadata(&part_path).await {
+                        Ok(metadata) => metadata.len(),
+                        Err(_) => 0,
+                    };
                     
-                    let buffer_size = buffer.len();
-                    if buffer_size == 0 {
-                        println!("Warning: Part {} has zero bytes", i);
-                        continue;
+                    if on_disk > segment.size() {
+                        eprintln!("Part {} is larger than its range ({} > {}), discarding it",
+                                 segment.index, on_disk, segment.size());
+                        if let Err(e) = tokio::fs::remove_file(&part_path).await {
+                            eprintln!("Error removing part {}: {}", segment.index, e);
+                        }
+                        segment.bytes_written = 0;
+                    } else {
+                        segment.bytes_written = on_disk;
                     }
-
-                    // Write to output file
-                    if let Err(e) = output_file.write_all(&buffer).await {
-                        return Err(format!("Failed to write part {} to output file: {}", i, e).into());
-                    }
-                    
-                    total_bytes_merged += buffer_size as u64;
-                    successful_parts += 1;
-                    println!("Merged part {} ({} bytes) successfully", i, buffer_size);
-                },
-                Err(e) => {
-                    eprintln!("Failed to open part file {}: {}", i, e);
-                    // Consider whether to fail here or continue with other parts
-                    continue;
                 }
+                
+                // Keep validators the server has started sending since the manifest was saved
+                manifest.etag = manifest.etag.or(etag);
+                manifest.last_modified = manifest.last_modified.or(last_modified);
+                
+                eprintln!("Resuming with saved segment layout ({} of {} bytes on disk)",
+                         manifest.bytes_written(), file_size);
+                Ok((manifest, None))
+            },
+            Some(manifest) => {
+                eprintln!("Saved segment layout doesn't match the file on the server anymore, starting over");
+                Self::remove_part_files(workspace, manifest.parts()).await;
+                Self::remove_preallocated(&self.output_path()).await;
+                self.plan_fresh(event_sender, file_size, etag, last_modified).await
+            },
+            None => {
+                // Without a manifest we can't tell which ranges leftover part files hold
+                Self::remove_part_files(workspace, self.parts).await;
+                Self::remove_preallocated(&self.output_path()).await;
+                self.plan_fresh(event_sender, file_size, etag, last_modified).await
             }
-            
-            // Delete part file after merging
-            if let Err(e) = tokio::fs::remove_file(&part_path).await {
-                // Just log error but continue with other parts
+        }
+    }
+    
+    /// Plan a new segment layout, claiming the output file and creating its bitmap
+    /// up front when downloading into a preallocated file
+    async fn plan_fresh(
+        &mut self,
+        event_sender: &Sender<DownloadEvent>,
+        file_size: u64,
+        etag: Option<String>,
+        last_modified: Option<String>,
+    ) -> Result<(DownloadManifest, Option<SharedBitmap>), Box<dyn std::error::Error + Send + Sync>> {
+        let manifest = DownloadManifest::plan(&self.url, file_size, self.parts, etag, last_modified, self.preallocate);
+        if !self.preallocate {
+            return Ok((manifest, None));
+        }
+        
+        let output_path = self.claim_output(event_sender).await?;
+        let bitmap = match Self::preallocate_output(&output_path, file_size).await {
+            Ok(bitmap) => bitmap,
+            Err(e) => {
+                Self::release_output(&output_path).await;
+                return Err(e);
+            }
+        };
+        eprintln!("Preallocated {} bytes for {}", file_size, output_path.display());
+        
+        Ok((manifest, Some(Arc::new(Mutex::new(bitmap)))))
+    }
+    
+    /// Size the claimed output file and create its progress bitmap
+    async fn preallocate_output(output_path: &Path, file_size: u64) -> Result<ProgressBitmap, Box<dyn std::error::Error + Send + Sync>> {
+        let file = match tokio::fs::File::create(output_path).await {
+            Ok(file) => file,
+            Err(e) => return Err(format!("Failed to create output file '{}': {}",
+                                        output_path.display(), e).into())
+        };
+        file.set_len(file_size).await?;
+        
+        let mut bitmap = ProgressBitmap::new(output_path, file_size);
+        bitmap.save().await?;
+        Ok(bitmap)
+    }
+    
+    /// Merge all part files into the final output file, in the given order.
+    /// Every part has to be complete before merging starts and the part files are
+    /// only removed once the output file has the expected size.
+    /// The copy runs on a blocking thread in bounded chunks and reports its progress.
+    /// Returns the digest of the merged file when a checksum is expected.
+    async fn merge_part_files(
+        &mut self,
+        event_sender: &Sender<DownloadEvent>,
+        workspace: &Path,
+        parts: &[(u64, Option<u64>)],
+    ) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync>> {
+        Self::verify_parts(workspace, parts).await?;
+        let output_path = self.claim_output(event_sender).await?;
+        
+        eprintln!("Merging {} part files into: {}", parts.len(), output_path.display());
+        let part_paths: Vec<(u64, PathBuf)> = parts
+            .iter()
+            .map(|&(i, _)| (i, Self::part_path(workspace, i)))
+            .collect();
+        
+        let (total_bytes_merged, digest) = match self.merge_into(event_sender, parts, &part_paths, &output_path).await {
+            Ok(merged) => merged,
+            Err(e) => {
+                Self::release_output(&output_path).await;
+                return Err(e);
+            }
+        };
+
+        eprintln!("Download complete! File saved to: {} (Total size: {} bytes from {} parts)", 
+                 output_path.display(), total_bytes_merged, parts.len());
+
+        // Only now are the part files no longer needed
+        for (i, part_path) in &part_paths {
+            if let Err(e) = tokio::fs::remove_file(part_path).await {
                 eprintln!("Error removing part {}: {}", i, e);
             }
         }
-        
-        if successful_parts == 0 {
-            return Err(format!("Failed to merge any part files - no valid parts found").into());
+
+        Ok(digest)
+    }
+    
+    /// Copy the part files into the claimed output file and check it got every byte.
+    /// Returns the bytes merged and the digest of the merged file.
+    async fn merge_into(
+        &self,
+        event_sender: &Sender<DownloadEvent>,
+        parts: &[(u64, Option<u64>)],
+        part_paths: &[(u64, PathBuf)],
+        output_path: &Path,
+    ) -> Result<(u64, Option<String>), Box<dyn std::error::Error + Send + Sync>> {
+        let event_tx = event_sender.clone();
+        let merge_output_path = output_path.to_path_buf();
+        let merge_part_paths = part_paths.to_vec();
+        let hasher = self.expected_checksum().map(|expected| Hasher::new(expected.algorithm));
+        let (total_bytes_merged, digest) = tokio::task::spawn_blocking(move || {
+            merge_parts_blocking(&merge_part_paths, &merge_output_path, &event_tx, hasher)
+        }).await??;
+
+        // The parts were complete, so anything else means writing the output went wrong
+        let expected_bytes: Option<u64> = parts.iter().map(|&(_, size)| size).sum();
+        let expected_bytes = expected_bytes.unwrap_or(total_bytes_merged);
+        let on_disk = match tokio::fs::metadata(output_path).await {
+            Ok(metadata) => metadata.len(),
+            Err(e) => return Err(format!("Unable to verify final file: {}", e).into()),
+        };
+        if on_disk == 0 {
+            return Err("Final file has 0 bytes after merging. This indicates a download failure.".into());
+        }
+        if on_disk != expected_bytes || total_bytes_merged != expected_bytes {
+            return Err(IncompleteDownload {
+                expected_bytes,
+                missing_bytes: expected_bytes.saturating_sub(on_disk.min(total_bytes_merged)),
+                details: vec![format!("output file has {} bytes after merging {} bytes", on_disk, total_bytes_merged)],
+            }.into());
         }
 
-        // Ensure all data is written to disk
-        if let Err(e) = output_file.flush().await {
-            eprintln!("Warning: Failed to flush output file: {}", e);
+        Ok((total_bytes_merged, digest))
+    }
+    
+    /// Check that every part file exists and holds exactly its range
+    async fn verify_parts(
+        workspace: &Path,
+        parts: &[(u64, Option<u64>)],
+    ) -> Result<(), IncompleteDownload> {
+        let mut expected_bytes = 0;
+        let mut missing_bytes = 0;
+        let mut details = Vec::new();
+        for &(i, size) in parts {
+            let on_disk = match tokio::fs::metadata(Self::part_path(workspace, i)).await {
+                Ok(metadata) => Some(metadata.len()),
+                Err(_) => None,
+            };
+            expected_bytes += size.or(on_disk).unwrap_or(0);
+            match (on_disk, size) {
+                (None, size) => {
+                    missing_bytes += size.unwrap_or(0);
+                    details.push(format!("part {} is missing", i));
+                },
+                (Some(on_disk), Some(size)) if on_disk < size => {
+                    missing_bytes += size - on_disk;
+                    details.push(format!("part {} has {} of {} bytes", i, on_disk, size));
+                },
+                (Some(on_disk), Some(size)) if on_disk > size => {
+                    // Resuming discards a part that outgrew its range, so all of it is missing
+                    missing_bytes += size;
+                    details.push(format!("part {} has {} bytes, more than its {} byte range", i, on_disk, size));
+                },
+                _ => {},
+            }
         }
         
-        println!("Download complete! File saved to: {} (Total size: {} bytes from {} parts)", 
-                 output_path.display(), total_bytes_merged, successful_parts);
+        if details.is_empty() {
+            Ok(())
+        } else {
+            Err(IncompleteDownload { expected_bytes, missing_bytes, details })
+        }
+    }
+}
 
-        // Verify the file was properly created with the expected size
-        match tokio::fs::metadata(&output_path).await {
-            Ok(metadata) => {
-                if metadata.len() == 0 {
-                    return Err("Final file has 0 bytes after merging. This indicates a download failure.".into());
-                } else if metadata.len() != total_bytes_merged {
-                    eprintln!("Warning: Final file size ({} bytes) doesn't match merged size ({} bytes)", 
-                              metadata.len(), total_bytes_merged);
-                }
-            },
-            Err(e) => {
-                return Err(format!("Unable to verify final file: {}", e).into());
+/// Take the second half of the largest range still being downloaded by a running
+/// segment, starting it on a multiple of `alignment`. Returns the index of the
+/// segment that was shrunk and the range split off, or None when the download
+/// already has `MAX_SEGMENTS` or no range is worth splitting.
+fn split_largest(cursors: &[SharedCursor], running: &HashSet<u64>, alignment: u64) -> Option<(u64, SegmentCursor)> {
+    if cursors.len() as u64 >= MAX_SEGMENTS {
+        return None;
+    }
+    let (victim, cursor) = cursors
+        .iter()
+        .enumerate()
+        .filter(|(index, _)| running.contains(&(*index as u64)))
+        .max_by_key(|(_, cursor)| cursor.lock().unwrap().remaining())?;
+    
+    let mut cursor = cursor.lock().unwrap();
+    let remaining = cursor.remaining();
+    if remaining < 2 * MIN_SPLIT_SIZE {
+        return None;
+    }
+    
+    // The victim can't write past its end once we've moved it, since it claims bytes under this lock
+    let split_at = cursor.position() + remaining / 2;
+    let split_at = (split_at + alignment - 1) / alignment * alignment;
+    if split_at > cursor.end {
+        return None;
+    }
+    let tail = SegmentCursor {
+        start: split_at,
+        end: cursor.end,
+        written: 0,
+        reported: 0,
+    };
+    cursor.end = split_at - 1;
+    Some((victim as u64, tail))
+}
+
+/// Build the manifest describing the ranges as they are right now
+fn current_layout(manifest: &DownloadManifest, cursors: &[SharedCursor]) -> DownloadManifest {
+    let segments = cursors
+        .iter()
+        .enumerate()
+        .map(|(index, cursor)| {
+            let cursor = cursor.lock().unwrap();
+            SegmentRange {
+                index: index as u64,
+                start: cursor.start,
+                end: cursor.end,
+                bytes_written: cursor.written,
+            }
+        })
+        .collect();
+    
+    DownloadManifest {
+        segments,
+        ..manifest.clone()
+    }
+}
+
+/// Append the part files to a new output file, returning the bytes merged and
+/// the digest when a hasher is given. Any part that can't be read fails the merge.
+/// Uses blocking I/O, so run it off the async runtime.
+fn merge_parts_blocking(
+    part_paths: &[(u64, PathBuf)],
+    output_path: &PathBuf,
+    event_tx: &Sender<DownloadEvent>,
+    mut hasher: Option<Hasher>,
+) -> Result<(u64, Option<String>), Box<dyn std::error::Error + Send + Sync>> {
+    use std::io::Write;
+    
+    let mut total_bytes_merged = 0u64;
+    
+    // Sizes are needed up front to report progress as a fraction
+    let total_bytes: u64 = part_paths
+        .iter()
+        .filter_map(|(_, part_path)| std::fs::metadata(part_path).ok())
+        .map(|metadata| metadata.len())
+        .sum();
+    
+    // Create the output file
+    let mut output_file = match std::fs::File::create(output_path) {
+        Ok(file) => file,
+        Err(e) => return Err(format!("Failed to create output file '{}': {}", 
+                                    output_path.display(), e).into())
+    };
+    event_tx.send(DownloadEvent::Merging { merged_bytes: 0, total_bytes })?;
+    
+    for (i, part_path) in part_paths {
+        let part_file = std::fs::File::open(part_path)
+            .map_err(|e| format!("Failed to open part file {}: {}", i, e))?;
+        
+        // Copy a bounded chunk at a time
+        let mut part_bytes = 0u64;
+        loop {
+            let copied = copy_chunk(&part_file, &mut output_file, hasher.as_mut())
+                .map_err(|e| format!("Failed to write part {} to output file: {}", i, e))?;
+            if copied == 0 {
+                break;
             }
+            part_bytes += copied;
+            total_bytes_merged += copied;
+            event_tx.send(DownloadEvent::Merging { merged_bytes: total_bytes_merged, total_bytes })?;
         }
+        eprintln!("Merged part {} ({} bytes) successfully", i, part_bytes);
+    }
+    
+    // Ensure all data is written to disk before the part files go away
+    output_file.flush()?;
+    output_file.sync_all()?;
+    
+    Ok((total_bytes_merged, hasher.map(Hasher::finalize_hex)))
+}
 
-        Ok(())
+/// Copy up to `MERGE_CHUNK_SIZE` bytes, returning how many were copied. Between two
+/// files, std::io::copy uses copy_file_range on Linux and a small stack buffer
+/// elsewhere, but hashing needs to see the bytes so it goes through a buffer of its own.
+fn copy_chunk(
+    mut part_file: &std::fs::File,
+    output_file: &mut std::fs::File,
+    hasher: Option<&mut Hasher>,
+) -> std::io::Result<u64> {
+    use std::io::{Read, Write};
+    
+    let hasher = match hasher {
+        Some(hasher) => hasher,
+        None => return std::io::copy(&mut part_file.take(MERGE_CHUNK_SIZE), output_file),
+    };
+    
+    let mut buffer = vec![0; 64 * 1024];
+    let mut copied = 0u64;
+    while copied < MERGE_CHUNK_SIZE {
+        let read = match part_file.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        hasher.update(&buffer[..read]);
+        output_file.write_all(&buffer[..read])?;
+        copied += read as u64;
+    }
+    Ok(copied)
+}
+
+/// Pick the validator to send as `If-Range`. Weak ETags can't be used there,
+/// so fall back to Last-Modified for those.
+fn if_range_validator(manifest: &DownloadManifest) -> Option<String> {
+    match &manifest.etag {
+        Some(etag) if !etag.starts_with("W/") => Some(etag.clone()),
+        _ => manifest.last_modified.clone(),
     }
 }
 
-/// Download a single segment of the file without requiring a &self reference
+/// Resolves once a stop has been requested, yielding the reason.
+/// Never resolves if the control handle is dropped without stopping.
+async fn wait_for_stop(stop_rx: &mut watch::Receiver<Option<StopReason>>) -> StopReason {
+    loop {
+        if let Some(reason) = *stop_rx.borrow() {
+            return reason;
+        }
+        if stop_rx.changed().await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+/// Download a single segment of the file without requiring a &self reference.
+///
+/// The range to fetch is read from `cursor`, whose end may be moved closer by the
+/// client while we're running when it hands part of our range to another connection.
 async fn download_segment_static(
     url: &str,
-    range_start: u64,
-    range_end: u64,
-    part_path: &PathBuf,
-    existing_bytes: u64,
-    total_chunks: u64,
+    if_range: Option<&str>,
+    cursor: SharedCursor,
+    output: &SegmentOutput,
     index: u64,
     segment_id: u64,
     progress_arc: Arc<Mutex<ClientProgress>>,
     event_tx: Sender<DownloadEvent>,
+    mut stop_rx: watch::Receiver<Option<StopReason>>,
+    throttle: Throttle,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let start_time = Instant::now();
-    println!("Starting download of segment {}: Range {}-{} (size: {})", 
+    let (segment_start, range_start, range_end, existing_bytes, unreported) = {
+        let mut cursor = cursor.lock().unwrap();
+        let unreported = cursor.written - cursor.reported;
+        cursor.reported = cursor.written;
+        (cursor.start, cursor.position(), cursor.end, cursor.written, unreported)
+    };
+    
+    // Report what's already on disk when resuming, or what a failed attempt didn't get to
+    if unreported > 0 {
+        {
+            let mut progress = progress_arc.lock().await;
+            progress.set_chunks(existing_bytes, &index);
+            progress.set_bytes_per_second(0.0, &index);
+        }
+        
+        event_tx.send(DownloadEvent::BytesReceived {
+            segment_id,
+            bytes: unreported,
+            speed: 0.0,
+        })?;
+    }
+    
+    // Only download if we haven't completed this segment
+    if range_start > range_end {
+        eprintln!("Segment {} is already complete", segment_id);
+        return Ok(());
+    }
+    
+    eprintln!("Starting download of segment {}: Range {}-{} (size: {})", 
              segment_id, range_start, range_end, range_end - range_start + 1);
 
     let client = reqwest::Client::new();
 
-    let response = client.get(url)
-        .header("Range", format!("bytes={}-{}", range_start, range_end))
-        .send()
-        .await?;
+    let mut request = client.get(url)
+        .header("Range", format!("bytes={}-{}", range_start, range_end));
+    if let Some(validator) = if_range {
+        request = request.header("If-Range", validator);
+    }
+    let request = request.send();
+    let response = tokio::select! {
+        response = request => response?,
+        reason = wait_for_stop(&mut stop_rx) => {
+            eprintln!("Segment {} stopped before receiving data ({:?})", segment_id, reason);
+            return Ok(());
+        }
+    };
 
     // Verify the server responded correctly to the range request
     let status = response.status();
-    println!("Segment {} HTTP status: {}", segment_id, status);
+    eprintln!("Segment {} HTTP status: {}", segment_id, status);
     if !status.is_success() {
-        return Err(format!("Server returned error status {} for segment {}", status, segment_id).into());
+        return Err(Box::new(HttpStatusError { segment_id, status: status.as_u16() }));
+    }
+    
+    // A full response instead of a partial one means our validator no longer
+    // matches, or without one, that the server doesn't do ranges after all
+    if status == reqwest::StatusCode::OK {
+        if if_range.is_some() {
+            return Err(Box::new(ResourceChanged { segment_id }));
+        }
+        return Err(Box::new(RangeIgnored { segment_id }));
     }
 
     // Check if the server respected our range request
     if let Some(content_range) = response.headers().get("content-range") {
-        println!("Segment {} Content-Range: {:?}", segment_id, content_range);
+        eprintln!("Segment {} Content-Range: {:?}", segment_id, content_range);
     } else {
-        println!("Warning: Server did not return Content-Range header for segment {}", segment_id);
+        eprintln!("Warning: Server did not return Content-Range header for segment {}", segment_id);
     }
 
-    // Open file in append mode if resuming, otherwise create new
-    let mut file = if existing_bytes > 0 {
-        tokio::fs::OpenOptions::new()
-            .write(true)
-            .append(true)
-            .open(part_path).await?
-    } else {
-        tokio::fs::File::create(part_path).await?
+    let mut file = match output {
+        // Open file in append mode if resuming, otherwise create new
+        SegmentOutput::PartFile(part_path) if existing_bytes > 0 => {
+            tokio::fs::OpenOptions::new()
+                .write(true)
+                .append(true)
+                .open(part_path).await?
+        },
+        SegmentOutput::PartFile(part_path) => tokio::fs::File::create(part_path).await?,
+        // Write into our own range of the preallocated file
+        SegmentOutput::Preallocated { path, .. } => {
+            let mut file = tokio::fs::OpenOptions::new()
+                .write(true)
+                .open(path).await?;
+            file.seek(SeekFrom::Start(range_start)).await?;
+            file
+        },
     };
 
-    println!("Segment {} file opened: {} with {} existing bytes", 
-             segment_id, part_path.display(), existing_bytes);
-
-    // Report initial progress if resuming
-    if existing_bytes > 0 {
-        let bytes_per_second = 0.0; // Initial speed is 0 when resuming
-        
-        // Update progress tracker with existing bytes
-        {
-            let mut progress = progress_arc.lock().await;
-            progress.set_chunks(existing_bytes, &index);
-            progress.set_bytes_per_second(bytes_per_second, &index);
-        }
-        
-        // Send initial bytes received event for existing bytes
-        event_tx.send(DownloadEvent::BytesReceived {
-            segment_id,
-            bytes: existing_bytes,
-            speed: bytes_per_second,
-        })?;
-    }
+    eprintln!("Segment {} file opened: {} with {} existing bytes", 
+             segment_id, output.path().display(), existing_bytes);
+    
+    // Everything before this offset is already marked in the progress bitmap
+    let mut marked = range_start;
     
     let mut bytes_downloaded = existing_bytes;
     let mut last_reported_bytes = existing_bytes;
     let mut chunks_received = 0;
+    let mut complete = false;
     
     let mut response = response;
-    while let Some(chunk) = response.chunk().await? {
-        let chunk_size = chunk.len() as u64;
+    loop {
+        let chunk = tokio::select! {
+            chunk = tokio::time::timeout(STALL_TIMEOUT, response.chunk()) => chunk,
+            reason = wait_for_stop(&mut stop_rx) => {
+                // Keep what we have on disk so a paused segment can resume from here
+                file.flush().await?;
+                mark_preallocated(output, &mut file, marked, segment_start + bytes_downloaded).await?;
+                eprintln!("Segment {} stopped at {} bytes ({:?})",
+                         segment_id, bytes_downloaded, reason);
+                return Ok(());
+            }
+        };
+        let chunk = match chunk {
+            Ok(Ok(Some(chunk))) => chunk,
+            Ok(Ok(None)) => break,
+            Ok(Err(e)) => {
+                file.flush().await?;
+                return Err(Box::new(e));
+            },
+            Err(_) => {
+                file.flush().await?;
+                let missing_bytes = cursor.lock().unwrap().remaining();
+                return Err(Box::new(SegmentInterrupted { segment_id, missing_bytes, stalled: true }));
+            }
+        };
         chunks_received += 1;
-        bytes_downloaded += chunk_size;
-        
-        // Ensure bytes_downloaded never exceeds total_chunks
-        bytes_downloaded = std::cmp::min(bytes_downloaded, total_chunks);
         
-        let bytes_per_second = (bytes_downloaded - existing_bytes) as f64 / start_time.elapsed().as_secs_f64();
+        // Claim the bytes before writing them, so the range can't be split below them.
+        // Anything past our (possibly moved) end belongs to another segment.
+        let (accepted, total_chunks) = {
+            let mut cursor = cursor.lock().unwrap();
+            let accepted = std::cmp::min(chunk.len() as u64, cursor.remaining());
+            cursor.written += accepted;
+            complete = cursor.remaining() == 0;
+            (accepted, cursor.size())
+        };
         
         // Write chunk to file
-        file.write_all(&chunk).await?;
+        file.write_all(&chunk[..accepted as usize]).await?;
+        bytes_downloaded += accepted;
+        
+        let bytes_per_second = (bytes_downloaded - existing_bytes) as f64 / start_time.elapsed().as_secs_f64();
         
         // Only report progress if enough has changed (avoid too frequent updates)
         let bytes_change = bytes_downloaded - last_reported_bytes;
-        if bytes_change >= 16 * 1024 || bytes_downloaded == total_chunks {
+        if bytes_change >= 16 * 1024 || complete {
             // Update progress tracker
             {
                 let mut progress = progress_arc.lock().await;
+                progress.set_total_bytes(total_chunks, &index);
                 progress.set_chunks(bytes_downloaded, &index);
                 progress.set_bytes_per_second(bytes_per_second, &index);
             }
@@ -557,52 +1705,155 @@ async fn download_segment_static(
             })?;
             
             last_reported_bytes = bytes_downloaded;
-            println!("Segment {} progress: {}/{} bytes ({:.1}%)", 
+            cursor.lock().unwrap().reported = bytes_downloaded;
+            mark_preallocated(output, &mut file, marked, segment_start + bytes_downloaded).await?;
+            marked = segment_start + bytes_downloaded;
+            eprintln!("Segment {} progress: {}/{} bytes ({:.1}%)", 
                      segment_id, bytes_downloaded, total_chunks,
                      (bytes_downloaded as f64 / total_chunks as f64) * 100.0);
-            
-            // If we've reached 100%, break to avoid any potential overruns
-            if bytes_downloaded >= total_chunks {
-                break;
-            }
         }
-    }
-    
-    // Ensure we send a final update with exactly 100% progress
-    if last_reported_bytes < total_chunks {
-        let final_bytes = total_chunks - last_reported_bytes;
-        let final_speed = (total_chunks - existing_bytes) as f64 / start_time.elapsed().as_secs_f64();
         
-        // Update local tracker with final value
-        {
-            let mut progress = progress_arc.lock().await;
-            progress.set_chunks(total_chunks, &index);
-            progress.set_bytes_per_second(final_speed, &index);
+        // Stop reading once our range is done, dropping the rest of the response
+        if complete {
+            break;
         }
         
-        // Send final update
-        event_tx.send(DownloadEvent::BytesReceived {
-            segment_id,
-            bytes: final_bytes,
-            speed: final_speed,
-        })?;
-    }
-    
-    // Verify the part file was written correctly
-    if let Ok(metadata) = tokio::fs::metadata(&part_path).await {
-        let final_size = metadata.len();
-        println!("Segment {} completed: Downloaded {} bytes in {} chunks, file size: {}",
-                 segment_id, bytes_downloaded - existing_bytes, chunks_received, final_size);
-        
-        if final_size == 0 {
-            return Err(format!("Segment {} file is empty after download", segment_id).into());
+        // Respect the download and global speed limits before reading more
+        tokio::select! {
+            _ = throttle.consume(accepted) => {},
+            reason = wait_for_stop(&mut stop_rx) => {
+                file.flush().await?;
+                mark_preallocated(output, &mut file, marked, segment_start + bytes_downloaded).await?;
+                eprintln!("Segment {} stopped at {} bytes ({:?})",
+                         segment_id, bytes_downloaded, reason);
+                return Ok(());
+            }
         }
-    } else {
-        return Err(format!("Segment {} file not found after download", segment_id).into());
     }
-
+    
     // Flush the file to ensure all data is written
     file.flush().await?;
     
+    eprintln!("Segment {} finished: Downloaded {} bytes in {} chunks",
+             segment_id, bytes_downloaded - existing_bytes, chunks_received);
+    
+    if !complete {
+        let missing_bytes = cursor.lock().unwrap().remaining();
+        return Err(Box::new(SegmentInterrupted { segment_id, missing_bytes, stalled: false }));
+    }
+    
+    Ok(())
+}
+
+/// Record in the progress bitmap that a preallocated segment has written the bytes
+/// `from..to`. Does nothing for segments writing part files.
+async fn mark_preallocated(
+    output: &SegmentOutput,
+    file: &mut tokio::fs::File,
+    from: u64,
+    to: u64,
+) -> std::io::Result<()> {
+    if let SegmentOutput::Preallocated { bitmap, .. } = output {
+        // Blocks may only be marked once their data has left our buffers
+        file.flush().await?;
+        
+        // The start of `from`'s block was written by this segment as well,
+        // since segments own whole blocks
+        let from = from - from % BLOCK_SIZE;
+        let mut bitmap = bitmap.lock().await;
+        bitmap.mark_written(from, to);
+        if let Err(e) = bitmap.save_if_due().await {
+            eprintln!("Failed to save progress bitmap: {}", e);
+        }
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIB: u64 = 1024 * 1024;
+
+    fn cursor(start: u64, end: u64, written: u64) -> SharedCursor {
+        Arc::new(StdMutex::new(SegmentCursor { start, end, written, reported: written }))
+    }
+
+    fn running(indexes: &[u64]) -> HashSet<u64> {
+        indexes.iter().copied().collect()
+    }
+
+    fn range(cursor: &SharedCursor) -> (u64, u64) {
+        let cursor = cursor.lock().unwrap();
+        (cursor.start, cursor.end)
+    }
+
+    #[test]
+    fn splits_the_largest_running_range_in_half() {
+        let cursors = vec![cursor(0, 10 * MIB - 1, 0), cursor(10 * MIB, 14 * MIB - 1, 0)];
+        let (victim, tail) = split_largest(&cursors, &running(&[0, 1]), 1).unwrap();
+        assert_eq!(victim, 0);
+        assert_eq!((tail.start, tail.end, tail.written), (5 * MIB, 10 * MIB - 1, 0));
+        assert_eq!(range(&cursors[0]), (0, 5 * MIB - 1));
+        assert_eq!(range(&cursors[1]), (10 * MIB, 14 * MIB - 1));
+    }
+
+    #[test]
+    fn skips_segments_that_are_not_running() {
+        let cursors = vec![cursor(0, 10 * MIB - 1, 0), cursor(10 * MIB, 14 * MIB - 1, 0)];
+        let (victim, tail) = split_largest(&cursors, &running(&[1]), 1).unwrap();
+        assert_eq!(victim, 1);
+        assert_eq!((tail.start, tail.end), (12 * MIB, 14 * MIB - 1));
+        assert!(split_largest(&cursors, &running(&[]), 1).is_none());
+    }
+
+    #[test]
+    fn splits_what_is_left_to_download() {
+        // 8 MiB are written, so only the remaining 2 MiB are halved
+        let cursors = vec![cursor(0, 10 * MIB - 1, 8 * MIB)];
+        let (_, tail) = split_largest(&cursors, &running(&[0]), 1).unwrap();
+        assert_eq!((tail.start, tail.end), (9 * MIB, 10 * MIB - 1));
+        assert_eq!(range(&cursors[0]), (0, 9 * MIB - 1));
+    }
+
+    #[test]
+    fn leaves_ranges_below_twice_the_minimum_alone() {
+        let cursors = vec![cursor(0, 2 * MIN_SPLIT_SIZE - 2, 0)];
+        assert!(split_largest(&cursors, &running(&[0]), 1).is_none());
+        assert_eq!(range(&cursors[0]), (0, 2 * MIN_SPLIT_SIZE - 2));
+
+        let cursors = vec![cursor(0, 2 * MIN_SPLIT_SIZE - 1, 0)];
+        let (_, tail) = split_largest(&cursors, &running(&[0]), 1).unwrap();
+        assert_eq!(tail.size(), MIN_SPLIT_SIZE);
+    }
+
+    #[test]
+    fn aligns_the_split_point() {
+        let alignment = 64 * 1024;
+        let cursors = vec![cursor(0, 10 * MIB + 99, 0)];
+        let (_, tail) = split_largest(&cursors, &running(&[0]), alignment).unwrap();
+        assert_eq!(tail.start, 5 * MIB + alignment);
+        assert_eq!(tail.start % alignment, 0);
+        assert_eq!(range(&cursors[0]), (0, 5 * MIB + alignment - 1));
+    }
+
+    #[test]
+    fn stops_splitting_at_the_segment_limit() {
+        let cursors: Vec<SharedCursor> = (0..MAX_SEGMENTS)
+            .map(|index| cursor(index * 10 * MIB, (index + 1) * 10 * MIB - 1, 0))
+            .collect();
+        let all: Vec<u64> = (0..MAX_SEGMENTS).collect();
+        assert!(split_largest(&cursors, &running(&all), 1).is_none());
+        assert!(split_largest(&cursors[1..], &running(&all), 1).is_some());
+    }
+
+    #[test]
+    fn cursor_counts_remaining_bytes_from_its_position() {
+        let cursor = SegmentCursor { start: 100, end: 199, written: 30, reported: 0 };
+        assert_eq!(cursor.position(), 130);
+        assert_eq!(cursor.remaining(), 70);
+        assert_eq!(cursor.size(), 100);
+        let done = SegmentCursor { start: 100, end: 199, written: 100, reported: 100 };
+        assert_eq!(done.remaining(), 0);
+    }
+}
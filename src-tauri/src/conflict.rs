@@ -0,0 +1,127 @@
+use crate::db_manager;
+use std::error::Error;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// Settings key for what happens when the output file already exists
+const CONFLICT_POLICY_KEY: &str = "file_conflict_policy";
+
+/// Gives up looking for a free name after this many `name (n).ext` candidates
+const MAX_RENAME_ATTEMPTS: u32 = 9999;
+
+/// What to do when a download's output file already exists
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// Replace the existing file
+    Overwrite,
+    /// Save as `name (1).ext`, `name (2).ext` and so on
+    Rename,
+    /// Leave the existing file alone and drop the download
+    Skip,
+    /// Stop before writing and keep the download until the user picks a policy
+    Ask,
+}
+
+/// Used when the setting hasn't been stored yet
+pub const DEFAULT_CONFLICT_POLICY: ConflictPolicy = ConflictPolicy::Rename;
+
+impl ConflictPolicy {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "overwrite" => Some(Self::Overwrite),
+            "rename" => Some(Self::Rename),
+            "skip" => Some(Self::Skip),
+            "ask" => Some(Self::Ask),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Overwrite => "overwrite",
+            Self::Rename => "rename",
+            Self::Skip => "skip",
+            Self::Ask => "ask",
+        }
+    }
+}
+
+impl fmt::Display for ConflictPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// The output file already exists and the policy doesn't allow replacing or renaming it
+#[derive(Debug)]
+pub struct OutputExists {
+    pub path: PathBuf,
+    pub policy: ConflictPolicy,
+}
+
+impl fmt::Display for OutputExists {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.policy {
+            ConflictPolicy::Skip => write!(f, "{} already exists, skipped the download", self.path.display()),
+            _ => write!(f, "{} already exists, choose whether to overwrite it or save under another name", self.path.display()),
+        }
+    }
+}
+
+impl Error for OutputExists {}
+
+/// `name (n).ext` next to `path`
+pub fn numbered(path: &Path, n: u32) -> PathBuf {
+    let file_name = path.file_name().and_then(|name| name.to_str()).unwrap_or("file");
+    // Split on the last dot, but a leading dot belongs to the name
+    let renamed = match file_name.rfind('.') {
+        Some(dot) if dot > 0 => format!("{} ({}){}", &file_name[..dot], n, &file_name[dot..]),
+        _ => format!("{} ({})", file_name, n),
+    };
+    path.with_file_name(renamed)
+}
+
+/// Claim the path the output is written to by creating it empty, so a concurrent
+/// download can't claim the same one. With `Overwrite` the path is used as is.
+/// Uses blocking I/O, so run it off the async runtime.
+pub fn claim(path: &Path, policy: ConflictPolicy) -> Result<PathBuf, Box<dyn Error + Send + Sync>> {
+    if policy == ConflictPolicy::Overwrite || create_new(path)? {
+        return Ok(path.to_path_buf());
+    }
+    if policy != ConflictPolicy::Rename {
+        return Err(OutputExists { path: path.to_path_buf(), policy }.into());
+    }
+    for n in 1..=MAX_RENAME_ATTEMPTS {
+        let candidate = numbered(path, n);
+        if create_new(&candidate)? {
+            return Ok(candidate);
+        }
+    }
+    Err(format!("Couldn't find a free name for {}", path.display()).into())
+}
+
+/// Create an empty file unless one exists already, returning whether it was created
+fn create_new(path: &Path) -> std::io::Result<bool> {
+    match std::fs::OpenOptions::new().write(true).create_new(true).open(path) {
+        Ok(_) => Ok(true),
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Get what happens when a download's output file already exists
+pub async fn conflict_policy() -> ConflictPolicy {
+    match db_manager::get_setting(CONFLICT_POLICY_KEY).await {
+        Ok(Some(value)) => ConflictPolicy::parse(&value).unwrap_or(DEFAULT_CONFLICT_POLICY),
+        Ok(None) => DEFAULT_CONFLICT_POLICY,
+        Err(e) => {
+            eprintln!("Failed to read {} setting: {}", CONFLICT_POLICY_KEY, e);
+            DEFAULT_CONFLICT_POLICY
+        }
+    }
+}
+
+/// Set what happens when a download's output file already exists
+pub async fn set_conflict_policy(policy: ConflictPolicy) -> rusqlite::Result<()> {
+    db_manager::set_setting(CONFLICT_POLICY_KEY, policy.as_str()).await
+}
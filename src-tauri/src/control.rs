@@ -0,0 +1,687 @@
+use crate::aria2;
+use crate::conflict::ConflictPolicy;
+use crate::db_manager;
+use crate::error::SpeedyError;
+use crate::manager::{self, DownloadManager, DownloadRequest, EventSink};
+use crate::probe;
+use crate::queue;
+use crate::registry;
+use crate::status::DownloadStatus;
+use crate::throttle;
+use base64::Engine;
+use hyper::body::HttpBody;
+use hyper::header::{self, HeaderValue};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::upgrade::Upgraded;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use rand::RngCore;
+use serde::de::DeserializeOwned;
+use serde_json::{json, Map, Value};
+use sha1::{Digest, Sha1};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::{mpsc, Mutex, OnceCell};
+use tokio::task::JoinHandle;
+
+/// Settings key for whether the control API is served
+const ENABLED_KEY: &str = "control_api_enabled";
+
+/// Settings key for the port the control API listens on
+const PORT_KEY: &str = "control_api_port";
+
+/// Settings key for the token clients of the control API authenticate with
+const TOKEN_KEY: &str = "control_api_token";
+
+/// Used when the setting hasn't been stored yet: nothing listens until the user opts in
+pub const DEFAULT_ENABLED: bool = false;
+
+/// Used when the setting hasn't been stored yet
+pub const DEFAULT_PORT: u16 = 6800;
+
+/// Largest request body a command is read from
+const MAX_BODY_BYTES: usize = 1024 * 1024;
+
+/// Largest WebSocket frame read from a client, which only ever sends pings and closes
+const MAX_FRAME_BYTES: u64 = 64 * 1024;
+
+/// Progress messages kept for a WebSocket client that is slow to read them
+const EVENT_BUFFER: usize = 256;
+
+/// Appended to the client's key to answer a WebSocket handshake (RFC 6455)
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+// The running server, replaced when its settings change
+static SERVER: Mutex<Option<JoinHandle<()>>> = Mutex::const_new(None);
+
+// Progress messages for WebSocket clients, fed by a sink added to the manager once
+static EVENTS: OnceCell<broadcast::Sender<String>> = OnceCell::const_new();
+
+/// Get whether the control API is served
+pub async fn control_api_enabled() -> bool {
+    match db_manager::get_setting(ENABLED_KEY).await {
+        Ok(Some(value)) => value == "true",
+        Ok(None) => DEFAULT_ENABLED,
+        Err(e) => {
+            eprintln!("Failed to read {} setting: {}", ENABLED_KEY, e);
+            DEFAULT_ENABLED
+        }
+    }
+}
+
+/// Set whether the control API is served, applies once the server is restarted
+pub async fn set_control_api_enabled(enabled: bool) -> rusqlite::Result<()> {
+    db_manager::set_setting(ENABLED_KEY, if enabled { "true" } else { "false" }).await
+}
+
+/// Get the port the control API listens on
+pub async fn control_api_port() -> u16 {
+    match db_manager::get_setting(PORT_KEY).await {
+        Ok(Some(value)) => value.parse().unwrap_or(DEFAULT_PORT),
+        Ok(None) => DEFAULT_PORT,
+        Err(e) => {
+            eprintln!("Failed to read {} setting: {}", PORT_KEY, e);
+            DEFAULT_PORT
+        }
+    }
+}
+
+/// Set the port the control API listens on, applies once the server is restarted
+pub async fn set_control_api_port(port: u16) -> rusqlite::Result<()> {
+    db_manager::set_setting(PORT_KEY, &port.to_string()).await
+}
+
+/// Get the token clients authenticate with, creating one the first time
+pub async fn control_api_token() -> rusqlite::Result<String> {
+    match db_manager::get_setting(TOKEN_KEY).await? {
+        Some(token) if !token.is_empty() => Ok(token),
+        _ => reset_control_api_token().await,
+    }
+}
+
+/// Replace the token clients authenticate with by a new random one
+pub async fn reset_control_api_token() -> rusqlite::Result<String> {
+    let mut bytes = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let token: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    db_manager::set_setting(TOKEN_KEY, &token).await?;
+    Ok(token)
+}
+
+/// Forwards download progress to the WebSocket clients
+struct ProgressSink {
+    events: broadcast::Sender<String>,
+}
+
+impl EventSink for ProgressSink {
+    fn progress(&self, _download_id: u64, payload: &Map<String, Value>) {
+        // Nobody to tell, skip building the message
+        if self.events.receiver_count() == 0 {
+            return;
+        }
+        let message = json!({ "event": "download-progress", "payload": payload });
+        let _ = self.events.send(message.to_string());
+    }
+}
+
+struct Context {
+    manager: DownloadManager,
+    token: String,
+    events: broadcast::Sender<String>,
+}
+
+/// Start serving the control API on localhost if it's enabled, stopping the server
+/// already running so changed settings apply
+pub async fn restart(manager: DownloadManager) {
+    let mut server = SERVER.lock().await;
+    if let Some(handle) = server.take() {
+        handle.abort();
+    }
+    if !control_api_enabled().await {
+        return;
+    }
+
+    let token = match control_api_token().await {
+        Ok(token) => token,
+        Err(e) => {
+            eprintln!("Failed to read control API token, not starting it: {}", e);
+            return;
+        }
+    };
+    let events = EVENTS
+        .get_or_init(|| async {
+            let (events, _) = broadcast::channel(EVENT_BUFFER);
+            manager.add_sink(ProgressSink { events: events.clone() });
+            events
+        })
+        .await
+        .clone();
+
+    // Only reachable from this machine, the token keeps out other users and web pages
+    let address = SocketAddr::from(([127, 0, 0, 1], control_api_port().await));
+    let builder = match hyper::Server::try_bind(&address) {
+        Ok(builder) => builder,
+        Err(e) => {
+            eprintln!("Failed to start control API on {}: {}", address, e);
+            return;
+        }
+    };
+    let context = Arc::new(Context { manager, token, events });
+    let make_service = make_service_fn(move |_| {
+        let context = context.clone();
+        async move { Ok::<_, Infallible>(service_fn(move |request| handle(context.clone(), request))) }
+    });
+
+    eprintln!("Control API listening on http://{}", address);
+    *server = Some(tokio::spawn(async move {
+        if let Err(e) = builder.serve(make_service).await {
+            eprintln!("Control API stopped: {}", e);
+        }
+    }));
+}
+
+async fn handle(context: Arc<Context>, mut request: Request<Body>) -> Result<Response<Body>, Infallible> {
+    // A page on another site resolving its name to 127.0.0.1 still sends its own Host
+    if !is_local_host(&request) {
+        return Ok(failure(StatusCode::FORBIDDEN, "forbidden", "Only requests to localhost are served"));
+    }
+    if request.method() == Method::OPTIONS {
+        return Ok(with_cors(Response::new(Body::empty())));
+    }
+    // aria2 clients send the token inside each JSON-RPC call instead
+    let path = request.uri().path().to_string();
+    let authorized = is_authorized(&request, &context.token);
+    if !authorized && path != "/jsonrpc" {
+        return Ok(failure(StatusCode::UNAUTHORIZED, "unauthorized", "Missing or wrong token"));
+    }
+
+    let response = match (request.method(), path.strip_prefix("/api/")) {
+        (&Method::GET, None) if path == "/ws" => websocket(context, request),
+        (&Method::POST, None) if path == "/jsonrpc" => {
+            let reply = match read_args(request.body_mut()).await {
+                Ok(body) => aria2::handle(&context.manager, &context.token, authorized, body).await,
+                Err(e) => aria2::parse_error(&e),
+            };
+            json_response(StatusCode::OK, &reply)
+        },
+        (&Method::POST, Some(command)) => {
+            let result = match read_args(request.body_mut()).await {
+                Ok(args) => call(&context.manager, command, &args).await,
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(value) => json_response(StatusCode::OK, &value),
+                Err(e) => json_response(status_for(&e), &json!(e)),
+            }
+        },
+        _ => failure(StatusCode::NOT_FOUND, "not_found", "Use POST /api/<command>, GET /ws or POST /jsonrpc"),
+    };
+    Ok(response)
+}
+
+fn is_local_host(request: &Request<Body>) -> bool {
+    let host = match request.headers().get(header::HOST).and_then(|host| host.to_str().ok()) {
+        Some(host) => host,
+        None => return false,
+    };
+    let name = match host.strip_prefix('[') {
+        Some(rest) => rest.split(']').next().unwrap_or(""),
+        None => host.split(':').next().unwrap_or(""),
+    };
+    matches!(name, "localhost" | "127.0.0.1" | "::1")
+}
+
+/// Checks the token from an `Authorization: Bearer` header, or from the `token`
+/// query parameter for WebSocket clients in browsers, which can't set headers
+fn is_authorized(request: &Request<Body>, token: &str) -> bool {
+    let from_header = request.headers().get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    let from_query = request.uri().query()
+        .and_then(|query| query.split('&').find_map(|pair| pair.strip_prefix("token=")));
+    match from_header.or(from_query) {
+        Some(given) => tokens_match(given.trim(), token),
+        None => false,
+    }
+}
+
+/// Compares every byte so the time taken doesn't reveal how much of the token matched
+pub fn tokens_match(given: &str, token: &str) -> bool {
+    given.len() == token.len()
+        && given.bytes().zip(token.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+fn with_cors(mut response: Response<Body>) -> Response<Body> {
+    let headers = response.headers_mut();
+    headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, HeaderValue::from_static("*"));
+    headers.insert(header::ACCESS_CONTROL_ALLOW_HEADERS, HeaderValue::from_static("Authorization, Content-Type"));
+    headers.insert(header::ACCESS_CONTROL_ALLOW_METHODS, HeaderValue::from_static("GET, POST, OPTIONS"));
+    response
+}
+
+fn json_response(status: StatusCode, value: &Value) -> Response<Body> {
+    let mut response = Response::new(Body::from(value.to_string()));
+    *response.status_mut() = status;
+    response.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    with_cors(response)
+}
+
+/// A failure that happens before any command runs, shaped like a SpeedyError
+fn failure(status: StatusCode, code: &str, message: &str) -> Response<Body> {
+    json_response(status, &json!({ "code": code, "message": message }))
+}
+
+fn status_for(error: &SpeedyError) -> StatusCode {
+    match error {
+        SpeedyError::InvalidInput { .. } => StatusCode::BAD_REQUEST,
+        SpeedyError::NotFound { .. } => StatusCode::NOT_FOUND,
+        SpeedyError::Conflict { .. } => StatusCode::CONFLICT,
+        SpeedyError::Network { .. } | SpeedyError::HttpStatus { .. } => StatusCode::BAD_GATEWAY,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// Reads the JSON object holding a command's arguments, an empty body means none
+async fn read_args(body: &mut Body) -> Result<Value, SpeedyError> {
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|e| SpeedyError::invalid_input(format!("Failed to read request: {}", e)))?;
+        if bytes.len() + chunk.len() > MAX_BODY_BYTES {
+            return Err(SpeedyError::invalid_input("Request body is too large"));
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    if bytes.is_empty() {
+        return Ok(Value::Object(Map::new()));
+    }
+    serde_json::from_slice(&bytes).map_err(|e| SpeedyError::invalid_input(format!("Invalid JSON: {}", e)))
+}
+
+fn arg<T: DeserializeOwned>(args: &Value, name: &str) -> Result<T, SpeedyError> {
+    serde_json::from_value(args.get(name).cloned().unwrap_or(Value::Null))
+        .map_err(|e| SpeedyError::invalid_input(format!("Invalid {}: {}", name, e)))
+}
+
+// The app passes IDs and part counts as strings since JavaScript numbers can't hold
+// every u64, scripts are just as likely to send numbers
+fn u64_arg(args: &Value, name: &str) -> Result<Option<u64>, SpeedyError> {
+    match args.get(name) {
+        None | Some(Value::Null) => Ok(None),
+        Some(Value::String(value)) => value.parse().map(Some)
+            .map_err(|_| SpeedyError::invalid_input(format!("Invalid {}: {}", name, value))),
+        Some(Value::Number(value)) => value.as_u64().map(Some)
+            .ok_or_else(|| SpeedyError::invalid_input(format!("Invalid {}: {}", name, value))),
+        Some(value) => Err(SpeedyError::invalid_input(format!("Invalid {}: {}", name, value))),
+    }
+}
+
+fn download_id_arg(args: &Value) -> Result<u64, SpeedyError> {
+    u64_arg(args, "downloadId")?.ok_or_else(|| SpeedyError::invalid_input("Missing downloadId"))
+}
+
+fn to_json<T: serde::Serialize>(value: T) -> Result<Value, SpeedyError> {
+    serde_json::to_value(value).map_err(|e| SpeedyError::other(format!("Failed to encode response: {}", e)))
+}
+
+/// Runs a command the way the app's command of the same name does, on the engine
+/// directly. Arguments are
+/// named as the app passes them, e.g. `downloadId`.
+async fn call(manager: &DownloadManager, command: &str, args: &Value) -> Result<Value, SpeedyError> {
+    match command {
+        "start_download" => {
+            let parts = u64_arg(args, "parts")?.unwrap_or(manager::DEFAULT_PARTS);
+            let mut request = DownloadRequest::new(arg(args, "url")?, parts);
+            request.name = arg(args, "name")?;
+            request.download_id = u64_arg(args, "downloadId")?;
+            request.directory = arg(args, "directory")?;
+            request.checksum = arg(args, "checksum")?;
+            request.checksum_url = arg(args, "checksumUrl")?;
+            let download_id = manager.add(request).await?;
+            Ok(json!({ "downloadId": download_id.to_string() }))
+        },
+        "list_downloads" => {
+            let downloads = db_manager::list_downloads().await
+                .map_err(|e| SpeedyError::from(e).context("Failed to list downloads"))?;
+            to_json(downloads)
+        },
+        "get_download" => {
+            let download = db_manager::get_download(download_id_arg(args)?).await
+                .map_err(|e| SpeedyError::from(e).context("Failed to get download"))?;
+            to_json(download)
+        },
+        "get_downloads_by_status" => {
+            let status: DownloadStatus = arg(args, "status")?;
+            let downloads = db_manager::get_downloads_by_status(status).await
+                .map_err(|e| SpeedyError::from(e).context("Failed to get downloads by status"))?;
+            to_json(downloads)
+        },
+        "get_queue" => {
+            let downloads = db_manager::get_queued_downloads().await
+                .map_err(|e| SpeedyError::from(e).context("Failed to get queue"))?;
+            to_json(downloads)
+        },
+        "pause_download" => to_json(manager.pause(download_id_arg(args)?).await?),
+        "resume_download" => to_json(manager.resume(download_id_arg(args)?).await?),
+        "cancel_download" => to_json(manager.cancel(download_id_arg(args)?).await?),
+        "delete_download" => {
+            let delete_file: Option<bool> = arg(args, "shouldAlsoDeleteFile")?;
+            to_json(manager.remove(download_id_arg(args)?, delete_file.unwrap_or(false)).await?)
+        },
+        "resolve_conflict" => {
+            let policy: String = arg(args, "policy")?;
+            let policy = ConflictPolicy::parse(&policy)
+                .ok_or_else(|| SpeedyError::invalid_input(format!("Invalid policy: {}. Use overwrite or rename", policy)))?;
+            to_json(manager.resolve_conflict(download_id_arg(args)?, policy).await?)
+        },
+        "set_download_priority" => {
+            let priority: i32 = arg(args, "priority")?;
+            db_manager::update_priority(download_id_arg(args)?, priority).await
+                .map_err(|e| SpeedyError::from(e).context("Failed to set download priority"))?;
+            Ok(Value::Null)
+        },
+        "move_in_queue" => {
+            let position: u32 = arg(args, "position")?;
+            to_json(queue::move_to(download_id_arg(args)?, position as usize).await?)
+        },
+        "set_speed_limit" => {
+            let limit_k_bps: f64 = arg(args, "limitKBps")?;
+            registry::set_speed_limit(download_id_arg(args)?, throttle::kbps_to_limit(limit_k_bps)).await;
+            Ok(Value::Null)
+        },
+        "set_global_speed_limit" => {
+            let limit_k_bps: f64 = arg(args, "limitKBps")?;
+            throttle::GLOBAL_LIMITER.set_limit(throttle::kbps_to_limit(limit_k_bps));
+            Ok(Value::Null)
+        },
+        "probe_url" => {
+            let url: String = arg(args, "url")?;
+            to_json(probe::probe(&reqwest::Client::new(), &url).await?)
+        },
+        "check_existing_download" => {
+            let url: String = arg(args, "url")?;
+            manager::check_existing_download(&url).await
+        },
+        _ => Err(SpeedyError::not_found(format!("Unknown command: {}", command))),
+    }
+}
+
+/// Accepts a WebSocket connection that progress is streamed over
+fn websocket(context: Arc<Context>, mut request: Request<Body>) -> Response<Body> {
+    let is_upgrade = request.headers().get(header::UPGRADE)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.eq_ignore_ascii_case("websocket"))
+        .unwrap_or(false);
+    let key = match request.headers().get(header::SEC_WEBSOCKET_KEY) {
+        Some(key) if is_upgrade => key.as_bytes().to_vec(),
+        _ => return failure(StatusCode::BAD_REQUEST, "invalid_input", "Expected a WebSocket handshake"),
+    };
+    let mut hasher = Sha1::new();
+    hasher.update(&key);
+    hasher.update(WEBSOCKET_GUID.as_bytes());
+    let accept = base64::engine::general_purpose::STANDARD.encode(hasher.finalize());
+
+    let events = context.events.subscribe();
+    tokio::spawn(async move {
+        match hyper::upgrade::on(&mut request).await {
+            Ok(stream) => stream_progress(stream, events).await,
+            Err(e) => eprintln!("WebSocket handshake failed: {}", e),
+        }
+    });
+
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = StatusCode::SWITCHING_PROTOCOLS;
+    let headers = response.headers_mut();
+    headers.insert(header::UPGRADE, HeaderValue::from_static("websocket"));
+    headers.insert(header::CONNECTION, HeaderValue::from_static("Upgrade"));
+    headers.insert(header::SEC_WEBSOCKET_ACCEPT, HeaderValue::from_str(&accept).expect("base64 is a valid header value"));
+    response
+}
+
+/// Sends every progress message to the client until it closes the connection
+async fn stream_progress(stream: Upgraded, mut events: broadcast::Receiver<String>) {
+    let (mut reader, mut writer) = tokio::io::split(stream);
+
+    // Frames are read on their own task, since a read interrupted halfway loses its place
+    let (replies_tx, mut replies) = mpsc::unbounded_channel::<(u8, Vec<u8>)>();
+    let read_task = tokio::spawn(async move {
+        loop {
+            match read_frame(&mut reader).await {
+                Ok((OPCODE_PING, payload)) => {
+                    let _ = replies_tx.send((OPCODE_PONG, payload));
+                },
+                Ok((OPCODE_CLOSE, _)) | Err(_) => {
+                    let _ = replies_tx.send((OPCODE_CLOSE, Vec::new()));
+                    break;
+                },
+                // Clients have nothing to say besides pings
+                Ok(_) => {},
+            }
+        }
+    });
+
+    loop {
+        let result = tokio::select! {
+            message = events.recv() => match message {
+                Ok(message) => write_frame(&mut writer, OPCODE_TEXT, message.as_bytes()).await,
+                // A slow client misses some updates, the next one catches it up
+                Err(RecvError::Lagged(_)) => Ok(()),
+                Err(RecvError::Closed) => break,
+            },
+            reply = replies.recv() => match reply {
+                Some((OPCODE_CLOSE, _)) | None => {
+                    let _ = write_frame(&mut writer, OPCODE_CLOSE, &[]).await;
+                    break;
+                },
+                Some((opcode, payload)) => write_frame(&mut writer, opcode, &payload).await,
+            },
+        };
+        if result.is_err() {
+            break;
+        }
+    }
+    read_task.abort();
+}
+
+/// Reads one frame sent by a client, unmasking its payload
+async fn read_frame(reader: &mut (impl AsyncRead + Unpin)) -> std::io::Result<(u8, Vec<u8>)> {
+    let mut header = [0u8; 2];
+    reader.read_exact(&mut header).await?;
+    let opcode = header[0] & 0x0f;
+    let masked = header[1] & 0x80 != 0;
+    let length = match header[1] & 0x7f {
+        126 => {
+            let mut length = [0u8; 2];
+            reader.read_exact(&mut length).await?;
+            u16::from_be_bytes(length) as u64
+        },
+        127 => {
+            let mut length = [0u8; 8];
+            reader.read_exact(&mut length).await?;
+            u64::from_be_bytes(length)
+        },
+        length => length as u64,
+    };
+    if length > MAX_FRAME_BYTES {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "WebSocket frame is too large"));
+    }
+
+    let mut mask = [0u8; 4];
+    if masked {
+        reader.read_exact(&mut mask).await?;
+    }
+    let mut payload = vec![0u8; length as usize];
+    reader.read_exact(&mut payload).await?;
+    if masked {
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+    }
+    Ok((opcode, payload))
+}
+
+/// Writes one unfragmented frame, servers don't mask theirs
+async fn write_frame(writer: &mut (impl AsyncWrite + Unpin), opcode: u8, payload: &[u8]) -> std::io::Result<()> {
+    let mut frame = Vec::with_capacity(payload.len() + 10);
+    frame.push(0x80 | opcode);
+    if payload.len() < 126 {
+        frame.push(payload.len() as u8);
+    } else if payload.len() <= u16::MAX as usize {
+        frame.push(126);
+        frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    } else {
+        frame.push(127);
+        frame.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+    }
+    frame.extend_from_slice(payload);
+    writer.write_all(&frame).await?;
+    writer.flush().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(host: Option<&str>, authorization: Option<&str>, uri: &str) -> Request<Body> {
+        let mut builder = Request::builder().uri(uri);
+        if let Some(host) = host {
+            builder = builder.header(header::HOST, host);
+        }
+        if let Some(authorization) = authorization {
+            builder = builder.header(header::AUTHORIZATION, authorization);
+        }
+        builder.body(Body::empty()).unwrap()
+    }
+
+    fn with_host(host: &str) -> Request<Body> {
+        request(Some(host), None, "/")
+    }
+
+    /// A masked client frame with its length in the 7-bit field, or 16 or 64 bits after it
+    fn client_frame(opcode: u8, payload: &[u8], length_bytes: usize) -> Vec<u8> {
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        let mut frame = vec![0x80 | opcode];
+        match length_bytes {
+            0 => frame.push(0x80 | payload.len() as u8),
+            2 => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+            },
+            _ => {
+                frame.push(0x80 | 127);
+                frame.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+            },
+        }
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]));
+        frame
+    }
+
+    #[test]
+    fn accepts_only_loopback_hosts() {
+        for host in ["localhost", "localhost:6800", "127.0.0.1:6800", "[::1]:6800", "[::1]"] {
+            assert!(is_local_host(&with_host(host)), "{}", host);
+        }
+        // A page on a rebinding domain resolves to 127.0.0.1 but keeps its own Host
+        for host in ["evil.example.com", "evil.example.com:6800", "localhost.evil.example.com",
+                     "127.0.0.1.nip.io:6800", "[::2]:6800", ""] {
+            assert!(!is_local_host(&with_host(host)), "{}", host);
+        }
+        assert!(!is_local_host(&request(None, None, "/")));
+    }
+
+    #[test]
+    fn checks_the_token_from_the_header_or_query() {
+        let token = "s3cret-token";
+        assert!(is_authorized(&request(None, Some("Bearer s3cret-token"), "/"), token));
+        assert!(is_authorized(&request(None, None, "/events?token=s3cret-token"), token));
+        assert!(is_authorized(&request(None, None, "/events?x=1&token=s3cret-token"), token));
+
+        assert!(!is_authorized(&request(None, None, "/"), token));
+        assert!(!is_authorized(&request(None, Some("Bearer wrong-tokenx"), "/"), token));
+        assert!(!is_authorized(&request(None, Some("Bearer s3cret"), "/"), token));
+        assert!(!is_authorized(&request(None, Some("Basic s3cret-token"), "/"), token));
+        assert!(!is_authorized(&request(None, None, "/events?token=s3cret"), token));
+        assert!(!is_authorized(&request(None, None, "/events?xtoken=s3cret-token"), token));
+    }
+
+    #[test]
+    fn tokens_match_only_in_full() {
+        assert!(tokens_match("abcdef", "abcdef"));
+        assert!(!tokens_match("abcdeg", "abcdef"));
+        assert!(!tokens_match("abc", "abcdef"));
+        assert!(!tokens_match("abcdefg", "abcdef"));
+        assert!(!tokens_match("", "abcdef"));
+    }
+
+    #[test]
+    fn reads_ids_as_strings_or_numbers() {
+        let args = json!({ "string": "42", "number": 42, "null": null, "negative": -1,
+                           "float": 1.5, "text": "abc", "bool": true });
+        assert_eq!(u64_arg(&args, "string").unwrap(), Some(42));
+        assert_eq!(u64_arg(&args, "number").unwrap(), Some(42));
+        assert_eq!(u64_arg(&args, "null").unwrap(), None);
+        assert_eq!(u64_arg(&args, "missing").unwrap(), None);
+        // Larger than a JavaScript number can hold exactly
+        assert_eq!(u64_arg(&json!({ "id": "18446744073709551615" }), "id").unwrap(), Some(u64::MAX));
+        for name in ["negative", "float", "text", "bool"] {
+            assert!(u64_arg(&args, name).is_err(), "{}", name);
+        }
+        assert!(download_id_arg(&json!({})).is_err());
+    }
+
+    #[tokio::test]
+    async fn reads_masked_frames_of_every_length_encoding() {
+        for (length, length_bytes) in [(5, 0), (125, 0), (126, 2), (127, 2), (300, 2), (126, 8), (127, 8)] {
+            let payload: Vec<u8> = (0..length).map(|i| i as u8).collect();
+            let frame = client_frame(OPCODE_TEXT, &payload, length_bytes);
+            let (opcode, read) = read_frame(&mut frame.as_slice()).await.unwrap();
+            assert_eq!(opcode, OPCODE_TEXT);
+            assert_eq!(read, payload, "{} bytes", length);
+        }
+    }
+
+    #[tokio::test]
+    async fn refuses_frames_over_the_limit() {
+        let mut frame = vec![0x80 | OPCODE_TEXT, 0x80 | 127];
+        frame.extend_from_slice(&(MAX_FRAME_BYTES + 1).to_be_bytes());
+        let error = read_frame(&mut frame.as_slice()).await.unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn writes_frames_with_the_shortest_length_encoding() {
+        for (length, header_len) in [(125, 2), (126, 4), (127, 4), (65535, 4), (65536, 10)] {
+            let payload = vec![b'x'; length];
+            let mut frame = Vec::new();
+            write_frame(&mut frame, OPCODE_TEXT, &payload).await.unwrap();
+            assert_eq!(frame[0], 0x80 | OPCODE_TEXT);
+            assert_eq!(frame.len(), header_len + length, "{} bytes", length);
+            assert_eq!(&frame[header_len..], payload.as_slice());
+            match header_len {
+                2 => assert_eq!(frame[1] as usize, length),
+                4 => {
+                    assert_eq!(frame[1], 126);
+                    assert_eq!(u16::from_be_bytes([frame[2], frame[3]]) as usize, length);
+                },
+                _ => {
+                    assert_eq!(frame[1], 127);
+                    assert_eq!(u64::from_be_bytes(frame[2..10].try_into().unwrap()) as usize, length);
+                },
+            }
+        }
+
+        // Our own frames read back unmasked
+        let mut frame = Vec::new();
+        write_frame(&mut frame, OPCODE_PONG, &[7; 126]).await.unwrap();
+        let (opcode, payload) = read_frame(&mut frame.as_slice()).await.unwrap();
+        assert_eq!(opcode, OPCODE_PONG);
+        assert_eq!(payload, vec![7; 126]);
+    }
+}
@@ -1,9 +1,11 @@
-use rusqlite::{params, Connection, Result};
+use rusqlite::{params, Connection, OptionalExtension, Result, Row, ToSql};
 use std::path::PathBuf;
 use serde::{Deserialize, Serialize};
 use serde_with::{serde_as, DisplayFromStr};
 use chrono::{DateTime, Utc};
 use specta::Type;
+use crate::manifest::{DownloadManifest, SegmentRange};
+use crate::status::DownloadStatus;
 
 // Define our Download struct that will represent a row in the database
 #[serde_as]
@@ -18,13 +20,24 @@ pub struct Download {
     pub total_size: u64,            // Total file size in bytes
     #[serde_as(as = "DisplayFromStr")]
     pub downloaded_bytes: u64,      // Currently downloaded bytes
-    pub status: String,             // Status: "in_progress", "paused", "completed", "error"
-    pub error_message: Option<String>, // Error message if status is "error"
+    pub status: DownloadStatus,     // Where the download is in its lifecycle
+    pub error_message: Option<String>, // Why the download failed or stopped
     pub parts: u64,                 // Number of parallel download parts
     pub created_at: DateTime<Utc>,  // When the download was started
     pub updated_at: DateTime<Utc>,  // Last update time
     pub completed_at: Option<DateTime<Utc>>, // When the download completed
     pub save_path: Option<String>,  // Where the file is saved after completion
+    pub priority: i32,              // Higher priority queued downloads start first
+    pub queue_position: u32,        // Order among queued downloads with the same priority
+    pub etag: Option<String>,       // ETag of the version being downloaded
+    pub last_modified: Option<String>, // Last-Modified of the version being downloaded
+    pub expected_checksum: Option<String>, // Digest to verify against, as "algorithm:hex"
+    pub checksum_url: Option<String>,   // URL of a checksum file listing the expected digest
+    pub actual_checksum: Option<String>, // Digest computed after downloading, as "algorithm:hex"
+    pub directory: Option<String>,  // Directory to save into, None for the user's downloads folder
+    pub conflict_policy: Option<String>, // What to do when the file exists, None for the global setting
+    pub workspace: Option<String>,  // Directory holding the part files, set when the download first starts
+    pub error_code: Option<String>, // Kind of error in error_message, see SpeedyError::code
 }
 
 impl Download {
@@ -38,17 +51,164 @@ impl Download {
             filename,
             total_size,
             downloaded_bytes: 0,
-            status: "in_progress".to_string(),
+            status: DownloadStatus::Queued,
             error_message: None,
             parts,
             created_at: now,
             updated_at: now,
             completed_at: None,
             save_path: None,
+            priority: 0,
+            queue_position: 0,
+            etag: None,
+            last_modified: None,
+            expected_checksum: None,
+            checksum_url: None,
+            actual_checksum: None,
+            directory: None,
+            conflict_policy: None,
+            workspace: None,
+            error_code: None,
         }
     }
 }
 
+// Columns selected for every Download query, in the order read by `download_from_row`
+const DOWNLOAD_COLUMNS: &str = "id, download_id, url, filename, total_size, downloaded_bytes, 
+    status, error_message, parts, created_at, updated_at, 
+    completed_at, save_path, priority, queue_position, etag, last_modified,
+    expected_checksum, checksum_url, actual_checksum, directory, conflict_policy, workspace, error_code";
+
+// Parse an RFC 3339 timestamp stored in the database, falling back to now
+fn parse_timestamp(value: &str) -> DateTime<Utc> {
+    chrono::DateTime::parse_from_rfc3339(value)
+        .map(|dt| dt.with_timezone(&Utc))
+        .unwrap_or_else(|_| Utc::now())
+}
+
+// Build a Download from a row selected with DOWNLOAD_COLUMNS
+fn download_from_row(row: &Row) -> Result<Download> {
+    Ok(Download {
+        id: Some(row.get(0)?),
+        download_id: row.get(1)?,
+        url: row.get(2)?,
+        filename: row.get(3)?,
+        total_size: row.get(4)?,
+        downloaded_bytes: row.get(5)?,
+        status: row.get(6)?,
+        error_message: row.get(7)?,
+        parts: row.get(8)?,
+        created_at: parse_timestamp(&row.get::<_, String>(9)?),
+        updated_at: parse_timestamp(&row.get::<_, String>(10)?),
+        completed_at: row.get::<_, Option<String>>(11)?
+            .map(|dt_str| parse_timestamp(&dt_str)),
+        save_path: row.get(12)?,
+        priority: row.get(13)?,
+        queue_position: row.get(14)?,
+        etag: row.get(15)?,
+        last_modified: row.get(16)?,
+        expected_checksum: row.get(17)?,
+        checksum_url: row.get(18)?,
+        actual_checksum: row.get(19)?,
+        directory: row.get(20)?,
+        conflict_policy: row.get(21)?,
+        workspace: row.get(22)?,
+        error_code: row.get(23)?,
+    })
+}
+
+// A schema change, applied inside a transaction
+type Migration = fn(&Connection) -> Result<()>;
+
+// Schema changes in the order they were made. `PRAGMA user_version` holds how many
+// a database has had, so new changes go at the end and existing ones never change.
+const MIGRATIONS: &[Migration] = &[
+    create_downloads_table,
+    apply_unversioned_changes,
+];
+
+// 1: the downloads table as the first version of Speedy created it
+fn create_downloads_table(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS downloads (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            download_id INTEGER NOT NULL,
+            url TEXT NOT NULL,
+            filename TEXT NOT NULL,
+            total_size INTEGER NOT NULL,
+            downloaded_bytes INTEGER NOT NULL DEFAULT 0,
+            status TEXT NOT NULL,
+            error_message TEXT,
+            parts INTEGER NOT NULL,
+            created_at TEXT NOT NULL,
+            updated_at TEXT NOT NULL,
+            completed_at TEXT,
+            save_path TEXT
+        );
+        CREATE INDEX IF NOT EXISTS idx_download_id ON downloads(download_id);
+        CREATE INDEX IF NOT EXISTS idx_status ON downloads(status);",
+    )
+}
+
+// 2: everything added before the schema was versioned. Databases from those
+// versions may have any of it already, so each part is skipped when present.
+fn apply_unversioned_changes(conn: &Connection) -> Result<()> {
+    add_column_if_missing(conn, "downloads", "priority", "INTEGER NOT NULL DEFAULT 0")?;
+    add_column_if_missing(conn, "downloads", "queue_position", "INTEGER NOT NULL DEFAULT 0")?;
+    add_column_if_missing(conn, "downloads", "etag", "TEXT")?;
+    add_column_if_missing(conn, "downloads", "last_modified", "TEXT")?;
+    add_column_if_missing(conn, "downloads", "expected_checksum", "TEXT")?;
+    add_column_if_missing(conn, "downloads", "checksum_url", "TEXT")?;
+    add_column_if_missing(conn, "downloads", "actual_checksum", "TEXT")?;
+    add_column_if_missing(conn, "downloads", "directory", "TEXT")?;
+    add_column_if_missing(conn, "downloads", "conflict_policy", "TEXT")?;
+    add_column_if_missing(conn, "downloads", "workspace", "TEXT")?;
+    add_column_if_missing(conn, "downloads", "error_code", "TEXT")?;
+
+    conn.execute_batch(
+        "-- Key/value store for application settings
+        CREATE TABLE IF NOT EXISTS settings (
+            key TEXT PRIMARY KEY,
+            value TEXT NOT NULL
+        );
+
+        -- Segment layout of unfinished downloads, used to resume them safely
+        CREATE TABLE IF NOT EXISTS download_manifests (
+            download_id INTEGER PRIMARY KEY,
+            url TEXT NOT NULL,
+            file_size INTEGER NOT NULL,
+            etag TEXT,
+            last_modified TEXT,
+            updated_at TEXT NOT NULL,
+            preallocated INTEGER NOT NULL DEFAULT 0
+        );
+        CREATE TABLE IF NOT EXISTS download_segments (
+            download_id INTEGER NOT NULL,
+            segment_index INTEGER NOT NULL,
+            range_start INTEGER NOT NULL,
+            range_end INTEGER NOT NULL,
+            bytes_written INTEGER NOT NULL DEFAULT 0,
+            PRIMARY KEY (download_id, segment_index)
+        );",
+    )?;
+    add_column_if_missing(conn, "download_manifests", "preallocated", "INTEGER NOT NULL DEFAULT 0")?;
+
+    // Older versions stored every running download as "in_progress"
+    conn.execute("UPDATE downloads SET status = 'downloading' WHERE status = 'in_progress'", [])?;
+    Ok(())
+}
+
+// Add a column to an existing table unless it's already there
+fn add_column_if_missing(conn: &Connection, table: &str, column: &str, definition: &str) -> Result<()> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let mut columns = stmt.query_map([], |row| row.get::<_, String>(1))?;
+    if columns.any(|name| name.map(|name| name == column).unwrap_or(false)) {
+        return Ok(());
+    }
+    conn.execute(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition), [])?;
+    Ok(())
+}
+
 // Define our database handler
 pub struct DownloadDb {
     conn: Connection,
@@ -70,45 +230,42 @@ impl DownloadDb {
             }
         };
 
-        println!("Using database at: {}", db_path.display());
-        
-        let conn = Connection::open(db_path)?;
-        
-        // Create the downloads table if it doesn't exist
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS downloads (
-                id INTEGER PRIMARY KEY AUTOINCREMENT,
-                download_id INTEGER NOT NULL,
-                url TEXT NOT NULL,
-                filename TEXT NOT NULL,
-                total_size INTEGER NOT NULL,
-                downloaded_bytes INTEGER NOT NULL DEFAULT 0,
-                status TEXT NOT NULL,
-                error_message TEXT,
-                parts INTEGER NOT NULL,
-                created_at TEXT NOT NULL,
-                updated_at TEXT NOT NULL,
-                completed_at TEXT,
-                save_path TEXT
-            )",
-            [],
-        )?;
+        eprintln!("Using database at: {}", db_path.display());
         
-        // Create indices for faster lookup
-        conn.execute("CREATE INDEX IF NOT EXISTS idx_download_id ON downloads(download_id)", [])?;
-        conn.execute("CREATE INDEX IF NOT EXISTS idx_status ON downloads(status)", [])?;
+        let mut conn = Connection::open(db_path)?;
+        Self::migrate(&mut conn, MIGRATIONS)?;
 
         Ok(Self { conn })
     }
     
+    // Apply the migrations the database hasn't had yet, each in its own transaction
+    // so a failing one leaves the database at the previous version
+    fn migrate(conn: &mut Connection, migrations: &[Migration]) -> Result<()> {
+        let version: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        if version > migrations.len() {
+            eprintln!("Database schema version {} is newer than this version of Speedy knows ({})", version, migrations.len());
+            return Ok(());
+        }
+
+        for (index, migration) in migrations.iter().enumerate().skip(version) {
+            let tx = conn.transaction()?;
+            migration(&tx)?;
+            tx.pragma_update(None, "user_version", (index + 1) as i64)?;
+            tx.commit()?;
+            eprintln!("Migrated database to schema version {}", index + 1);
+        }
+        Ok(())
+    }
+    
     // Insert a new download record
     pub fn insert_download(&self, download: &Download) -> Result<i64> {
         self.conn.execute(
             "INSERT INTO downloads (
                 download_id, url, filename, total_size, downloaded_bytes, 
                 status, error_message, parts, created_at, updated_at, 
-                completed_at, save_path
-            ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+                completed_at, save_path, priority, queue_position, etag, last_modified,
+                expected_checksum, checksum_url, actual_checksum, directory, conflict_policy, workspace, error_code
+            ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19, ?20, ?21, ?22, ?23)",
             params![
                 download.download_id,
                 download.url,
@@ -122,6 +279,17 @@ impl DownloadDb {
                 download.updated_at.to_rfc3339(),
                 download.completed_at.map(|dt| dt.to_rfc3339()),
                 download.save_path,
+                download.priority,
+                download.queue_position,
+                download.etag,
+                download.last_modified,
+                download.expected_checksum,
+                download.checksum_url,
+                download.actual_checksum,
+                download.directory,
+                download.conflict_policy,
+                download.workspace,
+                download.error_code,
             ],
         )?;
         
@@ -146,8 +314,19 @@ impl DownloadDb {
                 parts = ?8,
                 updated_at = ?9,
                 completed_at = ?10,
-                save_path = ?11
-            WHERE id = ?12",
+                save_path = ?11,
+                priority = ?12,
+                queue_position = ?13,
+                etag = ?14,
+                last_modified = ?15,
+                expected_checksum = ?16,
+                checksum_url = ?17,
+                actual_checksum = ?18,
+                directory = ?19,
+                conflict_policy = ?20,
+                workspace = ?21,
+                error_code = ?22
+            WHERE id = ?23",
             params![
                 download.download_id,
                 download.url,
@@ -160,6 +339,17 @@ impl DownloadDb {
                 Utc::now().to_rfc3339(),
                 download.completed_at.map(|dt| dt.to_rfc3339()),
                 download.save_path,
+                download.priority,
+                download.queue_position,
+                download.etag,
+                download.last_modified,
+                download.expected_checksum,
+                download.checksum_url,
+                download.actual_checksum,
+                download.directory,
+                download.conflict_policy,
+                download.workspace,
+                download.error_code,
                 download.id,
             ],
         )?;
@@ -184,38 +374,21 @@ impl DownloadDb {
         Ok(())
     }
     
-    // Mark a download as complete
-    pub fn mark_complete(&self, download_id: u64, save_path: &str) -> Result<()> {
+    // Store the checksum computed for a downloaded file
+    pub fn update_actual_checksum(&self, download_id: u64, checksum: &str) -> Result<()> {
         self.conn.execute(
-            "UPDATE downloads SET
-                status = 'completed',
-                completed_at = ?1,
-                updated_at = ?1,
-                save_path = ?2
-            WHERE download_id = ?3",
-            params![
-                Utc::now().to_rfc3339(),
-                save_path,
-                download_id,
-            ],
+            "UPDATE downloads SET actual_checksum = ?1, updated_at = ?2 WHERE download_id = ?3",
+            params![checksum, Utc::now().to_rfc3339(), download_id],
         )?;
         
         Ok(())
     }
     
-    // Mark a download as errored
-    pub fn mark_error(&self, download_id: u64, error_message: &str) -> Result<()> {
+    // Store the directory holding a download's part files
+    pub fn update_workspace(&self, download_id: u64, workspace: &str) -> Result<()> {
         self.conn.execute(
-            "UPDATE downloads SET
-                status = 'error',
-                error_message = ?1,
-                updated_at = ?2
-            WHERE download_id = ?3",
-            params![
-                error_message,
-                Utc::now().to_rfc3339(),
-                download_id,
-            ],
+            "UPDATE downloads SET workspace = ?1, updated_at = ?2 WHERE download_id = ?3",
+            params![workspace, Utc::now().to_rfc3339(), download_id],
         )?;
         
         Ok(())
@@ -223,38 +396,12 @@ impl DownloadDb {
     
     // Get a download by ID
     pub fn get_download(&self, download_id: u64) -> Result<Option<Download>> {
-        let mut stmt = self.conn.prepare(
-            "SELECT id, download_id, url, filename, total_size, downloaded_bytes, 
-                    status, error_message, parts, created_at, updated_at, 
-                    completed_at, save_path
-             FROM downloads
-             WHERE download_id = ?1"
-        )?;
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {} FROM downloads WHERE download_id = ?1",
+            DOWNLOAD_COLUMNS
+        ))?;
         
-        let download = stmt.query_row(params![download_id], |row| {
-            Ok(Download {
-                id: Some(row.get(0)?),
-                download_id: row.get(1)?,
-                url: row.get(2)?,
-                filename: row.get(3)?,
-                total_size: row.get(4)?,
-                downloaded_bytes: row.get(5)?,
-                status: row.get(6)?,
-                error_message: row.get(7)?,
-                parts: row.get(8)?,
-                created_at: chrono::DateTime::parse_from_rfc3339(&row.get::<_, String>(9)?)
-                    .map(|dt| dt.with_timezone(&Utc))
-                    .unwrap_or_else(|_| Utc::now()),
-                updated_at: chrono::DateTime::parse_from_rfc3339(&row.get::<_, String>(10)?)
-                    .map(|dt| dt.with_timezone(&Utc))
-                    .unwrap_or_else(|_| Utc::now()),
-                completed_at: row.get::<_, Option<String>>(11)?
-                    .map(|dt_str| chrono::DateTime::parse_from_rfc3339(&dt_str)
-                        .map(|dt| dt.with_timezone(&Utc))
-                        .unwrap_or_else(|_| Utc::now())),
-                save_path: row.get(12)?,
-            })
-        });
+        let download = stmt.query_row(params![download_id], download_from_row);
         
         match download {
             Ok(download) => Ok(Some(download)),
@@ -265,38 +412,12 @@ impl DownloadDb {
     
     // List all downloads
     pub fn list_downloads(&self) -> Result<Vec<Download>> {
-        let mut stmt = self.conn.prepare(
-            "SELECT id, download_id, url, filename, total_size, downloaded_bytes, 
-                    status, error_message, parts, created_at, updated_at, 
-                    completed_at, save_path
-             FROM downloads
-             ORDER BY created_at DESC"
-        )?;
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {} FROM downloads ORDER BY created_at DESC",
+            DOWNLOAD_COLUMNS
+        ))?;
         
-        let download_iter = stmt.query_map([], |row| {
-            Ok(Download {
-                id: Some(row.get(0)?),
-                download_id: row.get(1)?,
-                url: row.get(2)?,
-                filename: row.get(3)?,
-                total_size: row.get(4)?,
-                downloaded_bytes: row.get(5)?,
-                status: row.get(6)?,
-                error_message: row.get(7)?,
-                parts: row.get(8)?,
-                created_at: chrono::DateTime::parse_from_rfc3339(&row.get::<_, String>(9)?)
-                    .map(|dt| dt.with_timezone(&Utc))
-                    .unwrap_or_else(|_| Utc::now()),
-                updated_at: chrono::DateTime::parse_from_rfc3339(&row.get::<_, String>(10)?)
-                    .map(|dt| dt.with_timezone(&Utc))
-                    .unwrap_or_else(|_| Utc::now()),
-                completed_at: row.get::<_, Option<String>>(11)?
-                    .map(|dt_str| chrono::DateTime::parse_from_rfc3339(&dt_str)
-                        .map(|dt| dt.with_timezone(&Utc))
-                        .unwrap_or_else(|_| Utc::now())),
-                save_path: row.get(12)?,
-            })
-        })?;
+        let download_iter = stmt.query_map([], download_from_row)?;
         
         let mut downloads = Vec::new();
         for download in download_iter {
@@ -307,40 +428,13 @@ impl DownloadDb {
     }
     
     // Get downloads with a specific status
-    pub fn get_downloads_by_status(&self, status: &str) -> Result<Vec<Download>> {
-        let mut stmt = self.conn.prepare(
-            "SELECT id, download_id, url, filename, total_size, downloaded_bytes, 
-                    status, error_message, parts, created_at, updated_at, 
-                    completed_at, save_path
-             FROM downloads
-             WHERE status = ?1
-             ORDER BY created_at DESC"
-        )?;
+    pub fn get_downloads_by_status(&self, status: DownloadStatus) -> Result<Vec<Download>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {} FROM downloads WHERE status = ?1 ORDER BY created_at DESC",
+            DOWNLOAD_COLUMNS
+        ))?;
         
-        let download_iter = stmt.query_map(params![status], |row| {
-            Ok(Download {
-                id: Some(row.get(0)?),
-                download_id: row.get(1)?,
-                url: row.get(2)?,
-                filename: row.get(3)?,
-                total_size: row.get(4)?,
-                downloaded_bytes: row.get(5)?,
-                status: row.get(6)?,
-                error_message: row.get(7)?,
-                parts: row.get(8)?,
-                created_at: chrono::DateTime::parse_from_rfc3339(&row.get::<_, String>(9)?)
-                    .map(|dt| dt.with_timezone(&Utc))
-                    .unwrap_or_else(|_| Utc::now()),
-                updated_at: chrono::DateTime::parse_from_rfc3339(&row.get::<_, String>(10)?)
-                    .map(|dt| dt.with_timezone(&Utc))
-                    .unwrap_or_else(|_| Utc::now()),
-                completed_at: row.get::<_, Option<String>>(11)?
-                    .map(|dt_str| chrono::DateTime::parse_from_rfc3339(&dt_str)
-                        .map(|dt| dt.with_timezone(&Utc))
-                        .unwrap_or_else(|_| Utc::now())),
-                save_path: row.get(12)?,
-            })
-        })?;
+        let download_iter = stmt.query_map(params![status], download_from_row)?;
         
         let mut downloads = Vec::new();
         for download in download_iter {
@@ -361,27 +455,228 @@ impl DownloadDb {
             return Err(rusqlite::Error::QueryReturnedNoRows.into());
         }
         
+        self.delete_manifest(download_id)?;
+        
         Ok(())
     }
     
     // Update download status
-    pub fn update_status(&self, download_id: u64, status: &str) -> Result<()> {
+    pub fn update_status(&self, download_id: u64, status: DownloadStatus) -> Result<()> {
+        self.set_status_with(download_id, status, &[])
+    }
+    
+    // Move a download to `status`, setting `columns` (name and value) along with it
+    pub fn set_status_with(&self, download_id: u64, status: DownloadStatus, columns: &[(&str, &dyn ToSql)]) -> Result<()> {
+        let mut assignments = String::from("status = ?1, updated_at = ?2");
+        for (i, (column, _)) in columns.iter().enumerate() {
+            assignments.push_str(&format!(", {} = ?{}", column, i + 4));
+        }
+        let now = Utc::now().to_rfc3339();
+        let mut values: Vec<&dyn ToSql> = vec![&status, &now, &download_id];
+        values.extend(columns.iter().map(|(_, value)| *value));
+        
+        let affected_rows = self.conn.execute(
+            &format!("UPDATE downloads SET {} WHERE download_id = ?3", assignments),
+            values.as_slice(),
+        )?;
+        
+        if affected_rows != 1 {
+            return Err(rusqlite::Error::QueryReturnedNoRows);
+        }
+        
+        Ok(())
+    }
+    
+    // Get queued downloads in the order they should start
+    pub fn get_queued_downloads(&self) -> Result<Vec<Download>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {} FROM downloads WHERE status = 'queued'
+             ORDER BY priority DESC, queue_position ASC, created_at ASC",
+            DOWNLOAD_COLUMNS
+        ))?;
+        
+        let download_iter = stmt.query_map([], download_from_row)?;
+        
+        let mut downloads = Vec::new();
+        for download in download_iter {
+            downloads.push(download?);
+        }
+        
+        Ok(downloads)
+    }
+    
+    // Get the position to give a download appended to the end of the queue
+    pub fn next_queue_position(&self) -> Result<u32> {
+        self.conn.query_row(
+            "SELECT COALESCE(MAX(queue_position), 0) + 1 FROM downloads WHERE status = 'queued'",
+            [],
+            |row| row.get(0),
+        )
+    }
+    
+    // Record the ETag and Last-Modified of the version being downloaded
+    pub fn update_validators(&self, download_id: u64, etag: Option<&str>, last_modified: Option<&str>) -> Result<()> {
+        self.conn.execute(
+            "UPDATE downloads SET
+                etag = ?1,
+                last_modified = ?2,
+                updated_at = ?3
+            WHERE download_id = ?4",
+            params![
+                etag,
+                last_modified,
+                Utc::now().to_rfc3339(),
+                download_id,
+            ],
+        )?;
+        
+        Ok(())
+    }
+    
+    // Update the priority of a download
+    pub fn update_priority(&self, download_id: u64, priority: i32) -> Result<()> {
         let affected_rows = self.conn.execute(
             "UPDATE downloads SET
-                status = ?1,
+                priority = ?1,
                 updated_at = ?2
             WHERE download_id = ?3",
             params![
-                status,
+                priority,
                 Utc::now().to_rfc3339(),
                 download_id,
             ],
         )?;
         
         if affected_rows != 1 {
-            return Err(rusqlite::Error::QueryReturnedNoRows.into());
+            return Err(rusqlite::Error::QueryReturnedNoRows);
+        }
+        
+        Ok(())
+    }
+    
+    // Renumber queue positions to follow the given order of download IDs
+    pub fn reorder_queue(&mut self, download_ids: &[u64]) -> Result<()> {
+        let tx = self.conn.transaction()?;
+        for (position, download_id) in download_ids.iter().enumerate() {
+            tx.execute(
+                "UPDATE downloads SET queue_position = ?1 WHERE download_id = ?2",
+                params![position as u32 + 1, download_id],
+            )?;
+        }
+        tx.commit()
+    }
+    
+    // Store the segment layout of a download, replacing any previous one
+    pub fn save_manifest(&mut self, download_id: u64, manifest: &DownloadManifest) -> Result<()> {
+        let tx = self.conn.transaction()?;
+        tx.execute(
+            "INSERT OR REPLACE INTO download_manifests (
+                download_id, url, file_size, etag, last_modified, updated_at, preallocated
+            ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                download_id,
+                manifest.url,
+                manifest.file_size,
+                manifest.etag,
+                manifest.last_modified,
+                Utc::now().to_rfc3339(),
+                manifest.preallocated,
+            ],
+        )?;
+        tx.execute("DELETE FROM download_segments WHERE download_id = ?1", params![download_id])?;
+        for segment in &manifest.segments {
+            tx.execute(
+                "INSERT INTO download_segments (
+                    download_id, segment_index, range_start, range_end, bytes_written
+                ) VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    download_id,
+                    segment.index,
+                    segment.start,
+                    segment.end,
+                    segment.bytes_written,
+                ],
+            )?;
+        }
+        tx.commit()
+    }
+    
+    // Get the segment layout of a download, if one was saved
+    pub fn get_manifest(&self, download_id: u64) -> Result<Option<DownloadManifest>> {
+        let manifest = self.conn.query_row(
+            "SELECT url, file_size, etag, last_modified, preallocated FROM download_manifests WHERE download_id = ?1",
+            params![download_id],
+            |row| Ok(DownloadManifest {
+                url: row.get(0)?,
+                file_size: row.get(1)?,
+                etag: row.get(2)?,
+                last_modified: row.get(3)?,
+                segments: Vec::new(),
+                preallocated: row.get(4)?,
+            }),
+        ).optional()?;
+        
+        let mut manifest = match manifest {
+            Some(manifest) => manifest,
+            None => return Ok(None),
+        };
+        
+        let mut stmt = self.conn.prepare(
+            "SELECT segment_index, range_start, range_end, bytes_written
+             FROM download_segments
+             WHERE download_id = ?1
+             ORDER BY segment_index ASC"
+        )?;
+        let segment_iter = stmt.query_map(params![download_id], |row| {
+            Ok(SegmentRange {
+                index: row.get(0)?,
+                start: row.get(1)?,
+                end: row.get(2)?,
+                bytes_written: row.get(3)?,
+            })
+        })?;
+        for segment in segment_iter {
+            manifest.segments.push(segment?);
         }
         
+        Ok(Some(manifest))
+    }
+    
+    // Record how many bytes of a segment have been written
+    pub fn update_segment_progress(&self, download_id: u64, segment_index: u64, bytes_written: u64) -> Result<()> {
+        self.conn.execute(
+            "UPDATE download_segments SET bytes_written = ?1
+             WHERE download_id = ?2 AND segment_index = ?3",
+            params![bytes_written, download_id, segment_index],
+        )?;
+        
+        Ok(())
+    }
+    
+    // Remove the segment layout of a download
+    pub fn delete_manifest(&self, download_id: u64) -> Result<()> {
+        self.conn.execute("DELETE FROM download_segments WHERE download_id = ?1", params![download_id])?;
+        self.conn.execute("DELETE FROM download_manifests WHERE download_id = ?1", params![download_id])?;
+        Ok(())
+    }
+    
+    // Get an application setting
+    pub fn get_setting(&self, key: &str) -> Result<Option<String>> {
+        self.conn.query_row(
+            "SELECT value FROM settings WHERE key = ?1",
+            params![key],
+            |row| row.get(0),
+        ).optional()
+    }
+    
+    // Store an application setting, replacing any previous value
+    pub fn set_setting(&self, key: &str, value: &str) -> Result<()> {
+        self.conn.execute(
+            "INSERT INTO settings (key, value) VALUES (?1, ?2)
+             ON CONFLICT(key) DO UPDATE SET value = excluded.value",
+            params![key, value],
+        )?;
+        
         Ok(())
     }
 }
@@ -389,4 +684,159 @@ impl DownloadDb {
 // Create a singleton database connection
 pub fn get_db() -> Result<DownloadDb> {
     DownloadDb::new(None)
-} 
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A database as the first version of Speedy left it, with a few downloads
+    const BASELINE: &str = include_str!("../tests/fixtures/baseline.sql");
+
+    fn baseline_db() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(BASELINE).unwrap();
+        conn
+    }
+
+    fn user_version(conn: &Connection) -> usize {
+        conn.query_row("PRAGMA user_version", [], |row| row.get(0)).unwrap()
+    }
+
+    fn columns(conn: &Connection, table: &str) -> Vec<String> {
+        let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table)).unwrap();
+        let columns = stmt.query_map([], |row| row.get(1)).unwrap();
+        columns.map(|name| name.unwrap()).collect()
+    }
+
+    #[test]
+    fn creates_current_schema_in_empty_database() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        DownloadDb::migrate(&mut conn, MIGRATIONS).unwrap();
+        assert_eq!(user_version(&conn), MIGRATIONS.len());
+
+        let db = DownloadDb { conn };
+        let mut download = Download::new(42, "https://example.com/a.bin".to_string(), "a.bin".to_string(), 100, 8);
+        download.expected_checksum = Some("sha256:abc".to_string());
+        download.directory = Some("/tmp".to_string());
+        download.error_code = Some("network".to_string());
+        db.insert_download(&download).unwrap();
+
+        let stored = db.get_download(42).unwrap().unwrap();
+        assert_eq!(stored.expected_checksum, download.expected_checksum);
+        assert_eq!(stored.directory, download.directory);
+        assert_eq!(stored.error_code, download.error_code);
+    }
+
+    #[test]
+    fn upgrades_baseline_database() {
+        let mut conn = baseline_db();
+        assert_eq!(user_version(&conn), 0);
+        DownloadDb::migrate(&mut conn, MIGRATIONS).unwrap();
+        assert_eq!(user_version(&conn), MIGRATIONS.len());
+
+        let mut db = DownloadDb { conn };
+        assert_eq!(db.list_downloads().unwrap().len(), 3);
+
+        let running = db.get_download(1700000001).unwrap().unwrap();
+        assert_eq!(running.status, DownloadStatus::Downloading);
+        assert_eq!(running.downloaded_bytes, 1024);
+        assert_eq!(running.parts, 8);
+        assert_eq!(running.priority, 0);
+        assert_eq!(running.queue_position, 0);
+        assert_eq!(running.etag, None);
+        assert_eq!(running.workspace, None);
+
+        let done = db.get_download(1700000002).unwrap().unwrap();
+        assert_eq!(done.status, DownloadStatus::Completed);
+        assert_eq!(done.save_path.as_deref(), Some("/home/user/Downloads/done.zip"));
+        assert!(done.completed_at.is_some());
+
+        let broken = db.get_download(1700000003).unwrap().unwrap();
+        assert_eq!(broken.status, DownloadStatus::Error);
+        assert_eq!(broken.error_message.as_deref(), Some("Connection reset by peer"));
+        assert_eq!(broken.error_code, None);
+
+        // Upgraded rows take the columns added since
+        let mut running = running;
+        running.priority = 2;
+        running.etag = Some("\"v1\"".to_string());
+        running.workspace = Some("/tmp/speedy-1700000001".to_string());
+        db.update_download(&running).unwrap();
+        let stored = db.get_download(1700000001).unwrap().unwrap();
+        assert_eq!(stored.priority, 2);
+        assert_eq!(stored.etag, running.etag);
+        assert_eq!(stored.workspace, running.workspace);
+
+        // And the tables added since are there
+        db.set_setting("max_concurrent_downloads", "2").unwrap();
+        assert_eq!(db.get_setting("max_concurrent_downloads").unwrap().as_deref(), Some("2"));
+        let manifest = DownloadManifest::plan(&running.url, 4096, 4, None, None, false);
+        db.save_manifest(1700000001, &manifest).unwrap();
+        assert_eq!(db.get_manifest(1700000001).unwrap(), Some(manifest));
+    }
+
+    #[test]
+    fn upgrades_unversioned_database_with_some_later_changes() {
+        // Versions between the first one and versioned migrations added columns and tables as needed
+        let mut conn = baseline_db();
+        conn.execute_batch(
+            "ALTER TABLE downloads ADD COLUMN priority INTEGER NOT NULL DEFAULT 0;
+            ALTER TABLE downloads ADD COLUMN queue_position INTEGER NOT NULL DEFAULT 0;
+            UPDATE downloads SET priority = 3 WHERE download_id = 1700000002;
+            CREATE TABLE settings (key TEXT PRIMARY KEY, value TEXT NOT NULL);
+            INSERT INTO settings (key, value) VALUES ('max_concurrent_downloads', '5');",
+        ).unwrap();
+
+        DownloadDb::migrate(&mut conn, MIGRATIONS).unwrap();
+        assert_eq!(user_version(&conn), MIGRATIONS.len());
+
+        let db = DownloadDb { conn };
+        assert_eq!(db.get_download(1700000002).unwrap().unwrap().priority, 3);
+        assert_eq!(db.get_setting("max_concurrent_downloads").unwrap().as_deref(), Some("5"));
+        assert!(columns(&db.conn, "downloads").contains(&"error_code".to_string()));
+    }
+
+    #[test]
+    fn applied_migrations_are_not_run_again() {
+        let mut conn = baseline_db();
+        DownloadDb::migrate(&mut conn, MIGRATIONS).unwrap();
+
+        // The status rename would undo this if the migration ran again
+        conn.execute("UPDATE downloads SET status = 'in_progress' WHERE download_id = 1700000001", []).unwrap();
+        DownloadDb::migrate(&mut conn, MIGRATIONS).unwrap();
+
+        let status: String = conn.query_row(
+            "SELECT status FROM downloads WHERE download_id = 1700000001", [], |row| row.get(0),
+        ).unwrap();
+        assert_eq!(status, "in_progress");
+        assert_eq!(user_version(&conn), MIGRATIONS.len());
+    }
+
+    #[test]
+    fn failed_migration_is_rolled_back() {
+        fn broken(conn: &Connection) -> Result<()> {
+            conn.execute("ALTER TABLE downloads ADD COLUMN category TEXT", [])?;
+            conn.execute("UPDATE missing_table SET value = 1", [])?;
+            Ok(())
+        }
+
+        let mut conn = baseline_db();
+        DownloadDb::migrate(&mut conn, MIGRATIONS).unwrap();
+        let migrations = [MIGRATIONS, &[broken as Migration]].concat();
+        assert!(DownloadDb::migrate(&mut conn, &migrations).is_err());
+
+        assert_eq!(user_version(&conn), MIGRATIONS.len());
+        assert!(!columns(&conn, "downloads").contains(&"category".to_string()));
+    }
+
+    #[test]
+    fn leaves_database_from_newer_version_alone() {
+        let mut conn = baseline_db();
+        conn.pragma_update(None, "user_version", (MIGRATIONS.len() + 1) as i64).unwrap();
+        DownloadDb::migrate(&mut conn, MIGRATIONS).unwrap();
+
+        assert_eq!(user_version(&conn), MIGRATIONS.len() + 1);
+        assert!(!columns(&conn, "downloads").contains(&"priority".to_string()));
+    }
+}
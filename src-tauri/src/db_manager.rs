@@ -1,5 +1,11 @@
 use crate::db::{Download, DownloadDb};
-use rusqlite::Result;
+use crate::error::SpeedyError;
+use crate::manifest::DownloadManifest;
+use crate::status::{DownloadStatus, InvalidTransition};
+use rusqlite::{Result, ToSql};
+use std::error::Error;
+use std::fmt;
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 use tokio::sync::OnceCell;
 
@@ -7,9 +13,15 @@ static DB_INSTANCE: OnceCell<Arc<Mutex<DownloadDb>>> = OnceCell::const_new();
 
 /// Initialize the database and return a reference to it
 pub async fn init_db() -> Arc<Mutex<DownloadDb>> {
+    init_db_at(None).await
+}
+
+/// Initialize the database at `db_path`, or the default location when it's None.
+/// The path is ignored when the database is already initialized.
+pub async fn init_db_at(db_path: Option<PathBuf>) -> Arc<Mutex<DownloadDb>> {
     DB_INSTANCE
         .get_or_init(|| async {
-            match DownloadDb::new(None) {
+            match DownloadDb::new(db_path) {
                 Ok(db) => Arc::new(Mutex::new(db)),
                 Err(e) => {
                     eprintln!("Failed to initialize database: {}", e);
@@ -29,6 +41,61 @@ pub async fn get_db_instance() -> Arc<Mutex<DownloadDb>> {
     }
 }
 
+/// Why the status of a download couldn't be changed
+#[derive(Debug)]
+pub enum StatusError {
+    Sqlite(rusqlite::Error),
+    InvalidTransition(InvalidTransition),
+}
+
+impl fmt::Display for StatusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Sqlite(error) => write!(f, "{}", error),
+            Self::InvalidTransition(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl Error for StatusError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Sqlite(error) => Some(error),
+            Self::InvalidTransition(error) => Some(error),
+        }
+    }
+}
+
+impl From<rusqlite::Error> for StatusError {
+    fn from(error: rusqlite::Error) -> Self {
+        Self::Sqlite(error)
+    }
+}
+
+/// Reject moving a download to `next` when its current status doesn't lead there
+fn check_transition(db: &DownloadDb, download_id: u64, next: DownloadStatus) -> Result<(), StatusError> {
+    if let Some(download) = db.get_download(download_id)? {
+        if !download.status.can_transition_to(next) {
+            return Err(StatusError::InvalidTransition(InvalidTransition { download_id, from: download.status, to: next }));
+        }
+    }
+    Ok(())
+}
+
+/// Move a download to `status`, setting `columns` along with it, when its current status leads there
+fn set_status_with(db: &DownloadDb, download_id: u64, status: DownloadStatus, columns: &[(&str, &dyn ToSql)]) -> Result<(), StatusError> {
+    check_transition(db, download_id, status)?;
+    db.set_status_with(download_id, status, columns)?;
+    Ok(())
+}
+
+/// Move a download to a status that stopped it, recording the error that did
+async fn set_error_status(download_id: u64, status: DownloadStatus, error: &SpeedyError) -> Result<(), StatusError> {
+    let db = get_db_instance().await;
+    let db_guard = db.lock().unwrap();
+    set_status_with(&db_guard, download_id, status, &[("error_message", &error.message()), ("error_code", &error.code())])
+}
+
 /// Insert a new download into the database
 pub async fn insert_download(download: &Download) -> Result<i64> {
     let db = get_db_instance().await;
@@ -37,10 +104,12 @@ pub async fn insert_download(download: &Download) -> Result<i64> {
 }
 
 /// Update an existing download in the database
-pub async fn update_download(download: &Download) -> Result<()> {
+pub async fn update_download(download: &Download) -> Result<(), StatusError> {
     let db = get_db_instance().await;
     let db_guard = db.lock().unwrap();
-    db_guard.update_download(download)
+    check_transition(&db_guard, download.download_id, download.status)?;
+    db_guard.update_download(download)?;
+    Ok(())
 }
 
 /// Update download progress in the database
@@ -51,17 +120,50 @@ pub async fn update_progress(download_id: u64, bytes: u64) -> Result<()> {
 }
 
 /// Mark a download as complete in the database
-pub async fn mark_complete(download_id: u64, save_path: &str) -> Result<()> {
+pub async fn mark_complete(download_id: u64, save_path: &str) -> Result<(), StatusError> {
     let db = get_db_instance().await;
     let db_guard = db.lock().unwrap();
-    db_guard.mark_complete(download_id, save_path)
+    let completed_at = chrono::Utc::now().to_rfc3339();
+    set_status_with(&db_guard, download_id, DownloadStatus::Completed, &[("completed_at", &completed_at), ("save_path", &save_path)])
 }
 
 /// Mark a download as error in the database
-pub async fn mark_error(download_id: u64, error_message: &str) -> Result<()> {
+pub async fn mark_error(download_id: u64, error: &SpeedyError) -> Result<(), StatusError> {
+    set_error_status(download_id, DownloadStatus::Error, error).await
+}
+
+/// Mark a download whose output file exists, until the user chooses what to do
+pub async fn mark_conflict(download_id: u64, error: &SpeedyError) -> Result<(), StatusError> {
+    set_error_status(download_id, DownloadStatus::Conflict, error).await
+}
+
+/// Mark a download dropped because its output file exists
+pub async fn mark_skipped(download_id: u64, error: &SpeedyError) -> Result<(), StatusError> {
+    set_error_status(download_id, DownloadStatus::Skipped, error).await
+}
+
+/// Mark a download whose segments don't add up to the whole file
+pub async fn mark_incomplete(download_id: u64, error: &SpeedyError) -> Result<(), StatusError> {
+    set_error_status(download_id, DownloadStatus::Incomplete, error).await
+}
+
+/// Mark a download whose file doesn't match its expected checksum
+pub async fn mark_verification_failed(download_id: u64, error: &SpeedyError) -> Result<(), StatusError> {
+    set_error_status(download_id, DownloadStatus::VerificationFailed, error).await
+}
+
+/// Store the checksum computed for a downloaded file
+pub async fn update_actual_checksum(download_id: u64, checksum: &str) -> Result<()> {
+    let db = get_db_instance().await;
+    let db_guard = db.lock().unwrap();
+    db_guard.update_actual_checksum(download_id, checksum)
+}
+
+/// Store the directory holding a download's part files
+pub async fn update_workspace(download_id: u64, workspace: &str) -> Result<()> {
     let db = get_db_instance().await;
     let db_guard = db.lock().unwrap();
-    db_guard.mark_error(download_id, error_message)
+    db_guard.update_workspace(download_id, workspace)
 }
 
 /// Get a download by ID from the database
@@ -79,7 +181,7 @@ pub async fn list_downloads() -> Result<Vec<Download>> {
 }
 
 /// Get downloads with a specific status from the database
-pub async fn get_downloads_by_status(status: &str) -> Result<Vec<Download>> {
+pub async fn get_downloads_by_status(status: DownloadStatus) -> Result<Vec<Download>> {
     let db = get_db_instance().await;
     let db_guard = db.lock().unwrap();
     db_guard.get_downloads_by_status(status)
@@ -93,8 +195,85 @@ pub async fn delete_download(download_id: u64) -> Result<()> {
 }
 
 /// Update the status of a download in the database
-pub async fn update_status(download_id: u64, status: &str) -> Result<()> {
+pub async fn update_status(download_id: u64, status: DownloadStatus) -> Result<(), StatusError> {
+    let db = get_db_instance().await;
+    let db_guard = db.lock().unwrap();
+    set_status_with(&db_guard, download_id, status, &[])
+}
+
+/// Get queued downloads in the order they should start
+pub async fn get_queued_downloads() -> Result<Vec<Download>> {
     let db = get_db_instance().await;
     let db_guard = db.lock().unwrap();
-    db_guard.update_status(download_id, status)
-} 
\ No newline at end of file
+    db_guard.get_queued_downloads()
+}
+
+/// Get the position for a download appended to the end of the queue
+pub async fn next_queue_position() -> Result<u32> {
+    let db = get_db_instance().await;
+    let db_guard = db.lock().unwrap();
+    db_guard.next_queue_position()
+}
+
+/// Record the ETag and Last-Modified of the version being downloaded
+pub async fn update_validators(download_id: u64, etag: Option<&str>, last_modified: Option<&str>) -> Result<()> {
+    let db = get_db_instance().await;
+    let db_guard = db.lock().unwrap();
+    db_guard.update_validators(download_id, etag, last_modified)
+}
+
+/// Update the priority of a download in the database
+pub async fn update_priority(download_id: u64, priority: i32) -> Result<()> {
+    let db = get_db_instance().await;
+    let db_guard = db.lock().unwrap();
+    db_guard.update_priority(download_id, priority)
+}
+
+/// Renumber queue positions to follow the given order of download IDs
+pub async fn reorder_queue(download_ids: &[u64]) -> Result<()> {
+    let db = get_db_instance().await;
+    let mut db_guard = db.lock().unwrap();
+    db_guard.reorder_queue(download_ids)
+}
+
+/// Store the segment layout of a download in the database
+pub async fn save_manifest(download_id: u64, manifest: &DownloadManifest) -> Result<()> {
+    let db = get_db_instance().await;
+    let mut db_guard = db.lock().unwrap();
+    db_guard.save_manifest(download_id, manifest)
+}
+
+/// Get the saved segment layout of a download from the database
+pub async fn get_manifest(download_id: u64) -> Result<Option<DownloadManifest>> {
+    let db = get_db_instance().await;
+    let db_guard = db.lock().unwrap();
+    db_guard.get_manifest(download_id)
+}
+
+/// Record how many bytes of a segment have been written
+pub async fn update_segment_progress(download_id: u64, segment_index: u64, bytes_written: u64) -> Result<()> {
+    let db = get_db_instance().await;
+    let db_guard = db.lock().unwrap();
+    db_guard.update_segment_progress(download_id, segment_index, bytes_written)
+}
+
+/// Remove the saved segment layout of a download from the database
+pub async fn delete_manifest(download_id: u64) -> Result<()> {
+    let db = get_db_instance().await;
+    let db_guard = db.lock().unwrap();
+    db_guard.delete_manifest(download_id)
+}
+
+/// Get an application setting from the database
+pub async fn get_setting(key: &str) -> Result<Option<String>> {
+    let db = get_db_instance().await;
+    let db_guard = db.lock().unwrap();
+    db_guard.get_setting(key)
+}
+
+/// Store an application setting in the database
+pub async fn set_setting(key: &str, value: &str) -> Result<()> {
+    let db = get_db_instance().await;
+    let db_guard = db.lock().unwrap();
+    db_guard.set_setting(key, value)
+}
@@ -0,0 +1,200 @@
+use crate::checksum::ChecksumMismatch;
+use crate::client::IncompleteDownload;
+use crate::conflict::OutputExists;
+use crate::db_manager::StatusError;
+use crate::retry::HttpStatusError;
+use crate::status::InvalidTransition;
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use std::error::Error;
+use std::fmt;
+
+/// An error reported to the frontend, tagged with a code saying what kind of
+/// failure it is so the UI can react to it without parsing the message
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, Type)]
+#[serde(tag = "code", rename_all = "snake_case")]
+pub enum SpeedyError {
+    /// The server couldn't be reached or the connection failed
+    Network { message: String },
+    /// The server answered with an error status
+    HttpStatus { status: u16, message: String },
+    /// Reading or writing a file failed
+    Io { message: String },
+    /// There's no space left on the disk being written to
+    DiskFull { message: String },
+    /// The downloaded file doesn't have the expected checksum
+    Checksum { message: String },
+    /// The database couldn't be read or written
+    Db { message: String },
+    /// A command was called with a value it can't accept
+    InvalidInput { message: String },
+    /// The download or file asked for doesn't exist
+    NotFound { message: String },
+    /// The output file already exists and the policy doesn't allow replacing it
+    Conflict { message: String },
+    /// The download finished without every byte of the file
+    Incomplete { message: String },
+    /// Anything else
+    Other { message: String },
+}
+
+// OS error numbers for a full disk or exceeded quota
+#[cfg(target_os = "linux")]
+const DISK_FULL_OS_ERRORS: [i32; 2] = [28, 122]; // ENOSPC, EDQUOT
+#[cfg(all(unix, not(target_os = "linux")))]
+const DISK_FULL_OS_ERRORS: [i32; 2] = [28, 69]; // ENOSPC, EDQUOT
+#[cfg(windows)]
+const DISK_FULL_OS_ERRORS: [i32; 2] = [112, 39]; // ERROR_DISK_FULL, ERROR_HANDLE_DISK_FULL
+#[cfg(not(any(unix, windows)))]
+const DISK_FULL_OS_ERRORS: [i32; 0] = [];
+
+impl SpeedyError {
+    pub fn invalid_input(message: impl Into<String>) -> Self {
+        Self::InvalidInput { message: message.into() }
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        Self::NotFound { message: message.into() }
+    }
+
+    pub fn other(message: impl Into<String>) -> Self {
+        Self::Other { message: message.into() }
+    }
+
+    /// Code stored in the database and sent to the frontend, e.g. `disk_full`
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Network { .. } => "network",
+            Self::HttpStatus { .. } => "http_status",
+            Self::Io { .. } => "io",
+            Self::DiskFull { .. } => "disk_full",
+            Self::Checksum { .. } => "checksum",
+            Self::Db { .. } => "db",
+            Self::InvalidInput { .. } => "invalid_input",
+            Self::NotFound { .. } => "not_found",
+            Self::Conflict { .. } => "conflict",
+            Self::Incomplete { .. } => "incomplete",
+            Self::Other { .. } => "other",
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            Self::Network { message }
+            | Self::HttpStatus { message, .. }
+            | Self::Io { message }
+            | Self::DiskFull { message }
+            | Self::Checksum { message }
+            | Self::Db { message }
+            | Self::InvalidInput { message }
+            | Self::NotFound { message }
+            | Self::Conflict { message }
+            | Self::Incomplete { message }
+            | Self::Other { message } => message,
+        }
+    }
+
+    fn message_mut(&mut self) -> &mut String {
+        match self {
+            Self::Network { message }
+            | Self::HttpStatus { message, .. }
+            | Self::Io { message }
+            | Self::DiskFull { message }
+            | Self::Checksum { message }
+            | Self::Db { message }
+            | Self::InvalidInput { message }
+            | Self::NotFound { message }
+            | Self::Conflict { message }
+            | Self::Incomplete { message }
+            | Self::Other { message } => message,
+        }
+    }
+
+    /// Prefix the message with what was being done, keeping the code
+    pub fn context(mut self, context: &str) -> Self {
+        let message = self.message_mut();
+        *message = format!("{}: {}", context, message);
+        self
+    }
+
+    /// Classify an error by walking its chain of sources. The message is always
+    /// that of the outermost error, which usually says the most.
+    pub fn from_error(error: &(dyn Error + 'static)) -> Self {
+        let message = error.to_string();
+        let mut current: Option<&(dyn Error + 'static)> = Some(error);
+        while let Some(error) = current {
+            if let Some(speedy_error) = error.downcast_ref::<SpeedyError>() {
+                let mut speedy_error = speedy_error.clone();
+                *speedy_error.message_mut() = message;
+                return speedy_error;
+            }
+            if let Some(status_error) = error.downcast_ref::<HttpStatusError>() {
+                return Self::HttpStatus { status: status_error.status, message };
+            }
+            if error.is::<ChecksumMismatch>() {
+                return Self::Checksum { message };
+            }
+            if error.is::<OutputExists>() {
+                return Self::Conflict { message };
+            }
+            if error.is::<IncompleteDownload>() {
+                return Self::Incomplete { message };
+            }
+            if error.is::<InvalidTransition>() {
+                return Self::InvalidInput { message };
+            }
+            if let Some(reqwest_error) = error.downcast_ref::<reqwest::Error>() {
+                return match reqwest_error.status() {
+                    Some(status) => Self::HttpStatus { status: status.as_u16(), message },
+                    None => Self::Network { message },
+                };
+            }
+            if let Some(io_error) = error.downcast_ref::<std::io::Error>() {
+                let disk_full = io_error.raw_os_error()
+                    .map(|code| DISK_FULL_OS_ERRORS.contains(&code))
+                    .unwrap_or(false);
+                return if disk_full { Self::DiskFull { message } } else { Self::Io { message } };
+            }
+            if let Some(db_error) = error.downcast_ref::<rusqlite::Error>() {
+                return match db_error {
+                    rusqlite::Error::QueryReturnedNoRows => Self::NotFound { message },
+                    _ => Self::Db { message },
+                };
+            }
+            current = error.source();
+        }
+        Self::Other { message }
+    }
+}
+
+impl fmt::Display for SpeedyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message())
+    }
+}
+
+impl Error for SpeedyError {}
+
+impl From<Box<dyn Error + Send + Sync>> for SpeedyError {
+    fn from(error: Box<dyn Error + Send + Sync>) -> Self {
+        Self::from_error(error.as_ref())
+    }
+}
+
+impl From<rusqlite::Error> for SpeedyError {
+    fn from(error: rusqlite::Error) -> Self {
+        Self::from_error(&error)
+    }
+}
+
+impl From<StatusError> for SpeedyError {
+    fn from(error: StatusError) -> Self {
+        Self::from_error(&error)
+    }
+}
+
+impl From<std::io::Error> for SpeedyError {
+    fn from(error: std::io::Error) -> Self {
+        Self::from_error(&error)
+    }
+}
@@ -0,0 +1,202 @@
+use crate::probe::ProbeResult;
+
+/// Used when neither the server nor the URL gives us a usable name
+pub const DEFAULT_FILE_NAME: &str = "download";
+
+/// Longest file name most file systems accept, in bytes
+const MAX_FILE_NAME_LEN: usize = 255;
+
+/// Names Windows reserves for devices, with or without an extension
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL",
+    "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+    "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Pick the name to save a probed resource under: the one suggested by
+/// Content-Disposition, else the last path segment of the URL after redirects.
+/// Names without an extension get one derived from the Content-Type.
+pub fn detect(probe: &ProbeResult) -> String {
+    let name = probe.filename.as_deref()
+        .map(sanitize)
+        .filter(|name| name != DEFAULT_FILE_NAME)
+        .unwrap_or_else(|| from_url(&probe.final_url));
+    with_mime_extension(name, probe.content_type.as_deref())
+}
+
+/// Name taken from the last path segment of a URL, ignoring the query and fragment
+pub fn from_url(url: &str) -> String {
+    let without_query = url.split(['?', '#']).next().unwrap_or(url);
+    // Skip past the scheme and host so a bare host isn't taken as a name
+    let path = match without_query.split_once("://") {
+        Some((_, rest)) => rest.split_once('/').map(|(_, path)| path).unwrap_or(""),
+        None => without_query,
+    };
+    let segment = path.rsplit('/').next().unwrap_or("");
+    sanitize(&String::from_utf8_lossy(&percent_decode(segment)))
+}
+
+/// Make a name safe to create on any platform: path separators, reserved
+/// characters and control characters become `_`, trailing dots and spaces are
+/// dropped, device names get a suffix and overly long names are shortened
+pub fn sanitize(name: &str) -> String {
+    let mut sanitized: String = name
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    sanitized = sanitized.trim().trim_end_matches(['.', ' ']).to_string();
+    // A leading dot would hide the file, and "." or ".." would be a directory
+    sanitized = sanitized.trim_start_matches('.').to_string();
+
+    if sanitized.is_empty() {
+        return DEFAULT_FILE_NAME.to_string();
+    }
+
+    let stem = sanitized.split('.').next().unwrap_or("");
+    if RESERVED_NAMES.iter().any(|reserved| reserved.eq_ignore_ascii_case(stem)) {
+        sanitized.insert(stem.len(), '_');
+    }
+
+    truncate(sanitized)
+}
+
+/// Shorten a name to `MAX_FILE_NAME_LEN` bytes, keeping its extension
+fn truncate(name: String) -> String {
+    if name.len() <= MAX_FILE_NAME_LEN {
+        return name;
+    }
+    let extension = match name.rfind('.') {
+        Some(dot) if name.len() - dot <= 16 => &name[dot..],
+        _ => "",
+    };
+    let mut end = MAX_FILE_NAME_LEN - extension.len();
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}{}", &name[..end], extension)
+}
+
+/// Append the extension belonging to `content_type` when the name has none
+fn with_mime_extension(name: String, content_type: Option<&str>) -> String {
+    let has_extension = matches!(name.rfind('.'), Some(dot) if dot > 0 && dot < name.len() - 1);
+    if has_extension {
+        return name;
+    }
+    match content_type.and_then(extension_for_mime) {
+        Some(extension) => truncate(format!("{}.{}", name, extension)),
+        None => name,
+    }
+}
+
+/// File extension commonly used for a MIME type, ignoring its parameters
+pub fn extension_for_mime(content_type: &str) -> Option<&'static str> {
+    let mime = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+    let extension = match mime.as_str() {
+        "application/pdf" => "pdf",
+        "application/zip" | "application/x-zip-compressed" => "zip",
+        "application/gzip" | "application/x-gzip" => "gz",
+        "application/x-tar" => "tar",
+        "application/x-bzip2" => "bz2",
+        "application/x-xz" => "xz",
+        "application/x-7z-compressed" => "7z",
+        "application/vnd.rar" | "application/x-rar-compressed" => "rar",
+        "application/x-msdownload" | "application/vnd.microsoft.portable-executable" => "exe",
+        "application/x-msi" | "application/x-ms-installer" => "msi",
+        "application/x-apple-diskimage" => "dmg",
+        "application/vnd.debian.binary-package" => "deb",
+        "application/x-rpm" => "rpm",
+        "application/java-archive" => "jar",
+        "application/vnd.android.package-archive" => "apk",
+        "application/x-iso9660-image" => "iso",
+        "application/json" => "json",
+        "application/xml" | "text/xml" => "xml",
+        "application/javascript" | "text/javascript" => "js",
+        "text/plain" => "txt",
+        "text/html" => "html",
+        "text/css" => "css",
+        "text/csv" => "csv",
+        "image/jpeg" => "jpg",
+        "image/png" => "png",
+        "image/gif" => "gif",
+        "image/webp" => "webp",
+        "image/svg+xml" => "svg",
+        "audio/mpeg" => "mp3",
+        "audio/ogg" => "ogg",
+        "audio/wav" | "audio/x-wav" => "wav",
+        "audio/flac" => "flac",
+        "video/mp4" => "mp4",
+        "video/webm" => "webm",
+        "video/x-matroska" => "mkv",
+        "video/quicktime" => "mov",
+        _ => return None,
+    };
+    Some(extension)
+}
+
+/// Decode `%XX` escapes, leaving malformed ones as they are
+pub fn percent_decode(value: &str) -> Vec<u8> {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            if let (Some(high), Some(low)) = (hex_value(bytes[i + 1]), hex_value(bytes[i + 2])) {
+                decoded.push(high << 4 | low);
+                i += 3;
+                continue;
+            }
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+    decoded
+}
+
+fn hex_value(digit: u8) -> Option<u8> {
+    (digit as char).to_digit(16).map(|value| value as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percent_decodes_escapes() {
+        assert_eq!(percent_decode("a%20b%2Fc"), b"a b/c");
+        assert_eq!(percent_decode("%e2%82%ac"), "\u{20ac}".as_bytes());
+        assert_eq!(percent_decode("100%25"), b"100%");
+        assert_eq!(percent_decode(""), b"");
+    }
+
+    #[test]
+    fn percent_decode_keeps_malformed_escapes() {
+        assert_eq!(percent_decode("100%"), b"100%");
+        assert_eq!(percent_decode("a%2"), b"a%2");
+        assert_eq!(percent_decode("%zz%41"), b"%zzA");
+        assert_eq!(percent_decode("%%41"), b"%A");
+    }
+
+    #[test]
+    fn names_from_urls() {
+        assert_eq!(from_url("https://example.com/files/na%C3%AFve%20file.zip?token=1#top"), "na\u{ef}ve file.zip");
+        assert_eq!(from_url("https://example.com/dir/..%2F..%2Fetc%2Fpasswd"), "_.._etc_passwd");
+        assert_eq!(from_url("https://example.com"), DEFAULT_FILE_NAME);
+        assert_eq!(from_url("https://example.com/dir/"), DEFAULT_FILE_NAME);
+    }
+
+    #[test]
+    fn sanitizes_names() {
+        assert_eq!(sanitize("a/b\\c:d*e?.txt"), "a_b_c_d_e_.txt");
+        assert_eq!(sanitize(".hidden. "), "hidden");
+        assert_eq!(sanitize("con.txt"), "con_.txt");
+        assert_eq!(sanitize(".."), DEFAULT_FILE_NAME);
+        let long = format!("{}.tar.gz", "x".repeat(300));
+        let truncated = sanitize(&long);
+        assert_eq!(truncated.len(), MAX_FILE_NAME_LEN);
+        assert!(truncated.ends_with(".gz"));
+    }
+}
@@ -15,8 +15,60 @@ pub mod db;
 pub mod db_manager;
 
 /// Module containing the API functions
+#[cfg(feature = "gui")]
 pub mod api;
 
 /// Module containing download state tracking
 pub mod state;
 
+
+/// Module tracking running downloads so they can be paused or cancelled
+pub mod registry;
+
+/// Module containing bandwidth limiting shared by download segments
+pub mod throttle;
+
+/// Module scheduling queued downloads under a concurrency limit
+pub mod queue;
+
+/// Module describing the persisted segment layout of a download
+pub mod manifest;
+
+/// Module probing a URL for its size, range support and metadata
+pub mod probe;
+
+/// Module classifying segment errors and deciding how to retry them
+pub mod retry;
+
+/// Module tracking progress of downloads written into a preallocated output file
+pub mod prealloc;
+
+/// Module computing and checking checksums of finished downloads
+pub mod checksum;
+
+/// Module picking safe file names from server headers and URLs
+pub mod filename;
+
+/// Module deciding what happens when a download's output file already exists
+pub mod conflict;
+
+/// Module giving every download its own directory for part files
+pub mod workspace;
+
+/// Module restoring downloads interrupted by quitting the app
+pub mod recovery;
+
+/// Module defining the statuses a download goes through and the moves between them
+pub mod status;
+
+/// Module defining the errors reported to the frontend and the codes stored with them
+pub mod error;
+
+/// Module containing the download engine the app, and anything else, drives downloads through
+pub mod manager;
+
+/// Module serving the local HTTP and WebSocket API scripts and browser extensions control downloads with
+pub mod control;
+
+/// Module answering aria2-style JSON-RPC calls, so aria2 clients can drive downloads
+pub mod aria2;
@@ -8,18 +8,35 @@ mod state;
 mod db;
 mod client;
 mod db_manager;
+mod registry;
+mod throttle;
+mod queue;
+mod manifest;
+mod probe;
+mod retry;
+mod prealloc;
+mod checksum;
+mod filename;
+mod conflict;
+mod workspace;
+mod recovery;
+mod status;
+mod error;
+mod manager;
+mod control;
+mod aria2;
 
 use std::fs;
 use std::path::PathBuf;
-use specta::collect_types;
+use specta::{collect_types, DefOpts, Type, TypeDefs};
 use tauri::generate_handler;
 use tauri_specta::ts;
 use specta::ts::{BigIntExportBehavior, ExportConfiguration};
 
 #[tokio::main]
 async fn main() {
-    // Initialize the database
-    tauri_app::db_manager::init_db().await;
+    // Open the database, pausing or re-queueing downloads that were running when the app last quit
+    let download_manager = manager::DownloadManager::open(None).await;
     
     // Generate TypeScript bindings at runtime in debug mode
     // but only if necessary (if file doesn't exist or api.rs was modified more recently)
@@ -67,16 +84,49 @@ async fn main() {
         };
         
         if should_generate {
+            // Commands fail with a SpeedyError, but only the types of their results are
+            // collected from them, so the error type is added to the bindings here
+            let mut type_map = TypeDefs::default();
+            error::SpeedyError::reference(DefOpts { parent_inline: false, type_map: &mut type_map }, &[])
+                .expect("Failed to collect the SpeedyError type");
+            
             // Generate TypeScript bindings for all API functions
             let type_collection = collect_types![
+                type_map: type_map,
                 api::start_download,
                 api::list_downloads,
                 api::get_download,
                 api::delete_download,
                 api::pause_download,
                 api::resume_download,
+                api::cancel_download,
+                api::set_speed_limit,
+                api::set_global_speed_limit,
+                api::get_queue,
+                api::get_max_concurrent_downloads,
+                api::set_max_concurrent_downloads,
+                api::set_download_priority,
+                api::move_in_queue,
+                api::get_max_segment_retries,
+                api::set_max_segment_retries,
+                api::get_preallocate_files,
+                api::set_preallocate_files,
+                api::get_workspace_dir,
+                api::set_workspace_dir,
+                api::get_resume_on_startup,
+                api::set_resume_on_startup,
+                api::get_control_api_enabled,
+                api::set_control_api_enabled,
+                api::get_control_api_port,
+                api::set_control_api_port,
+                api::get_control_api_token,
+                api::reset_control_api_token,
+                api::get_conflict_policy,
+                api::set_conflict_policy,
+                api::resolve_conflict,
                 api::get_downloads_by_status,
                 api::check_existing_download,
+                api::probe_url,
                 api::open_details_window
             ].unwrap();
 
@@ -89,6 +139,14 @@ async fn main() {
     }
     
     tauri::Builder::default()
+        .manage(download_manager.clone())
+        .setup(move |app| {
+            // Restored downloads report their progress to the windows, so they start once the app exists
+            download_manager.add_sink(api::AppEventSink::new(app.handle()));
+            tauri::async_runtime::spawn(control::restart(download_manager.clone()));
+            tauri::async_runtime::spawn(async move { download_manager.restore().await });
+            Ok(())
+        })
         .invoke_handler(generate_handler![
             api::start_download,
             api::list_downloads,
@@ -96,8 +154,34 @@ async fn main() {
             api::delete_download,
             api::pause_download,
             api::resume_download,
+            api::cancel_download,
+            api::set_speed_limit,
+            api::set_global_speed_limit,
+            api::get_queue,
+            api::get_max_concurrent_downloads,
+            api::set_max_concurrent_downloads,
+            api::set_download_priority,
+            api::move_in_queue,
+            api::get_max_segment_retries,
+            api::set_max_segment_retries,
+            api::get_preallocate_files,
+            api::set_preallocate_files,
+            api::get_workspace_dir,
+            api::set_workspace_dir,
+            api::get_resume_on_startup,
+            api::set_resume_on_startup,
+            api::get_control_api_enabled,
+            api::set_control_api_enabled,
+            api::get_control_api_port,
+            api::set_control_api_port,
+            api::get_control_api_token,
+            api::reset_control_api_token,
+            api::get_conflict_policy,
+            api::set_conflict_policy,
+            api::resolve_conflict,
             api::get_downloads_by_status,
             api::check_existing_download,
+            api::probe_url,
             api::open_details_window,
             api::greet, // Keep the legacy function for backward compatibility
             api::debug_commands,
@@ -0,0 +1,884 @@
+use crate::checksum;
+use crate::client::{self, DownloadEvent};
+use crate::conflict::{self, ConflictPolicy};
+use crate::db::{self, Download};
+use crate::db_manager;
+use crate::error::SpeedyError;
+use crate::prealloc;
+use crate::probe;
+use crate::queue;
+use crate::recovery;
+use crate::registry;
+use crate::retry;
+use crate::state::{DownloadState, HighWaterMarkTracker};
+use crate::status::DownloadStatus;
+use crate::workspace;
+use crate::filename;
+use futures_util::future::BoxFuture;
+use serde_json::{Map, Value};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time;
+
+/// Receives what the engine reports about running downloads, so the same engine
+/// can drive the app's windows, a terminal or a test
+pub trait EventSink: Send + Sync {
+    /// Progress of a download, sent every 50ms while it runs. The payload is the one
+    /// `DownloadState::create_progress_json` builds, with a `downloadId` added.
+    fn progress(&self, download_id: u64, payload: &Map<String, Value>);
+
+    /// An event reported by the client, before the engine records it
+    fn event(&self, _download_id: u64, _event: &DownloadEvent) {}
+
+    /// A run of a download ended and its outcome is recorded, `download` is the row
+    /// as stored. A paused or cancelled run may end before its new status is saved.
+    fn finished(&self, _download: &Download) {}
+}
+
+/// Segments a download is split into when whoever starts it doesn't say
+pub const DEFAULT_PARTS: u64 = 8;
+
+/// What to download and where
+#[derive(Clone, Debug, Default)]
+pub struct DownloadRequest {
+    pub url: String,
+    pub parts: u64,
+    pub name: Option<String>,         // detected from the server when empty
+    pub download_id: Option<u64>,     // generated when missing
+    pub directory: Option<String>,    // the downloads folder when missing
+    pub checksum: Option<String>,     // expected digest like "sha256:<hex>"
+    pub checksum_url: Option<String>, // `.sha256` file or `SHA256SUMS` listing to read it from
+}
+
+impl DownloadRequest {
+    pub fn new(url: String, parts: u64) -> Self {
+        Self { url, parts, ..Default::default() }
+    }
+}
+
+/// The download engine: queues downloads, runs them, records their progress in
+/// the database and reports it to the registered event sinks.
+///
+/// Clones share the same sinks and progress. Downloads themselves are tracked
+/// process-wide, so there should be one manager per process.
+#[derive(Clone, Default)]
+pub struct DownloadManager {
+    sinks: Arc<RwLock<Vec<Arc<dyn EventSink>>>>,
+    states: Arc<Mutex<HashMap<u64, Arc<Mutex<DownloadState>>>>>, // download_id -> progress of its current run
+    owned: Option<Arc<Mutex<HashSet<u64>>>>, // downloads started through this manager, None when it owns every download
+}
+
+/// Path a download is saved to, in its chosen directory or the default one
+pub fn output_path(download: &Download) -> PathBuf {
+    let output_dir = match &download.directory {
+        Some(directory) => PathBuf::from(directory),
+        None => client::Client::default_output_dir(),
+    };
+    output_dir.join(&download.filename)
+}
+
+/// Name suggested by the server through Content-Disposition or redirects,
+/// falling back to the URL when the server can't be reached
+pub async fn detect_file_name(url: &str) -> String {
+    let http_client = reqwest::Client::new();
+    match probe::probe(&http_client, url).await {
+        Ok(probe) => filename::detect(&probe),
+        Err(e) => {
+            eprintln!("Failed to probe {} for its filename: {}", url, e);
+            client::Client::get_file_name(url)
+        }
+    }
+}
+
+/// Last ID handed out by `unused_download_id`, so IDs picked in the same millisecond
+/// but not yet stored don't collide
+static LAST_DOWNLOAD_ID: AtomicU64 = AtomicU64::new(0);
+
+/// A millisecond timestamp that no download has as its ID yet
+pub async fn unused_download_id() -> Result<u64, SpeedyError> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_millis() as u64;
+    loop {
+        let previous = LAST_DOWNLOAD_ID.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |last| Some(now.max(last + 1)))
+            .unwrap_or_else(|last| last);
+        let download_id = now.max(previous + 1);
+        let existing = db_manager::get_download(download_id).await
+            .map_err(|e| SpeedyError::from(e).context("Error retrieving download"))?;
+        if existing.is_none() {
+            return Ok(download_id);
+        }
+    }
+}
+
+/// Checks if a file is already being downloaded or exists in parts
+/// Returns information about any existing download with the same filename
+pub async fn check_existing_download(url: &str) -> Result<serde_json::Value, SpeedyError> {
+    // Get the filename the download would be saved under
+    let filename = detect_file_name(url).await;
+    
+    // Get the downloads directory
+    let downloads_dir = dirs::download_dir().unwrap_or_else(|| std::env::current_dir().unwrap_or_else(|_| std::path::PathBuf::from(".")));
+    
+    // Check if the complete file already exists in the downloads folder
+    let complete_file_path = downloads_dir.join(&filename);
+    let complete_file_exists = complete_file_path.exists();
+    
+    // Check for part files left in the workspace of an unfinished download of the same URL
+    let mut part_files = 0;
+    let mut location = None;
+    let downloads = db_manager::list_downloads().await
+        .map_err(|e| SpeedyError::from(e).context("Failed to list downloads"))?;
+    for download in downloads.iter().filter(|download| download.url == url && download.status != DownloadStatus::Completed) {
+        if let Some(download_workspace) = &download.workspace {
+            let count = workspace::part_file_count(Path::new(download_workspace)).await;
+            if count > 0 {
+                part_files = count;
+                location = Some(download_workspace.clone());
+                break;
+            }
+        }
+    }
+    
+    // Generate a unique filename regardless of whether we found existing files
+    let file_stem = std::path::Path::new(&filename)
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or("file");
+        
+    let extension = std::path::Path::new(&filename)
+        .extension()
+        .and_then(|s| s.to_str())
+        .unwrap_or("");
+    
+    // Try to find a non-colliding filename
+    let mut counter = 1;
+    let mut new_filename = if extension.is_empty() {
+        format!("{} ({})", file_stem, counter)
+    } else {
+        format!("{} ({}).{}", file_stem, counter, extension)
+    };
+    
+    let mut new_path = downloads_dir.join(&new_filename);
+    
+    // Keep incrementing until we find a name that doesn't exist
+    while new_path.exists() {
+        counter += 1;
+        new_filename = if extension.is_empty() {
+            format!("{} ({})", file_stem, counter)
+        } else {
+            format!("{} ({}).{}", file_stem, counter, extension)
+        };
+        new_path = downloads_dir.join(&new_filename);
+    }
+    
+    if part_files == 0 && !complete_file_exists {
+        // No existing files found
+        return Ok(serde_json::json!({
+            "exists": false,
+            "original_filename": filename,
+            "suggested_filename": filename
+        }));
+    }
+    
+    if let Some(location) = location {
+        // We found part files, suggesting a download is in progress or was interrupted
+        return Ok(serde_json::json!({
+            "exists": true,
+            "type": "in_progress",
+            "original_filename": filename,
+            "part_files": part_files,
+            "location": location,
+            "suggested_filename": new_filename
+        }));
+    }
+    
+    if complete_file_exists {
+        // The file already exists
+        return Ok(serde_json::json!({
+            "exists": true,
+            "type": "completed",
+            "original_filename": filename,
+            "suggested_filename": new_filename,
+            "file_path": complete_file_path.to_string_lossy()
+        }));
+    }
+    
+    // This should never happen as we've handled all cases above
+    Ok(serde_json::json!({
+        "exists": false,
+        "original_filename": filename,
+        "suggested_filename": filename
+    }))
+}
+
+impl DownloadManager {
+    /// Open the database, at `db_path` or the default location, and bring back
+    /// downloads interrupted by the last quit. Only the first call in a process
+    /// opens the database, later ones share it.
+    pub async fn open(db_path: Option<PathBuf>) -> Self {
+        db_manager::init_db_at(db_path).await;
+        // Pause or re-queue downloads that were running when the app last quit
+        recovery::reconcile().await;
+        Self::default()
+    }
+
+    /// Open the database alongside another process that may be running downloads
+    /// from it, like the app. Interrupted downloads are left alone and only the
+    /// downloads added or resumed through this manager are started.
+    pub async fn open_shared(db_path: Option<PathBuf>) -> Self {
+        db_manager::init_db_at(db_path).await;
+        Self { owned: Some(Arc::default()), ..Self::default() }
+    }
+
+    /// Whether this manager may start `download_id` when it comes up in the queue
+    fn owns(&self, download_id: u64) -> bool {
+        match &self.owned {
+            Some(owned) => owned.lock().unwrap().contains(&download_id),
+            None => true,
+        }
+    }
+
+    /// A manager sharing the database can't tell whether an active download it
+    /// didn't start is running elsewhere, so it refuses to take over or stop one
+    fn check_not_elsewhere(&self, download: &Download) -> Result<(), SpeedyError> {
+        match &self.owned {
+            Some(owned) if download.status.is_active() && !owned.lock().unwrap().contains(&download.download_id) => {
+                Err(SpeedyError::invalid_input(format!("Download {} is running in another process", download.download_id)))
+            },
+            _ => Ok(()),
+        }
+    }
+
+    /// Marks a download as started through this manager
+    fn claim(&self, download: &Download) -> Result<(), SpeedyError> {
+        self.check_not_elsewhere(download)?;
+        if let Some(owned) = &self.owned {
+            owned.lock().unwrap().insert(download.download_id);
+        }
+        Ok(())
+    }
+
+    /// Report progress and events of every download to `sink` from now on
+    pub fn add_sink(&self, sink: impl EventSink + 'static) {
+        self.sinks.write().unwrap().push(Arc::new(sink));
+    }
+
+    /// Progress of a download's current run, None when it isn't running
+    pub fn state(&self, download_id: u64) -> Option<Arc<Mutex<DownloadState>>> {
+        self.states.lock().unwrap().get(&download_id).cloned()
+    }
+
+    fn emit_progress(&self, download_id: u64, payload: &Map<String, Value>) {
+        for sink in self.sinks.read().unwrap().iter() {
+            sink.progress(download_id, payload);
+        }
+    }
+
+    fn emit_event(&self, download_id: u64, event: &DownloadEvent) {
+        for sink in self.sinks.read().unwrap().iter() {
+            sink.event(download_id, event);
+        }
+    }
+
+    fn emit_finished(&self, download: &Download) {
+        for sink in self.sinks.read().unwrap().iter() {
+            sink.finished(download);
+        }
+    }
+
+    /// Adds a download to the queue, it starts as soon as a download slot is free.
+    /// A download that's already known is put back in the queue instead, or left
+    /// alone while it runs, a known ID with a different URL is rejected.
+    /// Returns the ID of the download.
+    pub async fn add(&self, request: DownloadRequest) -> Result<u64, SpeedyError> {
+        // Reject a malformed digest now rather than after downloading the whole file
+        let expected_checksum = match request.checksum.as_deref().map(str::trim).filter(|c| !c.is_empty()) {
+            Some(checksum) => Some(checksum::ExpectedChecksum::parse(checksum).map_err(SpeedyError::invalid_input)?.to_string()),
+            None => None,
+        };
+        let checksum_url = request.checksum_url.map(|u| u.trim().to_string()).filter(|u| !u.is_empty());
+        if let Some(checksum_url) = &checksum_url {
+            if !checksum_url.starts_with("http://") && !checksum_url.starts_with("https://") {
+                return Err(SpeedyError::invalid_input(format!("Invalid checksum URL: {}. URL must start with http:// or https://", checksum_url)));
+            }
+        }
+
+        // Use the chosen filename, or ask the server which name it suggests
+        let name = request.name.as_deref().unwrap_or("").trim();
+        let filename = if name.is_empty() {
+            detect_file_name(&request.url).await
+        } else if name == "." || name == ".." || name.contains(['/', '\\']) {
+            return Err(SpeedyError::invalid_input(format!("Invalid filename: {}. It can't contain a path", name)));
+        } else {
+            name.to_string()
+        };
+
+        let directory = request.directory.map(|d| d.trim().to_string()).filter(|d| !d.is_empty());
+        if let Some(directory) = &directory {
+            if !PathBuf::from(directory).is_absolute() {
+                return Err(SpeedyError::invalid_input(format!("Invalid directory: {}. It must be an absolute path", directory)));
+            }
+        }
+
+        let download_id = match request.download_id {
+            Some(download_id) => download_id,
+            None => unused_download_id().await?,
+        };
+
+        match db_manager::get_download(download_id).await {
+            Ok(Some(existing)) if existing.url != request.url => {
+                return Err(SpeedyError::invalid_input(format!("Download {} already exists for {}", download_id, existing.url)));
+            },
+            Ok(Some(existing)) => {
+                if registry::is_active(download_id).await {
+                    eprintln!("Download {} is already running", download_id);
+                    return Ok(download_id);
+                }
+                // Starting a known download again just puts it back in the queue
+                self.claim(&existing)?;
+                if let Err(e) = queue::enqueue(download_id).await {
+                    return Err(SpeedyError::from(e).context("Failed to queue download"));
+                }
+            },
+            Ok(None) => {
+                // Create a database entry for this download
+                let mut download = db::Download::new(download_id, request.url, filename, 0, request.parts);
+                download.status = DownloadStatus::Queued;
+                download.queue_position = db_manager::next_queue_position().await.unwrap_or(0);
+                download.expected_checksum = expected_checksum;
+                download.checksum_url = checksum_url;
+                download.directory = directory;
+                self.claim(&download)?;
+                if let Err(e) = db_manager::insert_download(&download).await {
+                    return Err(SpeedyError::from(e).context("Failed to insert download into database"));
+                }
+            },
+            Err(e) => return Err(SpeedyError::from(e).context("Error retrieving download")),
+        }
+
+        self.process_queue(None).await;
+        Ok(download_id)
+    }
+
+    /// Starts queued downloads while there are free download slots
+    pub fn process_queue(&self, finishing: Option<u64>) -> BoxFuture<'static, ()> {
+        let manager = self.clone();
+        // Boxed because starting a download eventually calls back into this
+        Box::pin(async move {
+            queue::promote(finishing, |download_id| manager.owns(download_id), |download| manager.spawn_download(download)).await;
+        })
+    }
+
+    /// Starts the downloads restored when the manager was opened, if they are set
+    /// to resume on startup. Called once the sinks their progress goes to exist.
+    pub async fn restore(&self) {
+        if recovery::resume_on_startup().await {
+            self.process_queue(None).await;
+        }
+    }
+
+    /// Runs a download and tracks its progress
+    async fn spawn_download(&self, download: Download) -> Result<(), SpeedyError> {
+        let url = download.url.clone();
+        let parts = download.parts;
+        let download_id = download.download_id;
+
+        let (tx, rx) = std::sync::mpsc::channel::<DownloadEvent>();
+
+        // Create shared download state
+        let download_state = Arc::new(Mutex::new(DownloadState::new()));
+
+        if let Err(e) = db_manager::update_status(download_id, DownloadStatus::Probing).await {
+            return Err(SpeedyError::from(e).context("Failed to update download status"));
+        }
+
+        // Start the download process, registering it so it can be paused or cancelled
+        let mut client = client::Client::new(url.clone(), parts);
+        match db_manager::get_manifest(download_id).await {
+            Ok(Some(manifest)) => client.set_manifest(manifest),
+            Ok(None) => {},
+            Err(e) => eprintln!("Failed to load segment layout, starting over: {}", e),
+        }
+        client.set_retry_policy(retry::load_policy().await);
+        client.set_preallocate(prealloc::preallocate_files().await);
+        client.set_destination(download.directory.as_ref().map(PathBuf::from), Some(download.filename.clone()));
+        // Fixed on the first run, so moving the workspace root doesn't strand part files
+        let download_workspace = match &download.workspace {
+            Some(download_workspace) => PathBuf::from(download_workspace),
+            None => {
+                let download_workspace = workspace::for_download(&workspace::workspace_root().await, download_id);
+                if let Err(e) = db_manager::update_workspace(download_id, &download_workspace.to_string_lossy()).await {
+                    return Err(SpeedyError::from(e).context("Failed to save download workspace"));
+                }
+                download_workspace
+            }
+        };
+        client.set_workspace(download_workspace);
+        let conflict_policy = match download.conflict_policy.as_deref().and_then(ConflictPolicy::parse) {
+            Some(policy) => policy,
+            None => conflict::conflict_policy().await,
+        };
+        client.set_conflict_policy(conflict_policy);
+        // A digest given up front wins over a checksum file
+        match (&download.expected_checksum, &download.checksum_url) {
+            (Some(expected), _) => match checksum::ExpectedChecksum::parse(expected) {
+                Ok(expected) => client.set_checksum(checksum::ChecksumSource::Digest(expected)),
+                Err(e) => eprintln!("Ignoring stored checksum of download {}: {}", download_id, e),
+            },
+            (None, Some(checksum_url)) => client.set_checksum(checksum::ChecksumSource::Url(checksum_url.clone())),
+            (None, None) => {},
+        }
+        let control = client.control_handle();
+        let error_state = download_state.clone();
+        let finish_manager = self.clone();
+        registry::spawn(download_id, control, async move {
+            if let Err(e) = client.download(tx.clone()).await {
+                eprintln!("Download error: {}", e);
+                error_state.lock().unwrap().mark_stopped();
+                // Update database with error - avoid using the error directly across await
+                let conflict_policy = e.downcast_ref::<conflict::OutputExists>().map(|conflict| conflict.policy);
+                let error = SpeedyError::from_error(e.as_ref());
+                let result = if e.is::<checksum::ChecksumMismatch>() {
+                    db_manager::mark_verification_failed(download_id, &error).await
+                } else if conflict_policy == Some(ConflictPolicy::Skip) {
+                    // The client removed the part files, so there's nothing left to resume
+                    if let Err(db_err) = db_manager::delete_manifest(download_id).await {
+                        eprintln!("Failed to remove segment layout from database: {}", db_err);
+                    }
+                    db_manager::mark_skipped(download_id, &error).await
+                } else if conflict_policy.is_some() {
+                    // Kept until the user picks a policy with resolve_conflict
+                    db_manager::mark_conflict(download_id, &error).await
+                } else if e.is::<client::IncompleteDownload>() {
+                    // The layout and part files stay, resuming downloads only the missing ranges
+                    db_manager::mark_incomplete(download_id, &error).await
+                } else {
+                    db_manager::mark_error(download_id, &error).await
+                };
+                if let Err(db_err) = result {
+                    eprintln!("Failed to update database with error: {}", db_err);
+                }
+            }
+
+            // Hand this download's slot to the next one in the queue
+            finish_manager.process_queue(Some(download_id)).await;
+        }).await.map_err(SpeedyError::invalid_input)?;
+
+        // The client reports on a std channel, forward its events from a thread of their
+        // own so waiting for them doesn't hold up a runtime worker
+        let (event_tx, mut event_rx) = tokio::sync::mpsc::unbounded_channel();
+        std::thread::spawn(move || {
+            while let Ok(event) = rx.recv() {
+                if event_tx.send(event).is_err() {
+                    break;
+                }
+            }
+        });
+
+        // Create a task to process events and update the download state
+        let state = download_state.clone();
+        let event_manager = self.clone();
+        tokio::spawn(async move {
+            while let Some(event) = event_rx.recv().await {
+                event_manager.emit_event(download_id, &event);
+                match event {
+                    DownloadEvent::ManifestPlanned { manifest } => {
+                        // Persist the layout first so a crash mid-download can resume with it
+                        if let Err(e) = db_manager::save_manifest(download_id, &manifest).await {
+                            eprintln!("Failed to save segment layout in database: {}", e);
+                        }
+
+                        // Remember which version of the file we're downloading
+                        if let Err(e) = db_manager::update_validators(download_id, manifest.etag.as_deref(), manifest.last_modified.as_deref()).await {
+                            eprintln!("Failed to save ETag/Last-Modified in database: {}", e);
+                        }
+                    },
+                    DownloadEvent::Initialize { file_size, segments } => {
+                        // Use a block to limit the scope of the mutex guard
+                        {
+                            let mut state_guard = state.lock().unwrap();
+                            state_guard.initialize(file_size, segments.clone());
+                        }
+
+                        // Update the database with the file size. Bytes already on disk are
+                        // reported again by the segments, so the count starts from zero.
+                        if let Ok(Some(mut download)) = db_manager::get_download(download_id).await {
+                            download.status = DownloadStatus::Downloading;
+                            download.total_size = file_size;
+                            download.downloaded_bytes = 0;
+                            if let Err(e) = db_manager::update_download(&download).await {
+                                eprintln!("Failed to update download size in database: {}", e);
+                            }
+                        }
+                    },
+                    DownloadEvent::BytesReceived { segment_id, bytes, speed } => {
+                        // Use a block to limit the scope of the mutex guard
+                        let segment_bytes = {
+                            let mut state_guard = state.lock().unwrap();
+                            state_guard.add_bytes(segment_id, bytes, speed);
+                            state_guard.segment_progress.get(&segment_id).cloned().unwrap_or(0)
+                        };
+
+                        // Update the database with progress
+                        if let Err(e) = db_manager::update_progress(download_id, bytes).await {
+                            eprintln!("Failed to update download progress in database: {}", e);
+                        }
+                        if let Err(e) = db_manager::update_segment_progress(download_id, segment_id - 1, segment_bytes).await {
+                            eprintln!("Failed to update segment progress in database: {}", e);
+                        }
+                    },
+                    DownloadEvent::SegmentSplit { segment_id, segment_size, new_segment_id, new_segment_size } => {
+                        // The new layout itself arrives with the ManifestPlanned that follows
+                        state.lock().unwrap().split_segment(segment_id, segment_size, new_segment_id, new_segment_size);
+                    },
+                    DownloadEvent::Merging { merged_bytes, total_bytes } => {
+                        let started = {
+                            let mut state_guard = state.lock().unwrap();
+                            let started = !state_guard.is_merging;
+                            state_guard.set_merge_progress(merged_bytes, total_bytes);
+                            started
+                        };
+                        if started {
+                            if let Err(e) = db_manager::update_status(download_id, DownloadStatus::Merging).await {
+                                eprintln!("Failed to update download status: {}", e);
+                            }
+                        }
+                    },
+                    DownloadEvent::Verifying { hashed_bytes, total_bytes } => {
+                        let started = {
+                            let mut state_guard = state.lock().unwrap();
+                            let started = !state_guard.is_verifying;
+                            state_guard.set_verify_progress(hashed_bytes, total_bytes);
+                            started
+                        };
+                        if started {
+                            if let Err(e) = db_manager::update_status(download_id, DownloadStatus::Verifying).await {
+                                eprintln!("Failed to update download status: {}", e);
+                            }
+                        }
+                    },
+                    DownloadEvent::ChecksumComputed { checksum } => {
+                        // Stored before the comparison so a mismatch shows what was downloaded
+                        if let Err(e) = db_manager::update_actual_checksum(download_id, &checksum).await {
+                            eprintln!("Failed to save checksum in database: {}", e);
+                        }
+                    },
+                    DownloadEvent::Error { segment_id, message } => {
+                        eprintln!("Error in segment {}: {}", segment_id, message);
+
+                        // Update database with error
+                        if let Err(e) = db_manager::mark_error(download_id, &SpeedyError::other(message)).await {
+                            eprintln!("Failed to update database with error: {}", e);
+                        }
+                    },
+                    DownloadEvent::Renamed { file_name } => {
+                        // Remember the new name so a resumed download keeps writing to it
+                        if let Ok(Some(mut download)) = db_manager::get_download(download_id).await {
+                            download.filename = file_name;
+                            if let Err(e) = db_manager::update_download(&download).await {
+                                eprintln!("Failed to update download filename in database: {}", e);
+                            }
+                        }
+                    },
+                    DownloadEvent::Complete { output_path } => {
+                        // Mark the state as complete
+                        let (is_indeterminate, total_downloaded) = {
+                            let mut state_guard = state.lock().unwrap();
+                            state_guard.mark_complete();
+                            (state_guard.is_indeterminate, state_guard.total_downloaded)
+                        };
+
+                        // The size of a download of unknown length is only known now
+                        if is_indeterminate {
+                            if let Ok(Some(mut download)) = db_manager::get_download(download_id).await {
+                                download.total_size = total_downloaded;
+                                if let Err(e) = db_manager::update_download(&download).await {
+                                    eprintln!("Failed to update download size in database: {}", e);
+                                }
+                            }
+                        }
+
+                        // Record where the client actually saved the file
+                        let path_str = output_path.to_string_lossy().to_string();
+
+                        // Update database with completion
+                        if let Err(e) = db_manager::mark_complete(download_id, &path_str).await {
+                            eprintln!("Failed to mark download as complete in database: {}", e);
+                        }
+
+                        // The part files are merged, so there's nothing left to resume
+                        if let Err(e) = db_manager::delete_manifest(download_id).await {
+                            eprintln!("Failed to remove segment layout from database: {}", e);
+                        }
+
+                        break;
+                    },
+                    DownloadEvent::Stopped { reason } => {
+                        eprintln!("Download {} stopped: {:?}", download_id, reason);
+                        state.lock().unwrap().mark_stopped();
+                        break;
+                    }
+                }
+            }
+
+            // The client drops its sender only after a failure is recorded, so whichever
+            // way the loop ended the row now holds the outcome
+            match db_manager::get_download(download_id).await {
+                Ok(Some(download)) => event_manager.emit_finished(&download),
+                Ok(None) => {},
+                Err(e) => eprintln!("Failed to read finished download {}: {}", download_id, e),
+            }
+        });
+
+        // Report progress to the sinks until the download finishes or stops
+        let watcher_state = download_state.clone();
+        let watcher_manager = self.clone();
+        self.states.lock().unwrap().insert(download_id, download_state.clone());
+        tokio::spawn(async move {
+            let mut interval = time::interval(Duration::from_millis(50));
+            let mut high_water_mark = HighWaterMarkTracker::new();
+            let mut last_generation = 0;
+
+            loop {
+                interval.tick().await;
+
+                let download_complete;
+                let download_stopped;
+                let generation;
+                let mut progress_json;
+
+                {
+                    let state_guard = watcher_state.lock().unwrap();
+                    download_complete = state_guard.is_complete;
+                    download_stopped = state_guard.is_stopped;
+                    generation = state_guard.generation;
+                    progress_json = state_guard.create_progress_json();
+                }
+
+                // Progress legitimately goes back to zero when a download starts over
+                if generation != last_generation {
+                    high_water_mark = HighWaterMarkTracker::new();
+                    last_generation = generation;
+                }
+
+                // Ensure progress values never decrease
+                high_water_mark.ensure_monotonic_progress(&mut progress_json);
+
+                // Add the download ID to the payload
+                progress_json.insert("downloadId".to_string(), Value::from(download_id));
+
+                let total_progress = progress_json.get("progress").and_then(|v| v.as_f64()).unwrap_or(0.0);
+                watcher_manager.emit_progress(download_id, &progress_json);
+
+                // If download is complete and we've sent the 100% update, break the loop
+                if download_complete && total_progress >= 100.0 {
+                    break;
+                }
+
+                // A paused, cancelled or failed download won't make further progress
+                if download_stopped {
+                    break;
+                }
+            }
+
+            // Forget the run's progress, unless another run of the download replaced it already
+            let mut states = watcher_manager.states.lock().unwrap();
+            if states.get(&download_id).map(|state| Arc::ptr_eq(state, &watcher_state)) == Some(true) {
+                states.remove(&download_id);
+            }
+        });
+
+        Ok(())
+    }
+
+    /// Pauses a download, its part files stay so resuming continues where it left off
+    pub async fn pause(&self, download_id: u64) -> Result<(), SpeedyError> {
+        if let Ok(Some(download)) = db_manager::get_download(download_id).await {
+            self.check_not_elsewhere(&download)?;
+        }
+
+        // Stop the segment tasks, they flush their part files before exiting
+        if !registry::pause(download_id).await {
+            eprintln!("Download {} has no running task, only updating its status", download_id);
+        }
+
+        db_manager::update_status(download_id, DownloadStatus::Paused).await
+            .map_err(|e| SpeedyError::from(e).context("Failed to pause download"))
+    }
+
+    /// Puts a paused or failed download back in the queue
+    pub async fn resume(&self, download_id: u64) -> Result<(), SpeedyError> {
+        let download = match db_manager::get_download(download_id).await {
+            Ok(Some(download)) => download,
+            Ok(None) => return Err(SpeedyError::not_found("Download not found")),
+            Err(e) => return Err(SpeedyError::from(e).context("Error retrieving download")),
+        };
+
+        if download.status == DownloadStatus::Completed {
+            return Err(SpeedyError::invalid_input("Download is already completed"));
+        }
+
+        if registry::is_active(download_id).await {
+            eprintln!("Download {} is already running", download_id);
+            return Ok(());
+        }
+        self.claim(&download)?;
+
+        // Put the download back in the queue, it starts once a slot is free
+        if let Err(e) = queue::enqueue(download_id).await {
+            return Err(SpeedyError::from(e).context("Failed to update download status"));
+        }
+
+        self.process_queue(None).await;
+        Ok(())
+    }
+
+    /// Stops a download and removes its part files
+    pub async fn cancel(&self, download_id: u64) -> Result<(), SpeedyError> {
+        let download = match db_manager::get_download(download_id).await {
+            Ok(Some(download)) => download,
+            Ok(None) => return Err(SpeedyError::not_found("Download not found")),
+            Err(e) => return Err(SpeedyError::from(e).context("Error retrieving download")),
+        };
+        self.check_not_elsewhere(&download)?;
+
+        registry::cancel(download_id).await;
+
+        // The client removes its workspace when cancelled mid-download, but a paused
+        // or interrupted download still has its part files lying around
+        if let Some(download_workspace) = &download.workspace {
+            workspace::remove(Path::new(download_workspace)).await;
+        }
+        client::Client::remove_preallocated(&output_path(&download)).await;
+        if let Err(e) = db_manager::delete_manifest(download_id).await {
+            eprintln!("Failed to remove segment layout from database: {}", e);
+        }
+
+        db_manager::update_status(download_id, DownloadStatus::Cancelled).await
+            .map_err(|e| SpeedyError::from(e).context("Failed to cancel download"))
+    }
+
+    /// Removes a download from the database along with its workspace, and its saved
+    /// file too when `delete_file` is set. The download stays when the file can't be deleted.
+    pub async fn remove(&self, download_id: u64, delete_file: bool) -> Result<(), SpeedyError> {
+        let download = match db_manager::get_download(download_id).await {
+            Ok(Some(download)) => download,
+            Ok(None) if delete_file => return Err(SpeedyError::not_found("Download not found in database")),
+            Ok(None) => return self.delete_from_database(download_id, None).await,
+            Err(e) => return Err(SpeedyError::from(e).context("Error retrieving download")),
+        };
+        self.check_not_elsewhere(&download)?;
+
+        if delete_file {
+            if let Some(save_path) = download.save_path.as_deref().filter(|path| !path.is_empty()) {
+                match std::fs::remove_file(save_path) {
+                    Ok(_) => eprintln!("Successfully deleted file: {}", save_path),
+                    Err(e) => {
+                        eprintln!("Warning: Could not delete file at {}: {}", save_path, e);
+                        return Err(SpeedyError::from(e).context("File deletion failed, not removing from database"));
+                    }
+                }
+            } else {
+                eprintln!("No valid save path found, proceeding with database deletion");
+            }
+        }
+
+        self.delete_from_database(download_id, download.workspace).await
+    }
+
+    async fn delete_from_database(&self, download_id: u64, download_workspace: Option<String>) -> Result<(), SpeedyError> {
+        match db_manager::delete_download(download_id).await {
+            Ok(_) => {
+                eprintln!("Successfully deleted download: {}", download_id);
+                if let Some(download_workspace) = download_workspace {
+                    workspace::remove(Path::new(&download_workspace)).await;
+                }
+                Ok(())
+            },
+            Err(e) => {
+                eprintln!("Failed to delete download: {}", e);
+                Err(SpeedyError::from(e).context("Failed to delete download"))
+            },
+        }
+    }
+
+    /// Resolves a download stopped because its file exists by overwriting the file or
+    /// saving under another name, then puts the download back in the queue
+    pub async fn resolve_conflict(&self, download_id: u64, policy: ConflictPolicy) -> Result<(), SpeedyError> {
+        if !matches!(policy, ConflictPolicy::Overwrite | ConflictPolicy::Rename) {
+            return Err(SpeedyError::invalid_input(format!("Invalid policy: {}. Use overwrite or rename", policy)));
+        }
+
+        let mut download = match db_manager::get_download(download_id).await {
+            Ok(Some(download)) => download,
+            Ok(None) => return Err(SpeedyError::not_found("Download not found")),
+            Err(e) => return Err(SpeedyError::from(e).context("Error retrieving download")),
+        };
+        download.conflict_policy = Some(policy.to_string());
+        self.claim(&download)?;
+        if let Err(e) = db_manager::update_download(&download).await {
+            return Err(SpeedyError::from(e).context("Failed to save conflict policy"));
+        }
+
+        if let Err(e) = queue::enqueue(download_id).await {
+            return Err(SpeedyError::from(e).context("Failed to queue download"));
+        }
+        self.process_queue(None).await;
+        Ok(())
+    }
+
+    /// Sets how many downloads run at the same time, starting queued ones if the limit went up
+    pub async fn set_max_concurrent_downloads(&self, limit: u32) -> Result<(), SpeedyError> {
+        if let Err(e) = queue::set_max_concurrent_downloads(limit).await {
+            return Err(SpeedyError::from(e).context("Failed to save max concurrent downloads"));
+        }
+
+        // A higher limit may let queued downloads start right away
+        self.process_queue(None).await;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn adding_a_running_download_again_leaves_it_running() {
+        let db_path = std::env::temp_dir().join(format!("speedy-manager-test-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&db_path);
+        db_manager::init_db_at(Some(db_path.clone())).await;
+
+        let download_id = unused_download_id().await.unwrap();
+        let url = "http://127.0.0.1:9/running.bin".to_string();
+        let mut download = db::Download::new(download_id, url.clone(), "running.bin".to_string(), 100, 1);
+        download.status = DownloadStatus::Downloading;
+        db_manager::insert_download(&download).await.unwrap();
+
+        // Stands in for the download's task until it's told to stop
+        let client = client::Client::new(url.clone(), 1);
+        let control = client.control_handle();
+        registry::spawn(download_id, control.clone(), async move {
+            while client.stop_reason().is_none() {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        }).await.unwrap();
+
+        let mut request = DownloadRequest::new(url, 1);
+        request.name = Some("running.bin".to_string());
+        request.download_id = Some(download_id);
+        assert_eq!(DownloadManager::default().add(request).await.unwrap(), download_id);
+
+        let stored = db_manager::get_download(download_id).await.unwrap().unwrap();
+        assert_eq!(stored.status, DownloadStatus::Downloading);
+        assert!(registry::is_active(download_id).await);
+
+        control.cancel();
+        let _ = std::fs::remove_file(&db_path);
+    }
+}
@@ -0,0 +1,253 @@
+use crate::prealloc::BLOCK_SIZE;
+use serde::{Deserialize, Serialize};
+
+/// Byte range of a single segment and how much of it is on disk
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SegmentRange {
+    pub index: u64,         // 0-based index, also used for the part file name
+    pub start: u64,         // First byte of the range
+    pub end: u64,           // Last byte of the range (inclusive)
+    pub bytes_written: u64, // Bytes of this range written to its part file
+}
+
+impl SegmentRange {
+    /// Number of bytes covered by this segment
+    pub fn size(&self) -> u64 {
+        self.end - self.start + 1
+    }
+
+    /// 1-based segment ID reported to the frontend
+    pub fn segment_id(&self) -> u64 {
+        self.index + 1
+    }
+}
+
+/// The segment layout of a download, persisted so a resume reuses exactly
+/// the same ranges instead of recomputing them from the current settings
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DownloadManifest {
+    pub url: String,
+    pub file_size: u64,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    pub segments: Vec<SegmentRange>,
+    #[serde(default)]
+    pub preallocated: bool, // Segments write into the output file instead of part files
+}
+
+impl DownloadManifest {
+    /// Split a resource of `file_size` bytes into `parts` contiguous segments.
+    /// Preallocated layouts start every segment on a bitmap block boundary.
+    pub fn plan(url: &str, file_size: u64, parts: u64, etag: Option<String>, last_modified: Option<String>, preallocated: bool) -> Self {
+        let alignment = Self::alignment_for(preallocated);
+        let blocks = (file_size + alignment - 1) / alignment;
+
+        // Never create empty segments for tiny files
+        let parts = parts.clamp(1, blocks.max(1));
+        let chunk_size = (blocks / parts) * alignment;
+
+        let segments = (0..parts)
+            .map(|index| {
+                let start = index * chunk_size;
+                let end = if index == parts - 1 {
+                    file_size - 1
+                } else {
+                    start + chunk_size - 1
+                };
+                SegmentRange { index, start, end, bytes_written: 0 }
+            })
+            .collect();
+
+        Self {
+            url: url.to_string(),
+            file_size,
+            etag,
+            last_modified,
+            segments,
+            preallocated,
+        }
+    }
+
+    /// Every segment of this layout starts on a multiple of this many bytes
+    pub fn alignment(&self) -> u64 {
+        Self::alignment_for(self.preallocated)
+    }
+
+    fn alignment_for(preallocated: bool) -> u64 {
+        if preallocated { BLOCK_SIZE } else { 1 }
+    }
+
+    /// Number of segments in the layout
+    pub fn parts(&self) -> u64 {
+        self.segments.len() as u64
+    }
+
+    /// Checks that the manifest still describes the resource the server is serving now.
+    /// Validators are only compared when both sides have them.
+    pub fn matches_resource(&self, url: &str, file_size: u64, etag: Option<&str>, last_modified: Option<&str>) -> bool {
+        if self.url != url || self.file_size != file_size {
+            return false;
+        }
+
+        if let (Some(ours), Some(theirs)) = (self.etag.as_deref(), etag) {
+            // A weak and a strong ETag for the same content differ only by the W/ prefix
+            if ours.trim_start_matches("W/") != theirs.trim_start_matches("W/") {
+                return false;
+            }
+        }
+
+        if let (Some(ours), Some(theirs)) = (self.last_modified.as_deref(), last_modified) {
+            if ours != theirs {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    /// Checks that the segments are contiguous, cover the whole file and are indexed
+    /// 0..parts. Segments split off during a download are appended, so the index order
+    /// doesn't have to match the byte order.
+    pub fn is_consistent(&self) -> bool {
+        let mut indices: Vec<u64> = self.segments.iter().map(|segment| segment.index).collect();
+        indices.sort_unstable();
+        if indices.iter().enumerate().any(|(position, &index)| index != position as u64) {
+            return false;
+        }
+
+        let mut expected_start = 0;
+        for segment in self.sorted_segments() {
+            if segment.start != expected_start
+                || segment.end < segment.start
+                || segment.bytes_written > segment.size()
+            {
+                return false;
+            }
+            expected_start = segment.end + 1;
+        }
+
+        !self.segments.is_empty() && expected_start == self.file_size
+    }
+
+    /// Segments in the order their ranges appear in the file
+    pub fn sorted_segments(&self) -> Vec<&SegmentRange> {
+        let mut segments: Vec<&SegmentRange> = self.segments.iter().collect();
+        segments.sort_by_key(|segment| segment.start);
+        segments
+    }
+
+    /// Part indices with the size each part file must have, in the order their
+    /// contents have to be merged
+    pub fn merge_order(&self) -> Vec<(u64, Option<u64>)> {
+        self.sorted_segments().iter().map(|segment| (segment.index, Some(segment.size()))).collect()
+    }
+
+    /// Total bytes written across all segments
+    pub fn bytes_written(&self) -> u64 {
+        self.segments.iter().map(|segment| segment.bytes_written).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const URL: &str = "https://example.com/file.bin";
+
+    /// Index, first and last byte of each segment
+    type Segments = [(u64, u64, u64)];
+
+    fn ranges(manifest: &DownloadManifest) -> Vec<(u64, u64)> {
+        manifest.segments.iter().map(|segment| (segment.start, segment.end)).collect()
+    }
+
+    fn layout(segments: &Segments, file_size: u64) -> DownloadManifest {
+        let mut manifest = DownloadManifest::plan(URL, file_size, 1, None, None, false);
+        manifest.segments = segments
+            .iter()
+            .map(|&(index, start, end)| SegmentRange { index, start, end, bytes_written: 0 })
+            .collect();
+        manifest
+    }
+
+    #[test]
+    fn plans_equal_segments() {
+        let manifest = DownloadManifest::plan(URL, 100, 4, None, None, false);
+        assert_eq!(ranges(&manifest), vec![(0, 24), (25, 49), (50, 74), (75, 99)]);
+        assert!(manifest.is_consistent());
+    }
+
+    #[test]
+    fn last_segment_takes_the_remainder() {
+        let manifest = DownloadManifest::plan(URL, 10, 3, None, None, false);
+        assert_eq!(ranges(&manifest), vec![(0, 2), (3, 5), (6, 9)]);
+        assert!(manifest.is_consistent());
+    }
+
+    #[test]
+    fn never_plans_more_segments_than_bytes() {
+        let manifest = DownloadManifest::plan(URL, 3, 8, None, None, false);
+        assert_eq!(ranges(&manifest), vec![(0, 0), (1, 1), (2, 2)]);
+
+        let manifest = DownloadManifest::plan(URL, 10, 0, None, None, false);
+        assert_eq!(ranges(&manifest), vec![(0, 9)]);
+    }
+
+    #[test]
+    fn preallocated_segments_start_on_block_boundaries() {
+        let file_size = 3 * BLOCK_SIZE + 10;
+        let manifest = DownloadManifest::plan(URL, file_size, 2, None, None, true);
+        assert_eq!(ranges(&manifest), vec![(0, 2 * BLOCK_SIZE - 1), (2 * BLOCK_SIZE, file_size - 1)]);
+        assert_eq!(manifest.alignment(), BLOCK_SIZE);
+        assert!(manifest.is_consistent());
+
+        // A file within a single block can't be split
+        let manifest = DownloadManifest::plan(URL, BLOCK_SIZE - 1, 4, None, None, true);
+        assert_eq!(ranges(&manifest), vec![(0, BLOCK_SIZE - 2)]);
+    }
+
+    #[test]
+    fn accepts_split_segments_out_of_index_order() {
+        let manifest = layout(&[(0, 0, 49), (2, 50, 74), (1, 75, 99)], 100);
+        assert!(manifest.is_consistent());
+        assert_eq!(manifest.merge_order(), vec![(0, Some(50)), (2, Some(25)), (1, Some(25))]);
+    }
+
+    #[test]
+    fn rejects_inconsistent_layouts() {
+        let cases: &[(&str, &Segments)] = &[
+            ("no segments", &[]),
+            ("gap", &[(0, 0, 49), (1, 51, 99)]),
+            ("overlap", &[(0, 0, 50), (1, 50, 99)]),
+            ("not starting at 0", &[(0, 1, 99)]),
+            ("short of the end", &[(0, 0, 49), (1, 50, 98)]),
+            ("past the end", &[(0, 0, 49), (1, 50, 100)]),
+            ("end before start", &[(0, 0, 99), (1, 100, 50)]),
+            ("skipped index", &[(0, 0, 49), (2, 50, 99)]),
+            ("duplicate index", &[(0, 0, 49), (0, 50, 99)]),
+        ];
+        for (name, segments) in cases {
+            assert!(!layout(segments, 100).is_consistent(), "{}", name);
+        }
+
+        let mut overwritten = layout(&[(0, 0, 49), (1, 50, 99)], 100);
+        overwritten.segments[1].bytes_written = 51;
+        assert!(!overwritten.is_consistent());
+    }
+
+    #[test]
+    fn matches_only_the_same_resource() {
+        let manifest = DownloadManifest::plan(URL, 100, 2, Some("\"v1\"".to_string()),
+                                              Some("Mon, 01 Jan 2024 00:00:00 GMT".to_string()), false);
+        let modified = Some("Mon, 01 Jan 2024 00:00:00 GMT");
+        assert!(manifest.matches_resource(URL, 100, Some("\"v1\""), modified));
+        assert!(manifest.matches_resource(URL, 100, Some("W/\"v1\""), modified));
+        // Validators the server doesn't send anymore can't be compared
+        assert!(manifest.matches_resource(URL, 100, None, None));
+
+        assert!(!manifest.matches_resource("https://example.com/other.bin", 100, Some("\"v1\""), modified));
+        assert!(!manifest.matches_resource(URL, 101, Some("\"v1\""), modified));
+        assert!(!manifest.matches_resource(URL, 100, Some("\"v2\""), modified));
+        assert!(!manifest.matches_resource(URL, 100, Some("\"v1\""), Some("Tue, 02 Jan 2024 00:00:00 GMT")));
+    }
+}
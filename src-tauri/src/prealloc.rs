@@ -0,0 +1,272 @@
+use crate::db_manager;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+/// Settings key for writing segments straight into a preallocated output file
+const PREALLOCATE_KEY: &str = "preallocate_files";
+
+/// Granularity of the bitmap. Preallocated segments are aligned to it, so every
+/// block belongs to exactly one segment.
+pub const BLOCK_SIZE: u64 = 64 * 1024;
+
+/// Identifies a sidecar file written by us, followed by the format version
+const MAGIC: &[u8; 8] = b"SPDYMAP1";
+
+/// Header layout: magic, block size and file size as little endian u64
+const HEADER_LEN: usize = 24;
+
+/// Minimum time between two saves while the download is running
+const SAVE_INTERVAL: Duration = Duration::from_secs(1);
+
+pub type SharedBitmap = Arc<Mutex<ProgressBitmap>>;
+
+/// Tracks which blocks of a preallocated output file hold downloaded data.
+/// Saved next to the output file so an interrupted download knows where to resume.
+#[derive(Debug)]
+pub struct ProgressBitmap {
+    path: PathBuf,
+    file_size: u64,
+    block_size: u64,
+    bits: Vec<u8>,
+    last_save: Option<Instant>,
+}
+
+impl ProgressBitmap {
+    /// Path of the sidecar file kept next to `output_path`
+    pub fn sidecar_path(output_path: &Path) -> PathBuf {
+        let mut file_name = output_path.file_name().unwrap_or_default().to_os_string();
+        file_name.push(".speedy");
+        output_path.with_file_name(file_name)
+    }
+
+    /// Creates an empty bitmap for an output file of `file_size` bytes
+    pub fn new(output_path: &Path, file_size: u64) -> Self {
+        let blocks = (file_size + BLOCK_SIZE - 1) / BLOCK_SIZE;
+        Self {
+            path: Self::sidecar_path(output_path),
+            file_size,
+            block_size: BLOCK_SIZE,
+            bits: vec![0; ((blocks + 7) / 8) as usize],
+            last_save: None,
+        }
+    }
+
+    /// Loads the bitmap saved for `output_path`. Returns None if it doesn't exist
+    /// or doesn't describe a file of `file_size` bytes.
+    pub async fn load(output_path: &Path, file_size: u64) -> Option<Self> {
+        let path = Self::sidecar_path(output_path);
+        let data = tokio::fs::read(&path).await.ok()?;
+        if data.len() < HEADER_LEN || &data[..8] != MAGIC {
+            return None;
+        }
+
+        let block_size = u64::from_le_bytes(data[8..16].try_into().ok()?);
+        let saved_size = u64::from_le_bytes(data[16..24].try_into().ok()?);
+        let mut bitmap = Self::new(output_path, file_size);
+        if block_size != bitmap.block_size || saved_size != file_size || data.len() - HEADER_LEN != bitmap.bits.len() {
+            return None;
+        }
+
+        bitmap.bits.copy_from_slice(&data[HEADER_LEN..]);
+        Some(bitmap)
+    }
+
+    /// Number of blocks in the file
+    fn blocks(&self) -> u64 {
+        (self.file_size + self.block_size - 1) / self.block_size
+    }
+
+    fn is_set(&self, block: u64) -> bool {
+        self.bits[(block / 8) as usize] & (1 << (block % 8)) != 0
+    }
+
+    fn set(&mut self, block: u64) {
+        self.bits[(block / 8) as usize] |= 1 << (block % 8);
+    }
+
+    /// Marks the blocks fully covered by the bytes `from..to` as downloaded.
+    /// The last block of the file counts as covered once `to` reaches the end.
+    pub fn mark_written(&mut self, from: u64, to: u64) {
+        let first = (from + self.block_size - 1) / self.block_size;
+        let last = if to >= self.file_size { self.blocks() } else { to / self.block_size };
+        for block in first..last {
+            self.set(block);
+        }
+    }
+
+    /// Number of bytes downloaded from `start` onwards without a gap, at most up to `end` (inclusive)
+    pub fn contiguous_from(&self, start: u64, end: u64) -> u64 {
+        let mut position = start;
+        while position <= end {
+            let block = position / self.block_size;
+            if !self.is_set(block) {
+                break;
+            }
+            position = ((block + 1) * self.block_size).min(end + 1);
+        }
+        position - start
+    }
+
+    /// Number of bytes in blocks that haven't been downloaded yet
+    pub fn missing_bytes(&self) -> u64 {
+        (0..self.blocks())
+            .filter(|&block| !self.is_set(block))
+            .map(|block| self.block_size.min(self.file_size - block * self.block_size))
+            .sum()
+    }
+
+    /// Whether every block of the file has been downloaded
+    pub fn is_complete(&self) -> bool {
+        (0..self.blocks()).all(|block| self.is_set(block))
+    }
+
+    /// Writes the bitmap to its sidecar file, replacing the previous version atomically
+    pub async fn save(&mut self) -> std::io::Result<()> {
+        let mut data = Vec::with_capacity(HEADER_LEN + self.bits.len());
+        data.extend_from_slice(MAGIC);
+        data.extend_from_slice(&self.block_size.to_le_bytes());
+        data.extend_from_slice(&self.file_size.to_le_bytes());
+        data.extend_from_slice(&self.bits);
+
+        let mut temp_name = self.path.file_name().unwrap_or_default().to_os_string();
+        temp_name.push(".tmp");
+        let temp_path = self.path.with_file_name(temp_name);
+        tokio::fs::write(&temp_path, &data).await?;
+        tokio::fs::rename(&temp_path, &self.path).await?;
+
+        self.last_save = Some(Instant::now());
+        Ok(())
+    }
+
+    /// Saves the bitmap unless it was saved less than a second ago
+    pub async fn save_if_due(&mut self) -> std::io::Result<()> {
+        match self.last_save {
+            Some(last_save) if last_save.elapsed() < SAVE_INTERVAL => Ok(()),
+            _ => self.save().await,
+        }
+    }
+
+    /// Removes the sidecar file, once the download is complete or abandoned
+    pub async fn remove(output_path: &Path) {
+        let path = Self::sidecar_path(output_path);
+        match tokio::fs::remove_file(&path).await {
+            Ok(_) => eprintln!("Removed progress bitmap: {}", path.display()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {},
+            Err(e) => eprintln!("Error removing progress bitmap {}: {}", path.display(), e),
+        }
+    }
+}
+
+/// Whether new downloads write into a preallocated output file instead of part files
+pub async fn preallocate_files() -> bool {
+    match db_manager::get_setting(PREALLOCATE_KEY).await {
+        Ok(Some(value)) => value == "true",
+        Ok(None) => false,
+        Err(e) => {
+            eprintln!("Failed to read {} setting: {}", PREALLOCATE_KEY, e);
+            false
+        }
+    }
+}
+
+/// Choose between preallocated output files and part files for new downloads.
+/// Downloads keep the mode they were started with when resumed.
+pub async fn set_preallocate_files(enabled: bool) -> rusqlite::Result<()> {
+    db_manager::set_setting(PREALLOCATE_KEY, if enabled { "true" } else { "false" }).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const B: u64 = BLOCK_SIZE;
+
+    // Two full blocks and a last block of 100 bytes
+    const FILE_SIZE: u64 = 2 * B + 100;
+
+    fn bitmap() -> ProgressBitmap {
+        ProgressBitmap::new(Path::new("/tmp/file.bin"), FILE_SIZE)
+    }
+
+    #[test]
+    fn names_the_sidecar_after_the_output_file() {
+        assert_eq!(ProgressBitmap::sidecar_path(Path::new("/downloads/file.bin")), PathBuf::from("/downloads/file.bin.speedy"));
+    }
+
+    #[test]
+    fn marks_only_fully_written_blocks() {
+        let mut bitmap = bitmap();
+        // Partial first and last blocks of the range don't count
+        bitmap.mark_written(10, 2 * B - 1);
+        assert!(!bitmap.is_set(0));
+        assert!(!bitmap.is_set(1));
+
+        bitmap.mark_written(B, 2 * B);
+        assert!(bitmap.is_set(1));
+        assert!(!bitmap.is_set(0));
+        assert_eq!(bitmap.missing_bytes(), B + 100);
+    }
+
+    #[test]
+    fn short_last_block_counts_once_the_file_end_is_reached() {
+        let mut bitmap = bitmap();
+        bitmap.mark_written(2 * B, 2 * B + 99);
+        assert!(!bitmap.is_set(2));
+
+        bitmap.mark_written(2 * B, FILE_SIZE);
+        assert!(bitmap.is_set(2));
+        assert_eq!(bitmap.missing_bytes(), 2 * B);
+
+        bitmap.mark_written(0, 2 * B);
+        assert_eq!(bitmap.missing_bytes(), 0);
+        assert!(bitmap.is_complete());
+    }
+
+    #[test]
+    fn counts_contiguous_bytes_up_to_the_range_end() {
+        let mut bitmap = bitmap();
+        assert_eq!(bitmap.contiguous_from(0, FILE_SIZE - 1), 0);
+
+        bitmap.mark_written(0, B);
+        bitmap.mark_written(2 * B, FILE_SIZE);
+        // Block 1 is a gap
+        assert_eq!(bitmap.contiguous_from(0, FILE_SIZE - 1), B);
+        assert_eq!(bitmap.contiguous_from(2 * B, FILE_SIZE - 1), 100);
+        // A range ending inside a written block stops at its end
+        assert_eq!(bitmap.contiguous_from(0, 99), 100);
+
+        bitmap.mark_written(B, 2 * B);
+        assert_eq!(bitmap.contiguous_from(0, FILE_SIZE - 1), FILE_SIZE);
+    }
+
+    #[tokio::test]
+    async fn saves_and_loads_the_sidecar() {
+        let dir = std::env::temp_dir().join(format!("speedy-prealloc-test-{}", std::process::id()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        let output_path = dir.join("file.bin");
+
+        let mut bitmap = ProgressBitmap::new(&output_path, FILE_SIZE);
+        bitmap.mark_written(0, B);
+        bitmap.mark_written(2 * B, FILE_SIZE);
+        bitmap.save().await.unwrap();
+
+        let loaded = ProgressBitmap::load(&output_path, FILE_SIZE).await.unwrap();
+        assert_eq!(loaded.bits, bitmap.bits);
+        assert_eq!(loaded.missing_bytes(), B);
+
+        // A sidecar for a file of another size describes other blocks
+        assert!(ProgressBitmap::load(&output_path, FILE_SIZE + B).await.is_none());
+
+        let sidecar = ProgressBitmap::sidecar_path(&output_path);
+        let mut data = tokio::fs::read(&sidecar).await.unwrap();
+        data[0] = b'X';
+        tokio::fs::write(&sidecar, &data).await.unwrap();
+        assert!(ProgressBitmap::load(&output_path, FILE_SIZE).await.is_none());
+
+        ProgressBitmap::remove(&output_path).await;
+        assert!(ProgressBitmap::load(&output_path, FILE_SIZE).await.is_none());
+        let _ = tokio::fs::remove_dir_all(&dir).await;
+    }
+}
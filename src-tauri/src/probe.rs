@@ -0,0 +1,279 @@
+use reqwest::header::{HeaderMap, ACCEPT_RANGES, CONTENT_DISPOSITION, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, ETAG, LAST_MODIFIED, RANGE};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr};
+use specta::Type;
+use crate::filename;
+use crate::error::SpeedyError;
+
+// What we learned about a resource before downloading it
+#[serde_as]
+#[derive(Clone, Debug, Default, Serialize, Deserialize, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct ProbeResult {
+    pub final_url: String,             // URL after following redirects
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    pub total_size: Option<u64>,       // None when the server doesn't tell us
+    pub supports_ranges: bool,         // Whether range requests are honored
+    pub filename: Option<String>,      // Filename suggested by Content-Disposition
+    pub content_type: Option<String>,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+impl ProbeResult {
+    // Fill in whatever the headers of a response tell us that we don't know yet
+    fn merge_headers(&mut self, headers: &HeaderMap) {
+        if self.filename.is_none() {
+            self.filename = header_to_string(headers, CONTENT_DISPOSITION.as_str())
+                .and_then(|value| content_disposition_filename(&value));
+        }
+        if self.content_type.is_none() {
+            self.content_type = header_to_string(headers, CONTENT_TYPE.as_str());
+        }
+        if self.etag.is_none() {
+            self.etag = header_to_string(headers, ETAG.as_str());
+        }
+        if self.last_modified.is_none() {
+            self.last_modified = header_to_string(headers, LAST_MODIFIED.as_str());
+        }
+    }
+}
+
+/// Find out the size, range support and metadata of a resource without downloading it.
+///
+/// Tries `HEAD` first. If that fails or leaves range support unclear, falls back to
+/// `GET` with `Range: bytes=0-0`, which costs a single byte on servers that honor it.
+pub async fn probe(client: &reqwest::Client, url: &str) -> Result<ProbeResult, Box<dyn std::error::Error + Send + Sync>> {
+    let mut result = ProbeResult {
+        final_url: url.to_string(),
+        ..Default::default()
+    };
+
+    match client.head(url).send().await {
+        Ok(response) if response.status().is_success() => {
+            let headers = response.headers();
+            result.final_url = response.url().to_string();
+            result.total_size = header_to_u64(headers, CONTENT_LENGTH.as_str()).filter(|&size| size > 0);
+            result.supports_ranges = header_to_string(headers, ACCEPT_RANGES.as_str())
+                .map(|value| value.to_ascii_lowercase().contains("bytes"))
+                .unwrap_or(false);
+            result.merge_headers(headers);
+
+            if result.supports_ranges && result.total_size.is_some() {
+                return Ok(result);
+            }
+        },
+        Ok(response) => eprintln!("HEAD {} returned {}, probing with a range request", url, response.status()),
+        Err(e) => eprintln!("HEAD {} failed ({}), probing with a range request", url, e),
+    }
+
+    let response = client.get(url)
+        .header(RANGE, "bytes=0-0")
+        .send()
+        .await
+        .map_err(|e| SpeedyError::Network { message: format!("Failed to connect to URL: {}. Error: {}", url, e) })?;
+
+    let status = response.status();
+    let headers = response.headers().clone();
+    result.final_url = response.url().to_string();
+    // Dropping the response without reading the body stops a server that ignored the range
+    drop(response);
+
+    match status {
+        StatusCode::PARTIAL_CONTENT => {
+            result.supports_ranges = true;
+            if let Some(total) = header_to_string(&headers, CONTENT_RANGE.as_str()).and_then(|value| content_range_total(&value)) {
+                result.total_size = Some(total);
+            }
+        },
+        StatusCode::RANGE_NOT_SATISFIABLE => {
+            // Only an empty resource can't satisfy a range starting at 0
+            result.supports_ranges = true;
+            result.total_size = header_to_string(&headers, CONTENT_RANGE.as_str())
+                .and_then(|value| content_range_total(&value))
+                .or(Some(0));
+        },
+        status if status.is_success() => {
+            // The server sent the whole body, so ranges aren't supported
+            result.supports_ranges = false;
+            if result.total_size.is_none() {
+                result.total_size = header_to_u64(&headers, CONTENT_LENGTH.as_str());
+            }
+        },
+        status => {
+            return Err(SpeedyError::HttpStatus {
+                status: status.as_u16(),
+                message: format!("Server returned error status {} for URL: {}", status, url),
+            }.into());
+        }
+    }
+    result.merge_headers(&headers);
+
+    Ok(result)
+}
+
+/// Read a header as a string, ignoring values that aren't valid text
+pub fn header_to_string(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_string())
+}
+
+fn header_to_u64(headers: &HeaderMap, name: &str) -> Option<u64> {
+    header_to_string(headers, name).and_then(|value| value.trim().parse().ok())
+}
+
+/// Parse the complete length out of `Content-Range: bytes 0-0/12345`.
+/// Returns None when the length is unknown (`*`).
+pub fn content_range_total(value: &str) -> Option<u64> {
+    value.rsplit('/').next().and_then(|total| total.trim().parse().ok())
+}
+
+/// Extract the filename of a Content-Disposition header. The RFC 5987 encoded
+/// `filename*` parameter wins over the plain `filename` when both are present.
+/// The result may still contain path separators, so sanitize it before use.
+pub fn content_disposition_filename(value: &str) -> Option<String> {
+    let mut plain = None;
+    let mut extended = None;
+    for param in split_params(value) {
+        let (name, value) = match param.split_once('=') {
+            Some(pair) => pair,
+            None => continue,
+        };
+        let name = name.trim();
+        let value = value.trim();
+        // An empty value doesn't stop the other form from being used
+        if name.eq_ignore_ascii_case("filename*") {
+            extended = extended.or_else(|| decode_ext_value(value).filter(|name| !name.trim().is_empty()));
+        } else if name.eq_ignore_ascii_case("filename") {
+            plain = plain.or_else(|| Some(unquote(value)).filter(|name| !name.trim().is_empty()));
+        }
+    }
+    extended.or(plain)
+}
+
+/// Split header parameters on `;`, except inside quoted strings
+fn split_params(value: &str) -> Vec<&str> {
+    let mut params = Vec::new();
+    let mut in_quotes = false;
+    let mut escaped = false;
+    let mut start = 0;
+    for (i, c) in value.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_quotes => escaped = true,
+            '"' => in_quotes = !in_quotes,
+            ';' if !in_quotes => {
+                params.push(value[start..i].trim());
+                start = i + 1;
+            },
+            _ => {},
+        }
+    }
+    params.push(value[start..].trim());
+    params
+}
+
+/// Strip the quotes and backslash escapes of a quoted string
+fn unquote(value: &str) -> String {
+    match value.strip_prefix('"').and_then(|value| value.strip_suffix('"')) {
+        Some(inner) => {
+            let mut unquoted = String::with_capacity(inner.len());
+            let mut chars = inner.chars();
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => unquoted.extend(chars.next()),
+                    c => unquoted.push(c),
+                }
+            }
+            unquoted
+        },
+        None => value.to_string(),
+    }
+}
+
+/// Decode an RFC 5987 `charset'language'percent-encoded` value.
+/// Only UTF-8 and ISO-8859-1 are required to be supported.
+fn decode_ext_value(value: &str) -> Option<String> {
+    let value = unquote(value);
+    let mut parts = value.splitn(3, '\'');
+    let charset = parts.next()?;
+    let _language = parts.next()?;
+    let encoded = parts.next()?;
+    let bytes = filename::percent_decode(encoded);
+    if charset.eq_ignore_ascii_case("utf-8") {
+        String::from_utf8(bytes).ok()
+    } else if charset.eq_ignore_ascii_case("iso-8859-1") {
+        Some(bytes.iter().map(|&byte| byte as char).collect())
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_plain_filename() {
+        assert_eq!(content_disposition_filename("attachment; filename=report.pdf").as_deref(), Some("report.pdf"));
+        assert_eq!(content_disposition_filename("attachment; filename=\"annual report.pdf\"").as_deref(), Some("annual report.pdf"));
+        assert_eq!(content_disposition_filename("attachment;FILENAME = \"a.txt\" ").as_deref(), Some("a.txt"));
+        assert_eq!(content_disposition_filename("inline"), None);
+    }
+
+    #[test]
+    fn extended_filename_wins_over_plain() {
+        let value = "attachment; filename=\"fallback.txt\"; filename*=UTF-8''na%C3%AFve%20r%C3%A9sum%C3%A9.txt";
+        assert_eq!(content_disposition_filename(value).as_deref(), Some("na\u{ef}ve r\u{e9}sum\u{e9}.txt"));
+        // Whichever order they come in
+        let value = "attachment; filename*=utf-8'en'%E2%82%AC%20rates.csv; filename=rates.csv";
+        assert_eq!(content_disposition_filename(value).as_deref(), Some("\u{20ac} rates.csv"));
+    }
+
+    #[test]
+    fn decodes_iso_8859_1() {
+        let value = "attachment; filename*=ISO-8859-1''%A3%20and%20%E9t%E9.txt";
+        assert_eq!(content_disposition_filename(value).as_deref(), Some("\u{a3} and \u{e9}t\u{e9}.txt"));
+    }
+
+    #[test]
+    fn falls_back_to_plain_filename_when_extended_is_unusable() {
+        // Unsupported charset, invalid UTF-8 and a value without the charset'language' prefix
+        for extended in ["KOI8-R''%F0%D2%C9", "UTF-8''%FF%FE", "%41.txt"] {
+            let value = format!("attachment; filename*={}; filename=plain.txt", extended);
+            assert_eq!(content_disposition_filename(&value).as_deref(), Some("plain.txt"), "{}", extended);
+        }
+    }
+
+    #[test]
+    fn unescapes_quoted_filename() {
+        let value = r#"attachment; filename="say \"hi\" \\ bye.txt""#;
+        assert_eq!(content_disposition_filename(value).as_deref(), Some(r#"say "hi" \ bye.txt"#));
+    }
+
+    #[test]
+    fn keeps_semicolons_inside_quotes() {
+        let value = r#"attachment; filename="part; one \"; two\".txt"; size=10"#;
+        assert_eq!(content_disposition_filename(value).as_deref(), Some(r#"part; one "; two".txt"#));
+        assert_eq!(split_params(r#"a; b="x;y"; c"#), vec!["a", r#"b="x;y""#, "c"]);
+    }
+
+    #[test]
+    fn ignores_empty_values() {
+        assert_eq!(content_disposition_filename("attachment; filename="), None);
+        assert_eq!(content_disposition_filename("attachment; filename=\"  \""), None);
+        assert_eq!(content_disposition_filename("attachment; filename*=UTF-8''"), None);
+        assert_eq!(content_disposition_filename("attachment; filename*=UTF-8''; filename=a.txt").as_deref(), Some("a.txt"));
+        assert_eq!(content_disposition_filename("attachment; filename=\"\"; filename*=UTF-8''b.txt").as_deref(), Some("b.txt"));
+    }
+
+    #[test]
+    fn reads_content_range_total() {
+        assert_eq!(content_range_total("bytes 0-0/1234"), Some(1234));
+        assert_eq!(content_range_total("bytes */1234"), Some(1234));
+        assert_eq!(content_range_total("bytes 0-0/*"), None);
+    }
+}
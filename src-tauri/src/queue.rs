@@ -0,0 +1,104 @@
+use crate::db::Download;
+use crate::db_manager::{self, StatusError};
+use crate::error::SpeedyError;
+use crate::registry;
+use crate::status::DownloadStatus;
+use std::future::Future;
+use tokio::sync::Mutex;
+
+/// Settings key for the maximum number of downloads running at once
+const MAX_CONCURRENT_KEY: &str = "max_concurrent_downloads";
+
+/// Used when the setting hasn't been stored yet
+pub const DEFAULT_MAX_CONCURRENT: u32 = 3;
+
+// Serializes promotions so two finishing downloads can't both fill the same slot
+static PROMOTE_LOCK: Mutex<()> = Mutex::const_new(());
+
+/// Get the maximum number of downloads allowed to run at the same time
+pub async fn max_concurrent_downloads() -> u32 {
+    match db_manager::get_setting(MAX_CONCURRENT_KEY).await {
+        Ok(Some(value)) => value.parse().unwrap_or(DEFAULT_MAX_CONCURRENT),
+        Ok(None) => DEFAULT_MAX_CONCURRENT,
+        Err(e) => {
+            eprintln!("Failed to read {} setting: {}", MAX_CONCURRENT_KEY, e);
+            DEFAULT_MAX_CONCURRENT
+        }
+    }
+}
+
+/// Set the maximum number of downloads allowed to run at the same time (at least 1)
+pub async fn set_max_concurrent_downloads(limit: u32) -> rusqlite::Result<()> {
+    db_manager::set_setting(MAX_CONCURRENT_KEY, &limit.max(1).to_string()).await
+}
+
+/// Put a download at the back of the queue
+pub async fn enqueue(download_id: u64) -> Result<(), StatusError> {
+    let position = db_manager::next_queue_position().await?;
+    if let Some(mut download) = db_manager::get_download(download_id).await? {
+        download.status = DownloadStatus::Queued;
+        download.queue_position = position;
+        download.error_message = None;
+        download.error_code = None;
+        db_manager::update_download(&download).await?;
+    }
+    Ok(())
+}
+
+/// Move a queued download to the given 0-based position among queued downloads
+pub async fn move_to(download_id: u64, position: usize) -> Result<(), SpeedyError> {
+    let queued = db_manager::get_queued_downloads().await
+        .map_err(|e| SpeedyError::from(e).context("Failed to read queue"))?;
+
+    let mut order: Vec<u64> = queued.iter().map(|download| download.download_id).collect();
+    let current = order.iter().position(|&id| id == download_id)
+        .ok_or_else(|| SpeedyError::invalid_input(format!("Download {} is not queued", download_id)))?;
+
+    let download_id = order.remove(current);
+    order.insert(position.min(order.len()), download_id);
+
+    db_manager::reorder_queue(&order).await
+        .map_err(|e| SpeedyError::from(e).context("Failed to reorder queue"))
+}
+
+/// Start queued downloads until the concurrency limit is reached.
+///
+/// `finishing` is a download whose task is about to exit and should not be
+/// counted as running, since it calls this right before unregistering.
+/// Only downloads `owns` accepts are started, the rest stay queued.
+pub async fn promote<O, F, Fut>(finishing: Option<u64>, owns: O, start: F)
+where
+    O: Fn(u64) -> bool,
+    F: Fn(Download) -> Fut,
+    Fut: Future<Output = Result<(), SpeedyError>>,
+{
+    let _guard = PROMOTE_LOCK.lock().await;
+
+    let limit = max_concurrent_downloads().await as usize;
+    let running = registry::active_ids().await
+        .into_iter()
+        .filter(|&download_id| Some(download_id) != finishing)
+        .count();
+    if running >= limit {
+        return;
+    }
+
+    let queued = match db_manager::get_queued_downloads().await {
+        Ok(queued) => queued,
+        Err(e) => {
+            eprintln!("Failed to read download queue: {}", e);
+            return;
+        }
+    };
+
+    for download in queued.into_iter().filter(|download| owns(download.download_id)).take(limit - running) {
+        let download_id = download.download_id;
+        eprintln!("Starting queued download: {}", download_id);
+        if let Err(e) = start(download).await {
+            eprintln!("Failed to start queued download {}: {}", download_id, e);
+            if let Err(db_err) = db_manager::mark_error(download_id, &e).await {
+                eprintln!("Failed to update database with error: {}", db_err);
+            }
+        }
+    }
+}
@@ -0,0 +1,101 @@
+use crate::client::Client;
+use crate::db::Download;
+use crate::db_manager;
+use crate::prealloc::ProgressBitmap;
+use crate::queue;
+use crate::status::DownloadStatus;
+use std::path::PathBuf;
+
+/// Settings key for whether downloads interrupted by quitting resume on startup
+const RESUME_ON_STARTUP_KEY: &str = "resume_on_startup";
+
+/// Used when the setting hasn't been stored yet: interrupted downloads are paused
+pub const DEFAULT_RESUME_ON_STARTUP: bool = false;
+
+/// Get whether downloads interrupted by quitting are put back in the queue on startup
+pub async fn resume_on_startup() -> bool {
+    match db_manager::get_setting(RESUME_ON_STARTUP_KEY).await {
+        Ok(Some(value)) => value == "true",
+        Ok(None) => DEFAULT_RESUME_ON_STARTUP,
+        Err(e) => {
+            eprintln!("Failed to read {} setting: {}", RESUME_ON_STARTUP_KEY, e);
+            DEFAULT_RESUME_ON_STARTUP
+        }
+    }
+}
+
+/// Set whether downloads interrupted by quitting are put back in the queue on startup
+pub async fn set_resume_on_startup(enabled: bool) -> rusqlite::Result<()> {
+    db_manager::set_setting(RESUME_ON_STARTUP_KEY, if enabled { "true" } else { "false" }).await
+}
+
+/// Bring downloads the app was running when it last quit back to a state it can
+/// act on. Their progress is set to the bytes actually on disk, then they are
+/// paused or put back in the queue depending on the setting.
+/// Run before any download starts, since an active status at this point means
+/// the app quit before the download finished.
+pub async fn reconcile() {
+    let resume = resume_on_startup().await;
+    let downloads = match db_manager::list_downloads().await {
+        Ok(downloads) => downloads,
+        Err(e) => {
+            eprintln!("Failed to read downloads: {}", e);
+            return;
+        }
+    };
+
+    for mut download in downloads.into_iter().filter(|download| download.status.is_active()) {
+        let download_id = download.download_id;
+        download.downloaded_bytes = bytes_on_disk(&download).await;
+        if let Err(e) = db_manager::update_download(&download).await {
+            eprintln!("Failed to update progress of download {}: {}", download_id, e);
+        }
+
+        let result = if resume {
+            queue::enqueue(download_id).await
+        } else {
+            db_manager::update_status(download_id, DownloadStatus::Paused).await
+        };
+        match result {
+            Ok(_) => eprintln!("Restored interrupted download {} ({} bytes on disk, {})",
+                              download_id, download.downloaded_bytes, if resume { "queued" } else { "paused" }),
+            Err(e) => eprintln!("Failed to restore interrupted download {}: {}", download_id, e),
+        }
+    }
+}
+
+/// Bytes of a download that a resume won't have to fetch again: what its part
+/// files hold, or what the progress bitmap of a preallocated file marks as written
+async fn bytes_on_disk(download: &Download) -> u64 {
+    let manifest = match db_manager::get_manifest(download.download_id).await {
+        Ok(Some(manifest)) => manifest,
+        // Without a layout the download starts over anyway
+        _ => return 0,
+    };
+
+    if manifest.preallocated {
+        let output_dir = match &download.directory {
+            Some(directory) => PathBuf::from(directory),
+            None => Client::default_output_dir(),
+        };
+        return match ProgressBitmap::load(&output_dir.join(&download.filename), manifest.file_size).await {
+            Some(bitmap) => manifest.file_size - bitmap.missing_bytes(),
+            None => 0,
+        };
+    }
+
+    let workspace = match &download.workspace {
+        Some(workspace) => PathBuf::from(workspace),
+        None => return 0,
+    };
+    let mut total = 0;
+    for segment in &manifest.segments {
+        let part_path = Client::part_path(&workspace, segment.index);
+        // A part larger than its range is discarded when resuming
+        match tokio::fs::metadata(&part_path).await {
+            Ok(metadata) if metadata.len() <= segment.size() => total += metadata.len(),
+            _ => {},
+        }
+    }
+    total
+}
@@ -0,0 +1,161 @@
+use crate::client::{ControlHandle, StopReason};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{Mutex, OnceCell};
+use tokio::task::JoinHandle;
+
+/// How long to wait for segment tasks to flush and exit before aborting them
+const STOP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A download task that is currently running
+struct ActiveDownload {
+    run_id: u64,
+    control: ControlHandle,
+    task: JoinHandle<()>,
+}
+
+/// Running downloads plus settings that must outlive a single run
+#[derive(Default)]
+struct Registry {
+    active: HashMap<u64, ActiveDownload>,
+    speed_limits: HashMap<u64, u64>, // download_id -> bytes per second
+}
+
+static REGISTRY: OnceCell<Arc<Mutex<Registry>>> = OnceCell::const_new();
+
+// Distinguishes successive runs of the same download so a finishing task
+// never unregisters the run that replaced it
+static NEXT_RUN_ID: AtomicU64 = AtomicU64::new(1);
+
+async fn get_registry() -> Arc<Mutex<Registry>> {
+    REGISTRY
+        .get_or_init(|| async { Arc::new(Mutex::new(Registry::default())) })
+        .await
+        .clone()
+}
+
+/// Spawn a download task and keep track of it until it finishes
+pub async fn spawn<F>(download_id: u64, control: ControlHandle, future: F) -> Result<(), String>
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    let registry = get_registry().await;
+    let mut registry_guard = registry.lock().await;
+
+    if let Some(existing) = registry_guard.active.get(&download_id) {
+        if !existing.task.is_finished() {
+            return Err(format!("Download {} is already running", download_id));
+        }
+    }
+
+    // Apply a limit set while the download wasn't running
+    if let Some(&limit) = registry_guard.speed_limits.get(&download_id) {
+        control.set_speed_limit(Some(limit));
+    }
+
+    let run_id = NEXT_RUN_ID.fetch_add(1, Ordering::Relaxed);
+    // The task can't unregister itself before we insert it, since we hold the lock
+    let task = tokio::spawn(async move {
+        future.await;
+        unregister(download_id, run_id).await;
+    });
+
+    registry_guard.active.insert(download_id, ActiveDownload { run_id, control, task });
+    Ok(())
+}
+
+/// Remove a finished run from the registry
+async fn unregister(download_id: u64, run_id: u64) {
+    let registry = get_registry().await;
+    let mut registry_guard = registry.lock().await;
+    if registry_guard.active.get(&download_id).map(|active| active.run_id) == Some(run_id) {
+        registry_guard.active.remove(&download_id);
+    }
+}
+
+/// Check whether a download currently has a running task
+pub async fn is_active(download_id: u64) -> bool {
+    let registry = get_registry().await;
+    let registry_guard = registry.lock().await;
+    registry_guard
+        .active
+        .get(&download_id)
+        .map(|active| !active.task.is_finished())
+        .unwrap_or(false)
+}
+
+/// Get the IDs of all downloads that currently have a running task
+pub async fn active_ids() -> Vec<u64> {
+    let registry = get_registry().await;
+    let registry_guard = registry.lock().await;
+    registry_guard
+        .active
+        .iter()
+        .filter(|(_, active)| !active.task.is_finished())
+        .map(|(&download_id, _)| download_id)
+        .collect()
+}
+
+/// Stop a running download and wait for its task to exit.
+/// Returns false if the download wasn't running.
+pub async fn stop(download_id: u64, reason: StopReason) -> bool {
+    // Take the entry out first so we don't hold the lock while the task unregisters itself
+    let active = {
+        let registry = get_registry().await;
+        let mut registry_guard = registry.lock().await;
+        registry_guard.active.remove(&download_id)
+    };
+
+    let mut active = match active {
+        Some(active) => active,
+        None => return false,
+    };
+
+    active.control.stop(reason);
+    match tokio::time::timeout(STOP_TIMEOUT, &mut active.task).await {
+        Ok(Ok(())) => {},
+        Ok(Err(e)) => eprintln!("Download task {} ended abnormally: {}", download_id, e),
+        Err(_) => {
+            eprintln!("Download {} didn't stop within {:?}, aborting", download_id, STOP_TIMEOUT);
+            active.task.abort();
+        }
+    }
+
+    true
+}
+
+/// Pause a running download, keeping its part files
+pub async fn pause(download_id: u64) -> bool {
+    stop(download_id, StopReason::Paused).await
+}
+
+/// Cancel a running download, removing its part files
+pub async fn cancel(download_id: u64) -> bool {
+    stop(download_id, StopReason::Cancelled).await
+}
+
+/// Set the speed limit of a download in bytes per second, `None` for unlimited.
+/// Applies immediately if it's running and is remembered for later runs.
+pub async fn set_speed_limit(download_id: u64, limit: Option<u64>) {
+    let registry = get_registry().await;
+    let mut registry_guard = registry.lock().await;
+
+    match limit {
+        Some(limit) => registry_guard.speed_limits.insert(download_id, limit),
+        None => registry_guard.speed_limits.remove(&download_id),
+    };
+
+    if let Some(active) = registry_guard.active.get(&download_id) {
+        active.control.set_speed_limit(limit);
+    }
+}
+
+/// Get the speed limit of a download in bytes per second
+pub async fn speed_limit(download_id: u64) -> Option<u64> {
+    let registry = get_registry().await;
+    let registry_guard = registry.lock().await;
+    registry_guard.speed_limits.get(&download_id).copied()
+}
@@ -0,0 +1,311 @@
+use crate::db_manager;
+use std::error::Error;
+use std::fmt;
+use std::time::Duration;
+
+/// Settings key for how many times a segment is retried before the download fails
+const MAX_RETRIES_KEY: &str = "max_segment_retries";
+
+/// Used when the setting hasn't been stored yet
+pub const DEFAULT_MAX_RETRIES: u32 = 5;
+
+/// Delay before the first retry, doubled for every retry after that
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+
+/// Longest delay between two attempts
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// What kind of failure ended a segment attempt, used to decide whether retrying can help
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorClass {
+    /// The connection or a read timed out, or stalled
+    Timeout,
+    /// The connection was refused, reset or closed before the range was complete
+    ConnectionReset,
+    /// The server rejected the request (4xx other than 408, 416 and 429)
+    ClientError(u16),
+    /// The server failed to handle the request (5xx, and 408/429 which ask us to come back later)
+    ServerError(u16),
+    /// The range lies outside of the file, which means the file got smaller
+    RangeNotSatisfiable,
+    /// Anything else, such as failing to write the part file
+    Other,
+}
+
+impl ErrorClass {
+    /// Whether the same request may succeed when tried again later
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, ErrorClass::Timeout | ErrorClass::ConnectionReset | ErrorClass::ServerError(_))
+    }
+}
+
+impl fmt::Display for ErrorClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorClass::Timeout => write!(f, "timeout"),
+            ErrorClass::ConnectionReset => write!(f, "connection reset"),
+            ErrorClass::ClientError(status) => write!(f, "client error {}", status),
+            ErrorClass::ServerError(status) => write!(f, "server error {}", status),
+            ErrorClass::RangeNotSatisfiable => write!(f, "range not satisfiable"),
+            ErrorClass::Other => write!(f, "error"),
+        }
+    }
+}
+
+/// The server answered a segment request with an error status
+#[derive(Debug)]
+pub struct HttpStatusError {
+    pub segment_id: u64,
+    pub status: u16,
+}
+
+impl fmt::Display for HttpStatusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Server returned error status {} for segment {}", self.status, self.segment_id)
+    }
+}
+
+impl Error for HttpStatusError {}
+
+/// A segment's connection ended or stalled before its whole range arrived
+#[derive(Debug)]
+pub struct SegmentInterrupted {
+    pub segment_id: u64,
+    pub missing_bytes: u64,
+    pub stalled: bool, // no data arrived for too long, rather than the server closing the connection
+}
+
+impl fmt::Display for SegmentInterrupted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.stalled {
+            write!(f, "Segment {} stalled with {} bytes of its range missing", self.segment_id, self.missing_bytes)
+        } else {
+            write!(f, "Segment {} connection closed with {} bytes of its range missing", self.segment_id, self.missing_bytes)
+        }
+    }
+}
+
+impl Error for SegmentInterrupted {}
+
+/// A segment gave up, because retrying can't help or it ran out of retries
+#[derive(Debug)]
+pub struct SegmentFailed {
+    pub error: Box<dyn Error + Send + Sync>,
+    pub class: ErrorClass,
+    pub retries: u32,
+}
+
+impl fmt::Display for SegmentFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.retries > 0 {
+            write!(f, "{} ({}, gave up after {} retries)", self.error, self.class, self.retries)
+        } else {
+            write!(f, "{} ({})", self.error, self.class)
+        }
+    }
+}
+
+impl Error for SegmentFailed {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(self.error.as_ref())
+    }
+}
+
+/// Classify a segment error by walking its chain of sources
+pub fn classify(error: &(dyn Error + 'static)) -> ErrorClass {
+    let mut current: Option<&(dyn Error + 'static)> = Some(error);
+    while let Some(error) = current {
+        if let Some(status_error) = error.downcast_ref::<HttpStatusError>() {
+            return classify_status(status_error.status);
+        }
+        if let Some(interrupted) = error.downcast_ref::<SegmentInterrupted>() {
+            return if interrupted.stalled { ErrorClass::Timeout } else { ErrorClass::ConnectionReset };
+        }
+        if let Some(reqwest_error) = error.downcast_ref::<reqwest::Error>() {
+            if reqwest_error.is_timeout() {
+                return ErrorClass::Timeout;
+            }
+            if let Some(status) = reqwest_error.status() {
+                return classify_status(status.as_u16());
+            }
+            if reqwest_error.is_connect() || reqwest_error.is_request() || reqwest_error.is_body() {
+                return ErrorClass::ConnectionReset;
+            }
+        }
+        if let Some(io_error) = error.downcast_ref::<std::io::Error>() {
+            use std::io::ErrorKind;
+            match io_error.kind() {
+                ErrorKind::TimedOut => return ErrorClass::Timeout,
+                ErrorKind::ConnectionReset
+                | ErrorKind::ConnectionAborted
+                | ErrorKind::ConnectionRefused
+                | ErrorKind::BrokenPipe
+                | ErrorKind::UnexpectedEof => return ErrorClass::ConnectionReset,
+                _ => {},
+            }
+        }
+        current = error.source();
+    }
+    ErrorClass::Other
+}
+
+fn classify_status(status: u16) -> ErrorClass {
+    match status {
+        416 => ErrorClass::RangeNotSatisfiable,
+        // Request Timeout and Too Many Requests are worth another try
+        408 | 429 => ErrorClass::ServerError(status),
+        400..=499 => ErrorClass::ClientError(status),
+        500..=599 => ErrorClass::ServerError(status),
+        _ => ErrorClass::Other,
+    }
+}
+
+/// How often and how long to wait before retrying a failed segment
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: DEFAULT_MAX_RETRIES,
+            initial_backoff: INITIAL_BACKOFF,
+            max_backoff: MAX_BACKOFF,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Delay before the given retry (0-based), doubling each time up to `max_backoff`
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let factor = 2u32.saturating_pow(retry.min(16));
+        self.initial_backoff.saturating_mul(factor).min(self.max_backoff)
+    }
+}
+
+/// Get the number of times a failed segment is retried
+pub async fn max_segment_retries() -> u32 {
+    match db_manager::get_setting(MAX_RETRIES_KEY).await {
+        Ok(Some(value)) => value.parse().unwrap_or(DEFAULT_MAX_RETRIES),
+        Ok(None) => DEFAULT_MAX_RETRIES,
+        Err(e) => {
+            eprintln!("Failed to read {} setting: {}", MAX_RETRIES_KEY, e);
+            DEFAULT_MAX_RETRIES
+        }
+    }
+}
+
+/// Set the number of times a failed segment is retried, 0 to fail right away
+pub async fn set_max_segment_retries(retries: u32) -> rusqlite::Result<()> {
+    db_manager::set_setting(MAX_RETRIES_KEY, &retries.to_string()).await
+}
+
+/// Build the retry policy from the stored settings
+pub async fn load_policy() -> RetryPolicy {
+    RetryPolicy {
+        max_retries: max_segment_retries().await,
+        ..RetryPolicy::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::ErrorKind;
+
+    fn status(status: u16) -> ErrorClass {
+        classify(&HttpStatusError { segment_id: 1, status })
+    }
+
+    fn io(kind: ErrorKind) -> ErrorClass {
+        classify(&std::io::Error::new(kind, "test"))
+    }
+
+    #[test]
+    fn classifies_status_codes() {
+        let cases = [
+            (408, ErrorClass::ServerError(408)),
+            (429, ErrorClass::ServerError(429)),
+            (400, ErrorClass::ClientError(400)),
+            (403, ErrorClass::ClientError(403)),
+            (404, ErrorClass::ClientError(404)),
+            (416, ErrorClass::RangeNotSatisfiable),
+            (500, ErrorClass::ServerError(500)),
+            (503, ErrorClass::ServerError(503)),
+            (302, ErrorClass::Other),
+        ];
+        for (code, class) in cases {
+            assert_eq!(status(code), class, "{}", code);
+        }
+
+        assert!(status(408).is_retryable());
+        assert!(status(429).is_retryable());
+        assert!(status(502).is_retryable());
+        assert!(!status(404).is_retryable());
+        assert!(!status(416).is_retryable());
+    }
+
+    #[test]
+    fn classifies_io_errors() {
+        let cases = [
+            (ErrorKind::TimedOut, ErrorClass::Timeout),
+            (ErrorKind::ConnectionReset, ErrorClass::ConnectionReset),
+            (ErrorKind::ConnectionAborted, ErrorClass::ConnectionReset),
+            (ErrorKind::ConnectionRefused, ErrorClass::ConnectionReset),
+            (ErrorKind::BrokenPipe, ErrorClass::ConnectionReset),
+            (ErrorKind::UnexpectedEof, ErrorClass::ConnectionReset),
+            (ErrorKind::PermissionDenied, ErrorClass::Other),
+            (ErrorKind::NotFound, ErrorClass::Other),
+        ];
+        for (kind, class) in cases {
+            assert_eq!(io(kind), class, "{:?}", kind);
+        }
+    }
+
+    #[test]
+    fn classifies_interrupted_segments() {
+        let closed = SegmentInterrupted { segment_id: 1, missing_bytes: 10, stalled: false };
+        assert_eq!(classify(&closed), ErrorClass::ConnectionReset);
+        let stalled = SegmentInterrupted { segment_id: 1, missing_bytes: 10, stalled: true };
+        assert_eq!(classify(&stalled), ErrorClass::Timeout);
+    }
+
+    #[tokio::test]
+    async fn finds_a_timeout_behind_a_failed_segment() {
+        // Accepts the connection but never answers
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (_socket, _) = listener.accept().await.unwrap();
+            tokio::time::sleep(Duration::from_secs(5)).await;
+        });
+
+        let client = reqwest::Client::builder().timeout(Duration::from_millis(100)).build().unwrap();
+        let error = client.get(format!("http://{}/", address)).send().await.unwrap_err();
+        assert!(error.is_timeout());
+
+        let failed = SegmentFailed { error: Box::new(error), class: ErrorClass::Other, retries: 0 };
+        assert_eq!(classify(&failed), ErrorClass::Timeout);
+        server.abort();
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_maximum() {
+        let policy = RetryPolicy {
+            max_retries: 10,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(3),
+        };
+        assert_eq!(policy.backoff(0), Duration::from_millis(500));
+        assert_eq!(policy.backoff(1), Duration::from_secs(1));
+        assert_eq!(policy.backoff(2), Duration::from_secs(2));
+        assert_eq!(policy.backoff(3), Duration::from_secs(3));
+        assert_eq!(policy.backoff(4), Duration::from_secs(3));
+        assert_eq!(policy.backoff(u32::MAX), Duration::from_secs(3));
+
+        assert_eq!(RetryPolicy::default().backoff(20), MAX_BACKOFF);
+    }
+}
@@ -13,6 +13,15 @@ pub struct DownloadState {
     pub start_time: Instant,
     pub last_update_time: Instant,
     pub is_complete: bool,
+    pub is_stopped: bool,
+    pub generation: u64,                      // bumped whenever the download (re)initializes
+    pub is_indeterminate: bool,               // size unknown, only bytes received can be shown
+    pub is_merging: bool,                     // all bytes are in, part files are being merged
+    pub merged_bytes: u64,
+    pub merge_total: u64,
+    pub is_verifying: bool,                   // a preallocated file is being hashed
+    pub hashed_bytes: u64,
+    pub hash_total: u64,
 }
 
 impl DownloadState {
@@ -27,13 +36,36 @@ impl DownloadState {
             start_time: Instant::now(),
             last_update_time: Instant::now(),
             is_complete: false,
+            is_stopped: false,
+            generation: 0,
+            is_indeterminate: false,
+            is_merging: false,
+            merged_bytes: 0,
+            merge_total: 0,
+            is_verifying: false,
+            hashed_bytes: 0,
+            hash_total: 0,
         }
     }
 
-    /// Initializes the download state with file size and segment information
+    /// Initializes the download state with file size and segment information.
+    /// Called again when a download starts over, so previous progress is discarded.
     pub fn initialize(&mut self, file_size: u64, segments: HashMap<u64, u64>) {
         self.file_size = file_size;
         self.segment_sizes = segments;
+        self.segment_progress.clear();
+        self.segment_speeds.clear();
+        self.total_downloaded = 0;
+        self.start_time = Instant::now();
+        self.generation += 1;
+        // Single connection downloads of unknown length report a size of 0
+        self.is_indeterminate = file_size == 0;
+        self.is_merging = false;
+        self.merged_bytes = 0;
+        self.merge_total = 0;
+        self.is_verifying = false;
+        self.hashed_bytes = 0;
+        self.hash_total = 0;
         
         // Initialize progress for each segment to 0
         for (&segment_id, _) in &self.segment_sizes {
@@ -66,15 +98,74 @@ impl DownloadState {
         self.last_update_time = Instant::now();
     }
 
+    /// Shrinks a segment whose tail was handed to a new segment
+    pub fn split_segment(&mut self, segment_id: u64, segment_size: u64, new_segment_id: u64, new_segment_size: u64) {
+        self.segment_sizes.insert(segment_id, segment_size);
+        self.segment_sizes.insert(new_segment_id, new_segment_size);
+        self.segment_progress.insert(new_segment_id, 0);
+        self.segment_speeds.insert(new_segment_id, 0.0);
+    }
+
+    /// Records how far merging the part files has come
+    pub fn set_merge_progress(&mut self, merged_bytes: u64, total_bytes: u64) {
+        self.is_merging = true;
+        self.merged_bytes = merged_bytes;
+        self.merge_total = total_bytes;
+    }
+
+    /// Gets the merge progress percentage
+    pub fn get_merge_progress(&self) -> f64 {
+        if self.merge_total == 0 {
+            return 0.0;
+        }
+        (self.merged_bytes as f64 / self.merge_total as f64) * 100.0
+    }
+
+    /// Records how far hashing the output file has come
+    pub fn set_verify_progress(&mut self, hashed_bytes: u64, total_bytes: u64) {
+        self.is_verifying = true;
+        self.hashed_bytes = hashed_bytes;
+        self.hash_total = total_bytes;
+    }
+
+    /// Gets the verification progress percentage
+    pub fn get_verify_progress(&self) -> f64 {
+        if self.hash_total == 0 {
+            return 0.0;
+        }
+        (self.hashed_bytes as f64 / self.hash_total as f64) * 100.0
+    }
+
+    /// Gets the phase shown by the frontend: downloading, merging, verifying or completed
+    pub fn phase(&self) -> &'static str {
+        if self.is_complete {
+            "completed"
+        } else if self.is_verifying {
+            "verifying"
+        } else if self.is_merging {
+            "merging"
+        } else {
+            "downloading"
+        }
+    }
+
     /// Marks the download as complete
     pub fn mark_complete(&mut self) {
         self.is_complete = true;
     }
 
+    /// Marks the download as stopped (paused, cancelled or failed) so watchers can exit
+    pub fn mark_stopped(&mut self) {
+        self.is_stopped = true;
+    }
+
     /// Gets the progress percentage for a specific segment
     pub fn get_segment_progress(&self, segment_id: u64) -> f64 {
         let downloaded = self.segment_progress.get(&segment_id).cloned().unwrap_or(0);
-        let total = self.segment_sizes.get(&segment_id).cloned().unwrap_or(1); // Avoid div by zero
+        let total = self.segment_sizes.get(&segment_id).cloned().unwrap_or(0);
+        if total == 0 {
+            return 0.0; // Unknown size, avoid div by zero
+        }
         (downloaded as f64 / total as f64) * 100.0
     }
 
@@ -98,6 +189,9 @@ impl DownloadState {
 
     /// Estimates the remaining download time in seconds
     pub fn get_estimated_time_left(&self) -> f64 {
+        if self.is_indeterminate {
+            return 0.0;
+        }
         let speed = self.get_average_speed();
         if speed > 0.0 {
             let remaining_bytes = self.file_size.saturating_sub(self.total_downloaded);
@@ -119,6 +213,10 @@ impl DownloadState {
         map.insert("completed".to_string(), Value::from(self.total_downloaded));
         map.insert("speed".to_string(), Value::from(speed));
         map.insert("estimatedTimeLeft".to_string(), Value::from(estimated_time_left));
+        map.insert("indeterminate".to_string(), Value::from(self.is_indeterminate && !self.is_complete));
+        map.insert("phase".to_string(), Value::from(self.phase()));
+        map.insert("mergeProgress".to_string(), Value::from(if self.is_complete { 100.0 } else { self.get_merge_progress() }));
+        map.insert("verifyProgress".to_string(), Value::from(if self.is_complete { 100.0 } else { self.get_verify_progress() }));
         
         // Add segments data
         let mut segments = Vec::new();
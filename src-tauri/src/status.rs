@@ -0,0 +1,184 @@
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use std::error::Error;
+use std::fmt;
+
+/// Where a download is in its lifecycle.
+///
+/// A download moves through queued → probing → downloading → merging → verifying →
+/// completed, skipping merging or verifying when there's nothing to merge or verify.
+/// It can be paused, cancelled or fail along the way and is re-queued to continue.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize, Type)]
+#[serde(rename_all = "snake_case")]
+pub enum DownloadStatus {
+    /// Waiting for a free download slot
+    Queued,
+    /// Asking the server about the file before downloading
+    Probing,
+    /// Segments are being downloaded
+    Downloading,
+    /// Part files are being merged into the output file
+    Merging,
+    /// The output file is being hashed to check its checksum
+    Verifying,
+    /// The file is saved
+    Completed,
+    /// Stopped by the user, resuming continues where it left off
+    Paused,
+    /// Failed, the message says why
+    Error,
+    /// Stopped by the user and its part files removed
+    Cancelled,
+    /// Finished without every byte, resuming downloads only the missing ranges
+    Incomplete,
+    /// Downloaded, but the file doesn't have the expected checksum
+    VerificationFailed,
+    /// The output file exists and the user has to pick what to do
+    Conflict,
+    /// The output file exists and the download was dropped
+    Skipped,
+}
+
+impl DownloadStatus {
+    pub const ALL: [DownloadStatus; 13] = [
+        Self::Queued, Self::Probing, Self::Downloading, Self::Merging, Self::Verifying,
+        Self::Completed, Self::Paused, Self::Error, Self::Cancelled, Self::Incomplete,
+        Self::VerificationFailed, Self::Conflict, Self::Skipped,
+    ];
+
+    /// Parse the name a status is stored under
+    pub fn parse(value: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|status| status.as_str() == value)
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Queued => "queued",
+            Self::Probing => "probing",
+            Self::Downloading => "downloading",
+            Self::Merging => "merging",
+            Self::Verifying => "verifying",
+            Self::Completed => "completed",
+            Self::Paused => "paused",
+            Self::Error => "error",
+            Self::Cancelled => "cancelled",
+            Self::Incomplete => "incomplete",
+            Self::VerificationFailed => "verification_failed",
+            Self::Conflict => "conflict",
+            Self::Skipped => "skipped",
+        }
+    }
+
+    /// Whether a task is working on the download in this status
+    pub fn is_active(&self) -> bool {
+        matches!(self, Self::Probing | Self::Downloading | Self::Merging | Self::Verifying)
+    }
+
+    /// Whether a download in this status may move to `next`. Staying in the same
+    /// status is always allowed.
+    pub fn can_transition_to(&self, next: DownloadStatus) -> bool {
+        use DownloadStatus::*;
+        if *self == next {
+            return true;
+        }
+        match self {
+            Queued => matches!(next, Probing | Paused | Cancelled | Error),
+            // Running downloads can also be re-queued when the app quit underneath them
+            Probing => matches!(next, Downloading | Paused | Cancelled | Queued | Error | Conflict | Skipped),
+            Downloading => matches!(next, Merging | Verifying | Completed | Paused | Cancelled | Queued
+                | Error | Incomplete | VerificationFailed | Conflict | Skipped),
+            Merging => matches!(next, Verifying | Completed | Paused | Cancelled | Queued
+                | Error | Incomplete | VerificationFailed),
+            Verifying => matches!(next, Completed | Paused | Cancelled | Queued | Error | VerificationFailed),
+            // The download may have finished just as it was paused
+            Paused => matches!(next, Queued | Cancelled | Completed),
+            Error | Incomplete | VerificationFailed | Conflict | Skipped => matches!(next, Queued | Paused | Cancelled),
+            // Starting a cancelled or completed download again downloads it from scratch
+            Cancelled | Completed => matches!(next, Queued),
+        }
+    }
+}
+
+impl fmt::Display for DownloadStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl ToSql for DownloadStatus {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.as_str()))
+    }
+}
+
+impl FromSql for DownloadStatus {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        let value = value.as_str()?;
+        Self::parse(value).ok_or_else(|| FromSqlError::Other(format!("Unknown download status '{}'", value).into()))
+    }
+}
+
+/// A download was asked to move to a status its current one doesn't lead to
+#[derive(Debug)]
+pub struct InvalidTransition {
+    pub download_id: u64,
+    pub from: DownloadStatus,
+    pub to: DownloadStatus,
+}
+
+impl fmt::Display for InvalidTransition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Download {} can't go from {} to {}", self.download_id, self.from, self.to)
+    }
+}
+
+impl Error for InvalidTransition {}
+
+#[cfg(test)]
+mod tests {
+    use super::DownloadStatus::{self, *};
+
+    /// Every status with the statuses it may move to besides itself
+    const ALLOWED: [(DownloadStatus, &[DownloadStatus]); 13] = [
+        (Queued, &[Probing, Paused, Cancelled, Error]),
+        (Probing, &[Downloading, Paused, Cancelled, Queued, Error, Conflict, Skipped]),
+        (Downloading, &[Merging, Verifying, Completed, Paused, Cancelled, Queued, Error, Incomplete,
+                        VerificationFailed, Conflict, Skipped]),
+        (Merging, &[Verifying, Completed, Paused, Cancelled, Queued, Error, Incomplete, VerificationFailed]),
+        (Verifying, &[Completed, Paused, Cancelled, Queued, Error, VerificationFailed]),
+        (Completed, &[Queued]),
+        (Paused, &[Queued, Cancelled, Completed]),
+        (Error, &[Queued, Paused, Cancelled]),
+        (Cancelled, &[Queued]),
+        (Incomplete, &[Queued, Paused, Cancelled]),
+        (VerificationFailed, &[Queued, Paused, Cancelled]),
+        (Conflict, &[Queued, Paused, Cancelled]),
+        (Skipped, &[Queued, Paused, Cancelled]),
+    ];
+
+    #[test]
+    fn transitions_follow_the_table() {
+        for (from, allowed) in ALLOWED.iter() {
+            for next in DownloadStatus::ALL.iter() {
+                let expected = next == from || allowed.contains(next);
+                assert_eq!(from.can_transition_to(*next), expected, "{} -> {}", from, next);
+            }
+        }
+    }
+
+    #[test]
+    fn table_covers_every_status() {
+        for status in DownloadStatus::ALL.iter() {
+            assert!(ALLOWED.iter().any(|(from, _)| from == status), "{} is missing", status);
+        }
+    }
+
+    #[test]
+    fn parses_stored_names() {
+        for status in DownloadStatus::ALL.iter() {
+            assert_eq!(DownloadStatus::parse(status.as_str()), Some(*status));
+        }
+        assert_eq!(DownloadStatus::parse("unknown"), None);
+    }
+}
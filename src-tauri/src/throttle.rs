@@ -0,0 +1,184 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Longest single sleep while waiting for tokens, so limit changes apply quickly
+const MAX_WAIT: Duration = Duration::from_millis(100);
+
+/// Cap shared by every download, unlimited by default
+pub static GLOBAL_LIMITER: RateLimiter = RateLimiter::unlimited();
+
+#[derive(Debug)]
+struct Bucket {
+    limit: Option<u64>,          // bytes per second, None for unlimited
+    tokens: f64,                 // may go negative when a chunk is larger than the balance
+    last_refill: Option<Instant>,
+}
+
+/// Token bucket limiting how many bytes per second may be consumed.
+/// The limit can be changed at any time, including while tasks are waiting on it.
+#[derive(Debug)]
+pub struct RateLimiter {
+    bucket: Mutex<Bucket>,
+}
+
+impl RateLimiter {
+    /// Creates a limiter that never waits until a limit is set
+    pub const fn unlimited() -> Self {
+        Self {
+            bucket: Mutex::new(Bucket {
+                limit: None,
+                tokens: 0.0,
+                last_refill: None,
+            }),
+        }
+    }
+
+    /// Creates a limiter with the given limit in bytes per second
+    pub fn new(limit: Option<u64>) -> Self {
+        let limiter = Self::unlimited();
+        limiter.set_limit(limit);
+        limiter
+    }
+
+    /// Changes the limit in bytes per second. `None` or `Some(0)` removes the limit.
+    pub fn set_limit(&self, limit: Option<u64>) {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.limit = limit.filter(|&limit| limit > 0);
+        // Start from an empty bucket so a new limit doesn't allow a burst of saved-up tokens
+        bucket.tokens = 0.0;
+        bucket.last_refill = Some(Instant::now());
+    }
+
+    /// Gets the current limit in bytes per second
+    pub fn limit(&self) -> Option<u64> {
+        self.bucket.lock().unwrap().limit
+    }
+
+    /// Consumes `bytes` tokens, waiting while the bucket is in debt
+    pub async fn acquire(&self, bytes: u64) {
+        loop {
+            let wait = {
+                let mut bucket = self.bucket.lock().unwrap();
+                let limit = match bucket.limit {
+                    Some(limit) => limit as f64,
+                    None => return,
+                };
+
+                // Refill based on elapsed time, allowing at most one second of burst
+                let now = Instant::now();
+                let elapsed = bucket.last_refill
+                    .map(|last| now.duration_since(last).as_secs_f64())
+                    .unwrap_or(0.0);
+                bucket.tokens = (bucket.tokens + elapsed * limit).min(limit);
+                bucket.last_refill = Some(now);
+
+                if bucket.tokens >= 0.0 {
+                    bucket.tokens -= bytes as f64;
+                    return;
+                }
+
+                Duration::from_secs_f64(-bucket.tokens / limit).min(MAX_WAIT)
+            };
+
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// The limiters a single download has to respect: its own and the global one
+#[derive(Clone, Debug)]
+pub struct Throttle {
+    download: Arc<RateLimiter>,
+    global: &'static RateLimiter,
+}
+
+impl Default for Throttle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Throttle {
+    pub fn new() -> Self {
+        Self {
+            download: Arc::new(RateLimiter::unlimited()),
+            global: &GLOBAL_LIMITER,
+        }
+    }
+
+    /// The limiter specific to this download
+    pub fn download_limiter(&self) -> Arc<RateLimiter> {
+        self.download.clone()
+    }
+
+    /// Waits until `bytes` may be consumed under both limits
+    pub async fn consume(&self, bytes: u64) {
+        self.download.acquire(bytes).await;
+        self.global.acquire(bytes).await;
+    }
+}
+
+/// Converts a KB/s value from the frontend into a limit in bytes per second
+pub fn kbps_to_limit(limit_kbps: f64) -> Option<u64> {
+    if limit_kbps.is_finite() && limit_kbps > 0.0 {
+        Some((limit_kbps * 1024.0) as u64)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_kbps_to_bytes_per_second() {
+        assert_eq!(kbps_to_limit(1.0), Some(1024));
+        assert_eq!(kbps_to_limit(0.5), Some(512));
+        assert_eq!(kbps_to_limit(2048.0), Some(2 * 1024 * 1024));
+        for no_limit in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+            assert_eq!(kbps_to_limit(no_limit), None, "{}", no_limit);
+        }
+    }
+
+    #[test]
+    fn zero_limit_means_unlimited() {
+        let limiter = RateLimiter::new(Some(0));
+        assert_eq!(limiter.limit(), None);
+        limiter.set_limit(Some(1000));
+        assert_eq!(limiter.limit(), Some(1000));
+    }
+
+    #[tokio::test]
+    async fn unlimited_never_waits() {
+        let limiter = RateLimiter::unlimited();
+        let started = Instant::now();
+        limiter.acquire(u64::MAX).await;
+        limiter.acquire(u64::MAX).await;
+        assert!(started.elapsed() < Duration::from_millis(50));
+    }
+
+    #[tokio::test]
+    async fn waits_for_the_bucket_to_refill() {
+        let limiter = RateLimiter::new(Some(10_000));
+        let started = Instant::now();
+        // An empty bucket lets one chunk through and goes into debt for it
+        limiter.acquire(5_000).await;
+        assert!(started.elapsed() < Duration::from_millis(50));
+        // The debt takes half a second at 10 000 bytes per second to pay off
+        limiter.acquire(1).await;
+        let waited = started.elapsed();
+        assert!(waited >= Duration::from_millis(450), "{:?}", waited);
+        assert!(waited < Duration::from_millis(1000), "{:?}", waited);
+    }
+
+    #[tokio::test]
+    async fn new_limit_forgives_debt() {
+        let limiter = RateLimiter::new(Some(1_000));
+        limiter.acquire(1_000_000).await;
+        limiter.set_limit(Some(1_000));
+        let started = Instant::now();
+        limiter.acquire(1).await;
+        assert!(started.elapsed() < Duration::from_millis(50));
+    }
+}
@@ -0,0 +1,58 @@
+use crate::db_manager;
+use std::path::{Path, PathBuf};
+
+/// Settings key for the directory download workspaces are created in
+const WORKSPACE_DIR_KEY: &str = "workspace_dir";
+
+/// Directory workspaces are created in unless another one is configured:
+/// the user's cache directory, or the temp directory on systems without one
+pub fn default_root() -> PathBuf {
+    dirs::cache_dir().unwrap_or_else(std::env::temp_dir).join("speedy")
+}
+
+/// Get the directory download workspaces are created in
+pub async fn workspace_root() -> PathBuf {
+    match db_manager::get_setting(WORKSPACE_DIR_KEY).await {
+        Ok(Some(value)) if !value.trim().is_empty() => PathBuf::from(value),
+        Ok(_) => default_root(),
+        Err(e) => {
+            eprintln!("Failed to read {} setting: {}", WORKSPACE_DIR_KEY, e);
+            default_root()
+        }
+    }
+}
+
+/// Set the directory new download workspaces are created in. Downloads that
+/// already have a workspace keep using it.
+pub async fn set_workspace_root(root: &Path) -> rusqlite::Result<()> {
+    db_manager::set_setting(WORKSPACE_DIR_KEY, &root.to_string_lossy()).await
+}
+
+/// Workspace of a download under `root`, holding its part files until they are merged
+pub fn for_download(root: &Path, download_id: u64) -> PathBuf {
+    root.join(format!("download-{}", download_id))
+}
+
+/// Count the part files in a workspace
+pub async fn part_file_count(workspace: &Path) -> usize {
+    let mut entries = match tokio::fs::read_dir(workspace).await {
+        Ok(entries) => entries,
+        Err(_) => return 0,
+    };
+    let mut count = 0;
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        if entry.file_name().to_string_lossy().starts_with("part.") {
+            count += 1;
+        }
+    }
+    count
+}
+
+/// Remove a workspace along with any part files still in it
+pub async fn remove(workspace: &Path) {
+    match tokio::fs::remove_dir_all(workspace).await {
+        Ok(_) => eprintln!("Removed workspace: {}", workspace.display()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {},
+        Err(e) => eprintln!("Error removing workspace {}: {}", workspace.display(), e),
+    }
+}
@@ -0,0 +1,25 @@
+-- A downloads database as created by the first version of Speedy, before the
+-- schema was versioned. Migration tests upgrade it to the current schema.
+CREATE TABLE IF NOT EXISTS downloads (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    download_id INTEGER NOT NULL,
+    url TEXT NOT NULL,
+    filename TEXT NOT NULL,
+    total_size INTEGER NOT NULL,
+    downloaded_bytes INTEGER NOT NULL DEFAULT 0,
+    status TEXT NOT NULL,
+    error_message TEXT,
+    parts INTEGER NOT NULL,
+    created_at TEXT NOT NULL,
+    updated_at TEXT NOT NULL,
+    completed_at TEXT,
+    save_path TEXT
+);
+CREATE INDEX IF NOT EXISTS idx_download_id ON downloads(download_id);
+CREATE INDEX IF NOT EXISTS idx_status ON downloads(status);
+
+INSERT INTO downloads (download_id, url, filename, total_size, downloaded_bytes, status, error_message, parts, created_at, updated_at, completed_at, save_path)
+VALUES
+    (1700000001, 'https://example.com/running.iso', 'running.iso', 4096, 1024, 'in_progress', NULL, 8, '2023-11-14T22:13:21+00:00', '2023-11-14T22:14:00+00:00', NULL, NULL),
+    (1700000002, 'https://example.com/done.zip', 'done.zip', 2048, 2048, 'completed', NULL, 4, '2023-11-14T22:20:00+00:00', '2023-11-14T22:21:00+00:00', '2023-11-14T22:21:00+00:00', '/home/user/Downloads/done.zip'),
+    (1700000003, 'https://example.com/broken.tar', 'broken.tar', 0, 0, 'error', 'Connection reset by peer', 8, '2023-11-14T22:30:00+00:00', '2023-11-14T22:30:05+00:00', NULL, NULL);
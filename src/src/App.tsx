@@ -59,6 +59,9 @@ interface DownloadPayload {
   speed: number;
   estimatedTimeLeft: number;
   downloadId: number;
+  phase: 'downloading' | 'merging' | 'verifying' | 'completed';
+  mergeProgress: number;
+  verifyProgress: number;
   segments: Array<{
     id: number;
     totalBytes: number;
@@ -93,6 +96,9 @@ const DownloadScreen: React.FC<DownloadScreenProps> = ({
   const [speed, setSpeed] = useState(0);
   const [estimatedTimeLeft, setEstimatedTimeLeft] = useState(0);
   const [downloadedSize, setDownloadedSize] = useState(0);
+  const [phase, setPhase] = useState<DownloadPayload['phase']>('downloading');
+  const [mergeProgress, setMergeProgress] = useState(0);
+  const [verifyProgress, setVerifyProgress] = useState(0);
   
   // Speed limit settings
   const [speedLimit, setSpeedLimit] = useState(0); // 0 means unlimited
@@ -150,6 +156,9 @@ const DownloadScreen: React.FC<DownloadScreenProps> = ({
       }
       
       const { fileSize: size, progress: prog, speed: spd, estimatedTimeLeft: etl, segments: segmentsData } = payload;
+      setPhase(payload.phase);
+      setMergeProgress(payload.mergeProgress);
+      setVerifyProgress(payload.verifyProgress);
 
       // Ensure progress only increases
       const newProgress = Math.max(prog, highWaterMarks.progress);
@@ -216,7 +225,8 @@ const DownloadScreen: React.FC<DownloadScreenProps> = ({
       }
 
       // Notify when download completes
-      if (newProgress >= 100 && notifyOnComplete) {
+      // All bytes are in before the part files are merged, so wait for the merge too
+      if (payload.phase === 'completed' && notifyOnComplete) {
         // Using native notification API
         if ('Notification' in window && Notification.permission === 'granted') {
           new Notification('Download Complete', {
@@ -417,7 +427,11 @@ const DownloadScreen: React.FC<DownloadScreenProps> = ({
                   <div className={styles.statItem}>
                     <div className={styles.statLabel}>Time left</div>
                     <div className={styles.statValue}>
-                      {progress >= 100 ? 'Complete' : prettyTimeLeft(estimatedTimeLeft)}
+                      {phase === 'merging'
+                        ? `Merging ${Math.round(mergeProgress)}%`
+                        : phase === 'verifying'
+                        ? `Verifying ${Math.round(verifyProgress)}%`
+                        : progress >= 100 ? 'Complete' : prettyTimeLeft(estimatedTimeLeft)}
                     </div>
                   </div>
                 </div>
@@ -13,8 +13,8 @@ const invoke = () => window.__TAURI_INVOKE__;
 /**
  * Starts a download process and tracks its progress
  */
-export function startDownload(url: string, name: string, parts: string, downloadId: string | null) {
-    return invoke()<null>("start_download", { url,name,parts,downloadId })
+export function startDownload(url: string, name: string, parts: string, downloadId: string | null, directory: string | null, checksum: string | null, checksumUrl: string | null) {
+    return invoke()<null>("start_download", { url,name,parts,downloadId,directory,checksum,checksumUrl })
 }
 
 /**
@@ -55,7 +55,7 @@ export function resumeDownload(downloadId: string) {
 /**
  * Get downloads with a specific status from the database
  */
-export function getDownloadsByStatus(status: string) {
+export function getDownloadsByStatus(status: DownloadStatus) {
     return invoke()<Download[]>("get_downloads_by_status", { status })
 }
 
@@ -74,4 +74,10 @@ export function openDetailsWindow(downloadId: string, url: string, title: string
     return invoke()<null>("open_details_window", { downloadId,url,title })
 }
 
-export type Download = { id: string | null; download_id: string; url: string; filename: string; total_size: string; downloaded_bytes: string; status: string; error_message: string | null; parts: string; created_at: string; updated_at: string; completed_at: string | null; save_path: string | null }
+export type Download = { id: string | null; download_id: string; url: string; filename: string; total_size: string; downloaded_bytes: string; status: DownloadStatus; error_message: string | null; error_code: string | null; parts: string; created_at: string; updated_at: string; completed_at: string | null; save_path: string | null }
+export type DownloadStatus = "queued" | "probing" | "downloading" | "merging" | "verifying" | "completed" | "paused" | "error" | "cancelled" | "incomplete" | "verification_failed" | "conflict" | "skipped"
+/**
+ * An error reported to the frontend, tagged with a code saying what kind of
+ * failure it is so the UI can react to it without parsing the message
+ */
+export type SpeedyError = { code: "network"; message: string } | { code: "http_status"; status: number; message: string } | { code: "io"; message: string } | { code: "disk_full"; message: string } | { code: "checksum"; message: string } | { code: "db"; message: string } | { code: "invalid_input"; message: string } | { code: "not_found"; message: string } | { code: "conflict"; message: string } | { code: "incomplete"; message: string } | { code: "other"; message: string }
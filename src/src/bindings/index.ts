@@ -28,9 +28,18 @@ export type {
   Download,
   DownloadProgress,
   SegmentProgress,
-  DownloadProgressEvent
+  DownloadProgressEvent,
+  SpeedyError
 } from './commands';
 
+// Commands reject with a SpeedyError, anything else is shown as is
+export function errorMessage(error: unknown): string {
+  if (typeof error === 'object' && error !== null && 'message' in error) {
+    return String((error as { message: unknown }).message);
+  }
+  return String(error);
+}
+
 // Helper function to invoke commands with better error handling
 export async function invokeCommand<T>(
   command: string, 
@@ -8,6 +8,7 @@ import {
   pauseDownload as pauseDownloadCmd,
   resumeDownload as resumeDownloadCmd,
   deleteDownload as deleteDownloadCmd,
+  errorMessage,
   type Download as ApiDownload
 } from '../bindings';
 
@@ -35,6 +36,7 @@ interface UIDownload {
   status: string;
   progress: number;
   error_message: string | null;
+  error_code: string | null;
   created_at: string;
   updated_at: string;
   completed_at: string | null;
@@ -52,6 +54,8 @@ interface DownloadProgressEvent {
   fileSize: string;
   speed: number;
   estimatedTimeLeft: number;
+  phase: 'downloading' | 'merging' | 'verifying' | 'completed';
+  mergeProgress: number;
   segments: Array<{
     id: string;
     totalBytes: string;
@@ -91,6 +95,7 @@ function convertToUIDownload(apiDownload: ApiDownload): UIDownload {
     status: apiDownload.status,
     progress,
     error_message: apiDownload.error_message,
+    error_code: apiDownload.error_code,
     created_at: apiDownload.created_at,
     updated_at: apiDownload.updated_at,
     completed_at: apiDownload.completed_at,
@@ -131,7 +136,7 @@ export const useDownloads = () => {
       setError(null);
     } catch (err) {
       console.error('Failed to fetch downloads:', err);
-      setError(`Failed to load downloads: ${err}`);
+      setError(`Failed to load downloads: ${errorMessage(err)}`);
     } finally {
       setIsLoading(false);
     }
@@ -168,7 +173,7 @@ export const useDownloads = () => {
       setError(null);
     } catch (err) {
       console.error('Failed to fetch filtered downloads:', err);
-      setError(`Failed to load downloads: ${err}`);
+      setError(`Failed to load downloads: ${errorMessage(err)}`);
     } finally {
       setIsLoading(false);
     }
@@ -187,8 +192,8 @@ export const useDownloads = () => {
           return {
             ...download,
             downloaded_bytes: progress.completed,
-            // Convert fractional progress (0-100) to status if we're at 100%
-            status: progress.progress >= 100 ? DownloadStatus.COMPLETED : download.status
+            // Reaching 100% of the bytes isn't enough, the part files still have to be merged
+            status: progress.phase === 'completed' ? DownloadStatus.COMPLETED : download.status
           };
         }
         return download;
@@ -260,7 +265,7 @@ export const useDownloads = () => {
       return result;
     } catch (err) {
       console.error('Failed to check existing download:', err);
-      throw new Error(`Failed to check for existing download: ${err}`);
+      throw new Error(`Failed to check for existing download: ${errorMessage(err)}`);
     }
   }, []);
   
@@ -270,7 +275,7 @@ export const useDownloads = () => {
       console.log('Starting download for URL:', url, 'with filename:', filename);
       
       // Start the download with the Tauri command
-      await startDownloadCmd(url, filename, parts.toString(), null);
+      await startDownloadCmd(url, filename, parts.toString(), null, null, null, null);
       
       // Refresh download list after adding new download
       await fetchDownloads();
@@ -282,7 +287,7 @@ export const useDownloads = () => {
       return true;
     } catch (err) {
       console.error('Failed to start download:', err);
-      setError(`Failed to start download: ${err}`);
+      setError(`Failed to start download: ${errorMessage(err)}`);
       return false;
     }
   }, [fetchDownloads]);
@@ -306,7 +311,7 @@ export const useDownloads = () => {
       return startDownload(url, result.suggested_filename || result.original_filename, parts);
     } catch (err) {
       console.error('Failed to prepare download:', err);
-      setError(`Failed to prepare download: ${err}`);
+      setError(`Failed to prepare download: ${errorMessage(err)}`);
       return false;
     }
   }, [checkExistingDownload, startDownload]);
@@ -351,7 +356,7 @@ export const useDownloads = () => {
       await fetchDownloads();
     } catch (err) {
       console.error('Failed to toggle pause state:', err);
-      setError(`Failed to update download: ${err}`);
+      setError(`Failed to update download: ${errorMessage(err)}`);
     }
   }, [downloads, fetchDownloads]);
   
@@ -368,7 +373,7 @@ export const useDownloads = () => {
       await fetchDownloads();
     } catch (err) {
       console.error('Failed to cancel download:', err);
-      setError(`Failed to cancel download: ${err}`);
+      setError(`Failed to cancel download: ${errorMessage(err)}`);
     }
   }, [fetchDownloads]);
   